@@ -21,7 +21,7 @@ _pizauth()
     local cur prev sub
     local cmds=()
     cmds+=(dump restore reload shutdown status)
-    cmds+=(info server)
+    cmds+=(info server watch)
     cmds+=(refresh revoke show)
 
     cur=${COMP_WORDS[COMP_CWORD]}
@@ -35,7 +35,7 @@ _pizauth()
         2)
             case $sub in
                 dump|restore|reload|shutdown|status) COMPREPLY=();;
-                info) mapfile -t COMPREPLY < <(compgen -W '-j' -- "$cur") ;;
+                info|watch) mapfile -t COMPREPLY < <(compgen -W '-j' -- "$cur") ;;
                 refresh|show)
                     local accounts
                     mapfile -t accounts < <(_accounts)
@@ -6,7 +6,7 @@ function __fish_pizauth_accounts --description "Helper function to parse account
 end
 
 function __fish_pizauth_is_main_command --description "Returns true if we're not in a subcommand"
-    not __fish_seen_subcommand_from dump restore reload shutdown status info server refresh revoke show
+    not __fish_seen_subcommand_from dump restore reload shutdown status info server refresh revoke show watch
 end
 
 # Don't autocomplete files
@@ -23,9 +23,10 @@ complete -c pizauth -n "__fish_pizauth_is_main_command" -d "Start the server" -a
 complete -c pizauth -n "__fish_pizauth_is_main_command" -d "Print access token of account to stdout" -a "show"
 complete -c pizauth -n "__fish_pizauth_is_main_command" -d "Shut the server down" -a "shutdown"
 complete -c pizauth -n "__fish_pizauth_is_main_command" -d "Writes output about current accounts to stdout" -a "status"
+complete -c pizauth -n "__fish_pizauth_is_main_command" -d "Writes events to stdout as they happen" -a "watch"
 
-# pizauth info [-j]
-complete -c pizauth -n "__fish_seen_subcommand_from info" -s j -d "JSON output"
+# pizauth info/watch [-j]
+complete -c pizauth -n "__fish_seen_subcommand_from info watch" -s j -d "JSON output"
 
 # pizauth refresh/show [-u] account
 complete -c pizauth -n "__fish_seen_subcommand_from refresh show" -s u -d "Exclude authorization URL"
@@ -0,0 +1,331 @@
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io,
+    os::unix::net::UnixStream,
+    path::Path,
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{
+    cache_path,
+    protocol::{
+        read_msg, write_msg, AccountStatus, ClientMsg, Command, DpopProofRequest, Event, Outcome,
+        ProtocolError, Reply, ServerInfo, ServerMsg, PROTOCOL_VERSION,
+    },
+    sock_path,
+};
+
+/// The errors that a [Client] can encounter.
+#[derive(Debug)]
+pub enum ClientError {
+    /// Nothing is listening on the server's socket.
+    NotRunning(io::Error),
+    /// Communicating with the server failed.
+    Io(io::Error),
+    /// The server sent a message that does not make sense in context.
+    UnexpectedMessage(String),
+    /// The server reported an error.
+    Server(ProtocolError),
+    /// An access token will be available once the user has authorised the request at `url`
+    /// (entering `user_code`, for accounts using the device authorization grant). Both are `None`
+    /// if the client asked not to be given the URL.
+    Pending {
+        url: Option<String>,
+        user_code: Option<String>,
+    },
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotRunning(_) => write!(f, "pizauth authenticator not running or not responding"),
+            Self::Io(e) => write!(f, "Can't communicate with pizauth authenticator: {e}"),
+            Self::UnexpectedMessage(x) => write!(f, "Malformed response '{x}'"),
+            Self::Server(e) => write!(f, "{e}"),
+            Self::Pending {
+                url: Some(url),
+                user_code: Some(user_code),
+            } => write!(
+                f,
+                "Access token unavailable until authorised with URL {url:} and code {user_code:}"
+            ),
+            Self::Pending {
+                url: Some(url),
+                user_code: None,
+            } => write!(
+                f,
+                "Access token unavailable until authorised with URL {url:}"
+            ),
+            Self::Pending { url: None, .. } => {
+                write!(f, "Access token unavailable until authorised")
+            }
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::NotRunning(e) | Self::Io(e) => Some(e),
+            Self::Server(e) => Some(e),
+            Self::UnexpectedMessage(_) | Self::Pending { .. } => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClientError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// An access token, as returned by [`Client::show_token`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessToken {
+    pub access_token: String,
+    /// The account's `username`, if it has one.
+    pub username: Option<String>,
+    /// The DPoP proof requested with [`Client::show_token_with_dpop_proof`].
+    pub dpop_proof: Option<String>,
+}
+
+/// A connection to a pizauth server. Any number of requests can be made on a single connection,
+/// though the server closes connections which are idle for more than a few seconds.
+pub struct Client {
+    stream: UnixStream,
+    next_id: u64,
+}
+
+impl Client {
+    /// Connect to the pizauth server at the standard location (see [`crate::cache_path`]).
+    pub fn connect() -> Result<Self, ClientError> {
+        Self::connect_to(&sock_path(&cache_path()?))
+    }
+
+    /// Connect to the pizauth server listening on the socket `sock_path`.
+    pub fn connect_to(sock_path: &Path) -> Result<Self, ClientError> {
+        let stream = UnixStream::connect(sock_path).map_err(ClientError::NotRunning)?;
+        write_msg(
+            &stream,
+            &ClientMsg::Hello {
+                versions: vec![PROTOCOL_VERSION],
+            },
+        )?;
+        match read_msg(&stream)? {
+            Some(ServerMsg::Hello { version }) if version == PROTOCOL_VERSION => {
+                Ok(Self { stream, next_id: 0 })
+            }
+            Some(ServerMsg::Error(e)) => Err(ClientError::Server(e)),
+            None => Err(closed()),
+            Some(x) => Err(unexpected(&x)),
+        }
+    }
+
+    /// Send `command` to the server, returning its reply.
+    fn request(&mut self, command: Command) -> Result<Reply, ClientError> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_msg(&self.stream, &ClientMsg::Request { id, command })?;
+        match read_msg(&self.stream)? {
+            Some(ServerMsg::Response {
+                id: rid,
+                outcome: Outcome::Ok(reply),
+            }) if rid == id => Ok(reply),
+            Some(ServerMsg::Response {
+                id: rid,
+                outcome: Outcome::Error(e),
+            }) if rid == id => Err(ClientError::Server(e)),
+            Some(ServerMsg::Error(e)) => Err(ClientError::Server(e)),
+            None => Err(closed()),
+            Some(x) => Err(unexpected(&x)),
+        }
+    }
+
+    /// Return the server's (obfuscated, but not encrypted) token state, suitable for passing to
+    /// [`Client::restore`].
+    pub fn dump(&mut self) -> Result<Vec<u8>, ClientError> {
+        match self.request(Command::Dump)? {
+            Reply::Dump { dump } => STANDARD
+                .decode(dump)
+                .map_err(|e| ClientError::UnexpectedMessage(e.to_string())),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    pub fn info(&mut self) -> Result<ServerInfo, ClientError> {
+        match self.request(Command::Info)? {
+            Reply::Info(info) => Ok(info),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    /// Refresh `account`'s access token in the background or, if there is no access token,
+    /// request a new one, in which case [`ClientError::Pending`] is returned (with the
+    /// authorisation URL if `with_url` is `true`).
+    pub fn refresh(&mut self, account: &str, with_url: bool) -> Result<(), ClientError> {
+        let command = Command::Refresh {
+            account: account.to_owned(),
+            with_url,
+        };
+        match self.request(command)? {
+            Reply::Scheduled => Ok(()),
+            Reply::Pending { url, user_code } => Err(ClientError::Pending { url, user_code }),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    /// Ask the server to reload its configuration.
+    pub fn reload(&mut self) -> Result<(), ClientError> {
+        match self.request(Command::Reload)? {
+            Reply::Ok => Ok(()),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    /// Restore a dump previously returned by [`Client::dump`].
+    pub fn restore(&mut self, dump: &[u8]) -> Result<(), ClientError> {
+        let command = Command::Restore {
+            dump: STANDARD.encode(dump),
+        };
+        match self.request(command)? {
+            Reply::Ok => Ok(()),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    /// Revoke, and forget, `account`'s tokens.
+    pub fn revoke(&mut self, account: &str) -> Result<(), ClientError> {
+        let command = Command::Revoke {
+            account: account.to_owned(),
+        };
+        match self.request(command)? {
+            Reply::Ok => Ok(()),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    /// Return `account`'s access token. If there is no access token, a new one is requested and
+    /// [`ClientError::Pending`] is returned (with the authorisation URL if `with_url` is `true`),
+    /// unless `wait` is `Some`, in which case the server waits up to that long (`Duration::MAX`
+    /// meaning forever) for one to become available. If `min_valid` is `Some`, the access token
+    /// is refreshed first if it would expire within that time.
+    pub fn show_token(
+        &mut self,
+        account: &str,
+        with_url: bool,
+        wait: Option<Duration>,
+        min_valid: Option<Duration>,
+    ) -> Result<AccessToken, ClientError> {
+        self.show_token_inner(account, with_url, wait, min_valid, None)
+    }
+
+    /// As [`Client::show_token`], but also return a DPoP proof (RFC 9449), bound to the access
+    /// token, for an HTTP `method` request to `url`. The account must use DPoP.
+    pub fn show_token_with_dpop_proof(
+        &mut self,
+        account: &str,
+        with_url: bool,
+        wait: Option<Duration>,
+        min_valid: Option<Duration>,
+        method: &str,
+        url: &str,
+    ) -> Result<AccessToken, ClientError> {
+        let dpop_proof = DpopProofRequest {
+            method: method.to_owned(),
+            url: url.to_owned(),
+        };
+        self.show_token_inner(account, with_url, wait, min_valid, Some(dpop_proof))
+    }
+
+    fn show_token_inner(
+        &mut self,
+        account: &str,
+        with_url: bool,
+        wait: Option<Duration>,
+        min_valid: Option<Duration>,
+        dpop_proof: Option<DpopProofRequest>,
+    ) -> Result<AccessToken, ClientError> {
+        let command = Command::ShowToken {
+            account: account.to_owned(),
+            with_url,
+            // `Duration::MAX` becomes `u64::MAX` seconds, which the server treats as "forever".
+            wait: wait.map(|x| x.as_secs()),
+            min_valid: min_valid.map(|x| x.as_secs()),
+            dpop_proof,
+        };
+        match self.request(command)? {
+            Reply::AccessToken {
+                access_token,
+                username,
+                dpop_proof,
+            } => Ok(AccessToken {
+                access_token,
+                username,
+                dpop_proof,
+            }),
+            Reply::Pending { url, user_code } => Err(ClientError::Pending { url, user_code }),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    /// Ask the server to shut itself down.
+    pub fn shutdown(&mut self) -> Result<(), ClientError> {
+        match self.request(Command::Shutdown)? {
+            Reply::Ok => Ok(()),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    /// Stream events from the server. Since the connection is then dedicated to streaming
+    /// events, this consumes the [Client].
+    pub fn subscribe(mut self) -> Result<Subscription, ClientError> {
+        match self.request(Command::Subscribe)? {
+            Reply::Subscribed => Ok(Subscription {
+                stream: self.stream,
+            }),
+            x => Err(unexpected(&x)),
+        }
+    }
+
+    /// Return the status of every account.
+    pub fn status(&mut self) -> Result<Vec<AccountStatus>, ClientError> {
+        match self.request(Command::Status)? {
+            Reply::Status { accounts } => Ok(accounts),
+            x => Err(unexpected(&x)),
+        }
+    }
+}
+
+/// A stream of events from the server, as returned by [`Client::subscribe`]. The iterator blocks
+/// until the next event arrives, and ends if the server closes the connection.
+pub struct Subscription {
+    stream: UnixStream,
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Event, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match read_msg(&self.stream) {
+            Ok(Some(ServerMsg::Event(ev))) => Some(Ok(ev)),
+            Ok(Some(ServerMsg::Error(e))) => Some(Err(ClientError::Server(e))),
+            Ok(Some(x)) => Some(Err(unexpected(&x))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+fn closed() -> ClientError {
+    ClientError::Io(io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "connection closed by server",
+    ))
+}
+
+fn unexpected<T: fmt::Debug>(x: &T) -> ClientError {
+    ClientError::UnexpectedMessage(format!("{x:?}"))
+}
@@ -9,3 +9,6 @@ pub use daemon::daemon;
 // Use nix's daemon(3) wrapper on other platforms:
 #[cfg(not(target_os = "macos"))]
 pub use nix::unistd::daemon;
+
+mod peer_cred;
+pub use peer_cred::{exe_chain, peer_cred};
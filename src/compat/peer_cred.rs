@@ -0,0 +1,63 @@
+//! Finding out which process is at the other end of a Unix domain socket.
+
+use std::{io, os::unix::net::UnixStream, path::PathBuf};
+
+/// Return the pid (if this platform can tell us it) and effective uid of the process at the other
+/// end of `stream`.
+#[cfg(any(target_os = "android", target_os = "linux"))]
+pub fn peer_cred(stream: &UnixStream) -> io::Result<(Option<i32>, u32)> {
+    use nix::sys::socket::{getsockopt, sockopt::PeerCredentials};
+    let cred = getsockopt(stream, PeerCredentials)?;
+    Ok((Some(cred.pid()), cred.uid()))
+}
+
+/// Return the pid (if this platform can tell us it) and effective uid of the process at the other
+/// end of `stream`.
+#[cfg(not(any(target_os = "android", target_os = "linux")))]
+pub fn peer_cred(stream: &UnixStream) -> io::Result<(Option<i32>, u32)> {
+    let (uid, _) = nix::unistd::getpeereid(stream)?;
+    Ok((None, uid.as_raw()))
+}
+
+/// Return the executable of the process `pid` followed by those of its ancestors, stopping before
+/// `init`. Executables which cannot be determined (e.g. because the process has exited) are
+/// omitted.
+#[cfg(target_os = "linux")]
+pub fn exe_chain(pid: i32) -> Vec<PathBuf> {
+    use std::fs;
+
+    /// How many ancestors will we look at? This stops a process table which changes under our
+    /// feet from making us loop for a long time.
+    const MAX_DEPTH: usize = 64;
+
+    let mut exes = Vec::new();
+    let mut pid = pid;
+    for _ in 0..MAX_DEPTH {
+        if pid <= 1 {
+            break;
+        }
+        if let Ok(exe) = fs::read_link(format!("/proc/{pid}/exe")) {
+            exes.push(exe);
+        }
+        // The parent's pid is the second field after the command name, which is in parentheses
+        // and may itself contain spaces and parentheses.
+        let Some(ppid) = fs::read_to_string(format!("/proc/{pid}/stat"))
+            .ok()
+            .and_then(|x| {
+                x.rsplit_once(')')
+                    .and_then(|(_, x)| x.split_whitespace().nth(1)?.parse::<i32>().ok())
+            })
+        else {
+            break;
+        };
+        pid = ppid;
+    }
+    exes
+}
+
+/// Return the executable of the process `pid` followed by those of its ancestors, stopping before
+/// `init`. This platform has no way of finding out executables, so the result is always empty.
+#[cfg(not(target_os = "linux"))]
+pub fn exe_chain(_pid: i32) -> Vec<PathBuf> {
+    Vec::new()
+}
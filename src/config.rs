@@ -1,5 +1,10 @@
 use std::{
-    collections::HashMap, error::Error, fs::read_to_string, path::Path, sync::Arc, time::Duration,
+    collections::HashMap,
+    error::Error,
+    fs::{read_dir, read_to_string},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use lrlex::{lrlex_mod, DefaultLexerTypes, LRNonStreamingLexer};
@@ -8,7 +13,7 @@ use serde::{Deserialize, Serialize};
 use url::Url;
 use wincode::{SchemaRead, SchemaWrite};
 
-use crate::config_ast;
+use crate::{config_ast, shell_cmd::shell_cmd_stdout};
 
 lrlex_mod!("config.l");
 lrpar_mod!("config.y");
@@ -29,6 +34,9 @@ const AUTH_NOTIFY_INTERVAL_DEFAULT: u64 = 15 * 60;
 const HTTP_LISTEN_DEFAULT: &str = "127.0.0.1:0";
 /// What is the default `bind()` address for the HTTPS server?
 const HTTPS_LISTEN_DEFAULT: &str = "127.0.0.1:0";
+/// How long to run `client_id_cmd` and `client_secret_cmd` commands before killing them? These may
+/// need to wait for the user to unlock a password store, so we're generous.
+const SECRET_CMD_TIMEOUT: Duration = Duration::from_mins(1);
 /// Keys used by pizauth in queries and which we forbid users from overriding, since doing so is
 /// only going to end in tears.
 const RESERVED_AUTH_URI_KEYS: &[&str] = &[
@@ -45,16 +53,20 @@ const RESERVED_AUTH_URI_KEYS: &[&str] = &[
 #[derive(Debug)]
 pub struct Config {
     pub accounts: HashMap<String, Arc<Account>>,
+    pub audit_log: Option<PathBuf>,
     pub auth_notify_cmd: Option<String>,
     pub auth_notify_interval: Duration,
     pub error_notify_cmd: Option<String>,
     pub http_listen: Option<String>,
     pub https_listen: Option<String>,
+    pub kernel_keyring: KernelKeyring,
     pub transient_error_if_cmd: Option<String>,
     refresh_at_least: Option<Duration>,
     refresh_before_expiry: Option<Duration>,
     refresh_retry: Option<Duration>,
     pub startup_cmd: Option<String>,
+    pub state_file: Option<PathBuf>,
+    pub state_storage: StateStorage,
     pub token_event_cmd: Option<String>,
 }
 
@@ -62,14 +74,85 @@ impl Config {
     /// Create a `Config` from `path`, returning `Err(String)` (containing a human readable
     /// message) if it was unable to do so.
     pub fn from_path(conf_path: &Path) -> Result<Self, String> {
+        let mut builder = ConfigBuilder::default();
+        builder.process_path(conf_path)?;
+        builder.finish()
+    }
+
+    #[cfg(test)]
+    pub fn from_str(input: &str) -> Result<Self, String> {
+        let mut builder = ConfigBuilder::default();
+        builder.process(input, None)?;
+        builder.finish()
+    }
+}
+
+/// Builds up a [Config] from one or more files: the top-level file and any files it (directly or
+/// indirectly) includes.
+#[derive(Default)]
+#[allow(clippy::option_option)]
+struct ConfigBuilder {
+    accounts: HashMap<String, Arc<Account>>,
+    /// The canonicalised path of the file each account was defined in (`None` if the config was
+    /// not read from a file). An account defined more than once in the same file takes its last
+    /// definition, but one file can't redefine an account from another.
+    account_paths: HashMap<String, Option<PathBuf>>,
+    audit_log: Option<PathBuf>,
+    auth_notify_cmd: Option<String>,
+    auth_notify_interval: Option<Duration>,
+    error_notify_cmd: Option<String>,
+    http_listen: Option<Option<String>>,
+    https_listen: Option<Option<String>>,
+    kernel_keyring: Option<KernelKeyring>,
+    transient_error_if_cmd: Option<String>,
+    refresh_at_least: Option<Duration>,
+    refresh_before_expiry: Option<Duration>,
+    refresh_retry: Option<Duration>,
+    startup_cmd: Option<String>,
+    state_file: Option<PathBuf>,
+    state_storage: Option<StateStorage>,
+    token_event_cmd: Option<String>,
+    templates: HashMap<String, AccountSettings>,
+    /// The canonicalised paths of the files currently being processed, used to detect include
+    /// cycles.
+    including: Vec<PathBuf>,
+}
+
+impl ConfigBuilder {
+    /// Process the config file at `conf_path`.
+    fn process_path(&mut self, conf_path: &Path) -> Result<(), String> {
         let input = match read_to_string(conf_path) {
             Ok(s) => s,
             Err(e) => return Err(format!("Can't read {conf_path:?}: {e}")),
         };
-        Self::from_str(&input)
+        let canon = conf_path
+            .canonicalize()
+            .map_err(|e| format!("Can't read {conf_path:?}: {e}"))?;
+        if let Some(i) = self.including.iter().position(|x| x == &canon) {
+            let chain = self.including[i..]
+                .iter()
+                .chain([&canon])
+                .map(|x| x.display().to_string())
+                .collect::<Vec<_>>();
+            return Err(format!(
+                "{conf_path:?} includes itself: {}",
+                chain.join(" -> ")
+            ));
+        }
+        self.including.push(canon);
+        let r = self.process(&input, Some(conf_path));
+        self.including.pop();
+        r
     }
 
-    pub fn from_str(input: &str) -> Result<Self, String> {
+    /// Process the config `input`, which was read from `conf_path` if it is not `None`. Errors
+    /// are prefixed with `conf_path` so that the user can tell which file they relate to.
+    fn process(&mut self, input: &str, conf_path: Option<&Path>) -> Result<(), String> {
+        let in_file = |msg: String| match conf_path {
+            Some(p) => format!("{}: {msg}", p.display()),
+            None => msg,
+        };
+
         let lexerdef = config_l::lexerdef();
         let lexer = lexerdef.lexer(input);
         let (astopt, errs) = config_y::parse(&lexer);
@@ -78,148 +161,303 @@ impl Config {
                 .iter()
                 .map(|e| e.pp(&lexer, &config_y::token_epp))
                 .collect::<Vec<_>>();
-            return Err(msgs.join("\n"));
-        }
-
-        let mut accounts = HashMap::new();
-        let mut auth_notify_cmd = None;
-        let mut auth_notify_interval = None;
-        let mut error_notify_cmd = None;
-        let mut http_listen = None;
-        let mut https_listen = None;
-        let mut transient_error_if_cmd = None;
-        let mut refresh_at_least = None;
-        let mut refresh_before_expiry = None;
-        let mut refresh_retry = None;
-        let mut startup_cmd = None;
-        let mut token_event_cmd = None;
+            return Err(in_file(msgs.join("\n")));
+        }
+
         match astopt {
             Some(Ok(opts)) => {
                 for opt in opts {
                     match opt {
-                        config_ast::TopLevel::Account(overall_span, name, fields) => {
-                            let act_name = unescape_str(lexer.span_str(name));
-                            accounts.insert(
-                                act_name.clone(),
-                                Arc::new(Account::from_fields(
-                                    act_name,
-                                    &lexer,
-                                    overall_span,
-                                    fields,
-                                )?),
-                            );
-                        }
-                        config_ast::TopLevel::AuthErrorCmd(span) => {
-                            return Err(error_at_span(
-                                &lexer,
-                                span,
-                                "'auth_error_cmd' has been renamed to 'error_notify_cmd'",
-                            ));
-                        }
-                        config_ast::TopLevel::AuthNotifyCmd(span) => {
-                            auth_notify_cmd = Some(check_not_assigned_str(
-                                &lexer,
-                                "auth_notify_cmd",
-                                span,
-                                auth_notify_cmd,
-                            )?);
-                        }
-                        config_ast::TopLevel::AuthNotifyInterval(span) => {
-                            auth_notify_interval =
-                                Some(time_str_to_duration(check_not_assigned_time(
-                                    &lexer,
-                                    "auth_notify_interval",
-                                    span,
-                                    auth_notify_interval,
-                                )?)?);
-                        }
-                        config_ast::TopLevel::ErrorNotifyCmd(span) => {
-                            error_notify_cmd = Some(check_not_assigned_str(
-                                &lexer,
-                                "error_notify_cmd",
-                                span,
-                                error_notify_cmd,
-                            )?);
-                        }
-                        config_ast::TopLevel::HttpListen(span) => {
-                            http_listen = Some(Some(check_not_assigned_str(
-                                &lexer,
-                                "http_listen",
-                                span,
-                                http_listen,
-                            )?));
-                        }
-                        config_ast::TopLevel::HttpListenNone(span) => {
-                            check_not_assigned(&lexer, "http_listen", span, http_listen)?;
-                            http_listen = Some(None);
+                        config_ast::TopLevel::Include(span) => {
+                            let path = include_path(&lexer, conf_path, span);
+                            // Errors from the included file already name that file.
+                            self.process_path(&path)?;
                         }
-                        config_ast::TopLevel::HttpsListen(span) => {
-                            https_listen = Some(Some(check_not_assigned_str(
-                                &lexer,
-                                "https_listen",
-                                span,
-                                https_listen,
-                            )?));
-                        }
-                        config_ast::TopLevel::HttpsListenNone(span) => {
-                            check_not_assigned(&lexer, "https_listen", span, https_listen)?;
-                            https_listen = Some(None);
-                        }
-                        config_ast::TopLevel::TransientErrorIfCmd(span) => {
-                            transient_error_if_cmd = Some(check_not_assigned_str(
-                                &lexer,
-                                "transient_error_if_cmd",
-                                span,
-                                transient_error_if_cmd,
-                            )?);
-                        }
-                        config_ast::TopLevel::RefreshAtLeast(span) => {
-                            refresh_at_least =
-                                Some(time_str_to_duration(check_not_assigned_time(
-                                    &lexer,
-                                    "refresh_at_least",
-                                    span,
-                                    refresh_at_least,
-                                )?)?);
+                        config_ast::TopLevel::IncludeDir(span) => {
+                            let dir = include_path(&lexer, conf_path, span);
+                            let mut paths = read_dir(&dir)
+                                .and_then(|rd| {
+                                    rd.map(|e| e.map(|e| e.path()))
+                                        .collect::<Result<Vec<_>, _>>()
+                                })
+                                .map_err(|e| {
+                                    in_file(error_at_span(
+                                        &lexer,
+                                        span,
+                                        &format!("Can't read directory {dir:?}: {e}"),
+                                    ))
+                                })?;
+                            paths.retain(|p| {
+                                p.is_file()
+                                    && p.extension().is_some_and(|x| x == "conf")
+                                    && !p
+                                        .file_name()
+                                        .and_then(|x| x.to_str())
+                                        .is_some_and(|x| x.starts_with('.'))
+                            });
+                            paths.sort();
+                            for path in paths {
+                                self.process_path(&path)?;
+                            }
                         }
-                        config_ast::TopLevel::RefreshBeforeExpiry(span) => {
-                            refresh_before_expiry =
-                                Some(time_str_to_duration(check_not_assigned_time(
-                                    &lexer,
-                                    "refresh_before_expiry",
+                        opt => self.top_level(&lexer, conf_path, opt).map_err(in_file)?,
+                    }
+                }
+            }
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Process a single top-level option, from the file `conf_path` if it is not `None`, other
+    /// than an include.
+    fn top_level(
+        &mut self,
+        lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+        conf_path: Option<&Path>,
+        opt: config_ast::TopLevel,
+    ) -> Result<(), String> {
+        match opt {
+            config_ast::TopLevel::Account(overall_span, name, tmpl_name, fields) => {
+                let act_name = unescape_str(lexer.span_str(name));
+                let path = self.including.last().cloned();
+                match self.account_paths.get(&act_name) {
+                    Some(Some(prev)) if Some(prev) != path.as_ref() => {
+                        return Err(error_at_span(
+                            lexer,
+                            name,
+                            &format!(
+                                "Mustn't specify account '{act_name}' more than once: it is also specified in {}",
+                                prev.display()
+                            ),
+                        ));
+                    }
+                    _ => (),
+                }
+                let template = match tmpl_name {
+                    Some(span) => {
+                        let tmpl_name = unescape_str(lexer.span_str(span));
+                        match self.templates.get(&tmpl_name) {
+                            Some(x) => Some(x),
+                            None => {
+                                return Err(error_at_span(
+                                    lexer,
                                     span,
-                                    refresh_before_expiry,
-                                )?)?);
-                        }
-                        config_ast::TopLevel::RefreshRetry(span) => {
-                            refresh_retry = Some(time_str_to_duration(check_not_assigned_time(
-                                &lexer,
-                                "refresh_retry",
-                                span,
-                                refresh_retry,
-                            )?)?);
-                        }
-                        config_ast::TopLevel::StartupCmd(span) => {
-                            startup_cmd = Some(check_not_assigned_str(
-                                &lexer,
-                                "startup_cmd",
-                                span,
-                                startup_cmd,
-                            )?);
-                        }
-                        config_ast::TopLevel::TokenEventCmd(span) => {
-                            token_event_cmd = Some(check_not_assigned_str(
-                                &lexer,
-                                "token_event_cmd",
-                                span,
-                                token_event_cmd,
-                            )?);
+                                    &format!(
+                                        "Template '{tmpl_name}' must be defined before it is used"
+                                    ),
+                                ))
+                            }
                         }
                     }
+                    None => None,
+                };
+                let act = Account::from_fields(
+                    act_name.clone(),
+                    lexer,
+                    conf_path,
+                    overall_span,
+                    template,
+                    fields,
+                )?;
+                self.account_paths.insert(act_name.clone(), path);
+                self.accounts.insert(act_name, Arc::new(act));
+            }
+            config_ast::TopLevel::AuditLog(span) => {
+                self.audit_log = Some(check_not_assigned_abs_path(
+                    lexer,
+                    "audit_log",
+                    span,
+                    self.audit_log.as_ref(),
+                )?);
+            }
+            config_ast::TopLevel::AuthErrorCmd(span) => {
+                return Err(error_at_span(
+                    lexer,
+                    span,
+                    "'auth_error_cmd' has been renamed to 'error_notify_cmd'",
+                ));
+            }
+            config_ast::TopLevel::AuthNotifyCmd(span) => {
+                self.auth_notify_cmd = Some(check_not_assigned_str(
+                    lexer,
+                    "auth_notify_cmd",
+                    span,
+                    self.auth_notify_cmd.as_ref(),
+                )?);
+            }
+            config_ast::TopLevel::AuthNotifyInterval(span) => {
+                self.auth_notify_interval = Some(time_str_to_duration(check_not_assigned_time(
+                    lexer,
+                    "auth_notify_interval",
+                    span,
+                    self.auth_notify_interval,
+                )?)?);
+            }
+            config_ast::TopLevel::ErrorNotifyCmd(span) => {
+                self.error_notify_cmd = Some(check_not_assigned_str(
+                    lexer,
+                    "error_notify_cmd",
+                    span,
+                    self.error_notify_cmd.as_ref(),
+                )?);
+            }
+            config_ast::TopLevel::HttpListen(span) => {
+                self.http_listen = Some(Some(check_not_assigned_str(
+                    lexer,
+                    "http_listen",
+                    span,
+                    self.http_listen.as_ref(),
+                )?));
+            }
+            config_ast::TopLevel::HttpListenNone(span) => {
+                check_not_assigned(lexer, "http_listen", span, self.http_listen.as_ref())?;
+                self.http_listen = Some(None);
+            }
+            config_ast::TopLevel::HttpsListen(span) => {
+                self.https_listen = Some(Some(check_not_assigned_str(
+                    lexer,
+                    "https_listen",
+                    span,
+                    self.https_listen.as_ref(),
+                )?));
+            }
+            config_ast::TopLevel::HttpsListenNone(span) => {
+                check_not_assigned(lexer, "https_listen", span, self.https_listen.as_ref())?;
+                self.https_listen = Some(None);
+            }
+            config_ast::TopLevel::Include(_) | config_ast::TopLevel::IncludeDir(_) => {
+                unreachable!()
+            }
+            config_ast::TopLevel::TransientErrorIfCmd(span) => {
+                self.transient_error_if_cmd = Some(check_not_assigned_str(
+                    lexer,
+                    "transient_error_if_cmd",
+                    span,
+                    self.transient_error_if_cmd.as_ref(),
+                )?);
+            }
+            config_ast::TopLevel::RefreshAtLeast(span) => {
+                self.refresh_at_least = Some(time_str_to_duration(check_not_assigned_time(
+                    lexer,
+                    "refresh_at_least",
+                    span,
+                    self.refresh_at_least,
+                )?)?);
+            }
+            config_ast::TopLevel::RefreshBeforeExpiry(span) => {
+                self.refresh_before_expiry = Some(time_str_to_duration(check_not_assigned_time(
+                    lexer,
+                    "refresh_before_expiry",
+                    span,
+                    self.refresh_before_expiry,
+                )?)?);
+            }
+            config_ast::TopLevel::RefreshRetry(span) => {
+                self.refresh_retry = Some(time_str_to_duration(check_not_assigned_time(
+                    lexer,
+                    "refresh_retry",
+                    span,
+                    self.refresh_retry,
+                )?)?);
+            }
+            config_ast::TopLevel::Template(name, fields) => {
+                let tmpl_name = unescape_str(lexer.span_str(name));
+                if self.templates.contains_key(&tmpl_name) {
+                    return Err(error_at_span(
+                        lexer,
+                        name,
+                        &format!("Mustn't specify template '{tmpl_name}' more than once"),
+                    ));
                 }
+                self.templates
+                    .insert(tmpl_name, AccountSettings::from_fields(lexer, fields)?);
+            }
+            config_ast::TopLevel::StartupCmd(span) => {
+                self.startup_cmd = Some(check_not_assigned_str(
+                    lexer,
+                    "startup_cmd",
+                    span,
+                    self.startup_cmd.as_ref(),
+                )?);
+            }
+            config_ast::TopLevel::StateFile(span) => {
+                self.state_file = Some(check_not_assigned_abs_path(
+                    lexer,
+                    "state_file",
+                    span,
+                    self.state_file.as_ref(),
+                )?);
+            }
+            config_ast::TopLevel::KernelKeyring(span) => {
+                check_not_assigned(lexer, "kernel_keyring", span, self.kernel_keyring.as_ref())?;
+                self.kernel_keyring = Some(match lexer.span_str(span) {
+                    "session" => KernelKeyring::Session,
+                    "user" => KernelKeyring::User,
+                    _ => unreachable!(),
+                });
+            }
+            config_ast::TopLevel::StateStorage(span) => {
+                check_not_assigned(lexer, "state_storage", span, self.state_storage.as_ref())?;
+                let storage = match lexer.span_str(span) {
+                    "file" => StateStorage::File,
+                    "kernel_keyring" => StateStorage::KernelKeyring,
+                    "none" => StateStorage::None,
+                    "secret_service" => StateStorage::SecretService,
+                    _ => unreachable!(),
+                };
+                if storage == StateStorage::KernelKeyring && !cfg!(target_os = "linux") {
+                    return Err(error_at_span(
+                        lexer,
+                        span,
+                        "The kernel keyring is only available on Linux",
+                    ));
+                }
+                if storage == StateStorage::SecretService && !cfg!(feature = "secret-service") {
+                    return Err(error_at_span(
+                        lexer,
+                        span,
+                        "pizauth was built without the 'secret-service' feature",
+                    ));
+                }
+                self.state_storage = Some(storage);
+            }
+            config_ast::TopLevel::TokenEventCmd(span) => {
+                self.token_event_cmd = Some(check_not_assigned_str(
+                    lexer,
+                    "token_event_cmd",
+                    span,
+                    self.token_event_cmd.as_ref(),
+                )?);
             }
-            _ => unreachable!(),
         }
+        Ok(())
+    }
+
+    /// Check the options that relate to more than one part of the configuration and, if they're
+    /// valid, create a [Config].
+    fn finish(self) -> Result<Config, String> {
+        let Self {
+            accounts,
+            audit_log,
+            auth_notify_cmd,
+            auth_notify_interval,
+            error_notify_cmd,
+            http_listen,
+            https_listen,
+            kernel_keyring,
+            transient_error_if_cmd,
+            refresh_at_least,
+            refresh_before_expiry,
+            refresh_retry,
+            startup_cmd,
+            state_file,
+            state_storage,
+            token_event_cmd,
+            account_paths: _,
+            templates: _,
+            including: _,
+        } = self;
 
         if (&http_listen, &https_listen) == (&Some(None), &Some(None)) {
             return Err("Cannot set both http_listen and https_listen to 'none'".into());
@@ -229,6 +467,23 @@ impl Config {
             return Err("Must specify at least one account".into());
         }
 
+        let state_storage = match (state_storage, &state_file) {
+            (None, Some(_)) => StateStorage::File,
+            (None, None) => StateStorage::None,
+            (Some(StateStorage::File), None) => {
+                return Err("'state_storage = file' requires 'state_file' to be set".into());
+            }
+            (Some(x), Some(_)) if x != StateStorage::File => {
+                return Err("'state_file' can only be set if 'state_storage = file'".into());
+            }
+            (Some(x), _) => x,
+        };
+        if kernel_keyring.is_some() && state_storage != StateStorage::KernelKeyring {
+            return Err(
+                "'kernel_keyring' can only be set if 'state_storage = kernel_keyring'".into(),
+            );
+        }
+
         for (act_name, act) in &accounts {
             if act.redirect_uri.starts_with("https") {
                 match https_listen {
@@ -247,24 +502,42 @@ impl Config {
             }
         }
 
-        Ok(Self {
+        Ok(Config {
             accounts,
+            audit_log,
             auth_notify_cmd,
             auth_notify_interval: auth_notify_interval
                 .unwrap_or_else(|| Duration::from_secs(AUTH_NOTIFY_INTERVAL_DEFAULT)),
             error_notify_cmd,
             http_listen: http_listen.unwrap_or_else(|| Some(HTTP_LISTEN_DEFAULT.to_owned())),
             https_listen: https_listen.unwrap_or_else(|| Some(HTTPS_LISTEN_DEFAULT.to_owned())),
+            kernel_keyring: kernel_keyring.unwrap_or(KernelKeyring::User),
             transient_error_if_cmd,
             refresh_at_least,
             refresh_before_expiry,
             refresh_retry,
             startup_cmd,
+            state_file,
+            state_storage,
             token_event_cmd,
         })
     }
 }
 
+/// Return the path named by the string at `span`. Relative paths are relative to the directory
+/// containing `conf_path` (or, if that is `None`, the current directory).
+fn include_path(
+    lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+    conf_path: Option<&Path>,
+    span: Span,
+) -> PathBuf {
+    let path = PathBuf::from(unescape_str(lexer.span_str(span)));
+    match conf_path.and_then(|x| x.parent()) {
+        Some(dir) if path.is_relative() => dir.join(path),
+        _ => path,
+    }
+}
+
 fn check_not_assigned<T>(
     lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
     name: &str,
@@ -297,6 +570,24 @@ fn check_not_assigned_str<T>(
     }
 }
 
+/// As [`check_not_assigned_str`], additionally checking that the string is an absolute path.
+fn check_not_assigned_abs_path<T>(
+    lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+    name: &str,
+    span: Span,
+    v: Option<T>,
+) -> Result<PathBuf, String> {
+    let path = PathBuf::from(check_not_assigned_str(lexer, name, span, v)?);
+    if !path.is_absolute() {
+        return Err(error_at_span(
+            lexer,
+            span,
+            &format!("'{name:}' must be an absolute path"),
+        ));
+    }
+    Ok(path)
+}
+
 fn check_not_assigned_time<'a, T>(
     lexer: &'a LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
     name: &str,
@@ -363,56 +654,109 @@ fn check_assigned<T>(
     }
 }
 
-/// If you add to the, or alter the semantics of any existing, fields in this struct, you *must*
-/// check whether any of the following also need to be chnaged:
-///   * `Account::secure_eq`
-///   * `Account::dump`
-///   * `Account::secure_restoreable`
-///   * `AccountDump`
-///
-/// These functions are vital to the security guarantees pizauth makes when reloading/restoring
-/// configurations.
-#[derive(Clone, Debug)]
-pub struct Account {
-    pub name: String,
-    pub auth_uri: String,
-    pub auth_uri_fields: Vec<(String, String)>,
-    pub client_id: String,
-    pub client_secret: Option<String>,
-    redirect_uri: String,
+/// The settings in an `account` or `template` block, before any template has been applied and
+/// before defaults have been filled in.
+#[derive(Clone, Default)]
+struct AccountSettings {
+    allow_exe: Option<Vec<PathBuf>>,
+    allow_uid: Option<Vec<u32>>,
+    auth_uri: Option<String>,
+    auth_uri_fields: Option<Vec<(String, String)>>,
+    client_assertion_key: Option<PathBuf>,
+    client_assertion_kid: Option<String>,
+    client_id: Option<String>,
+    /// The command and the span of the `client_id_cmd` field. The span is `None` if the command
+    /// was inherited from a template.
+    client_id_cmd: Option<(String, Option<Span>)>,
+    client_secret: Option<String>,
+    /// As `client_id_cmd`.
+    client_secret_cmd: Option<(String, Option<Span>)>,
+    device_auth_uri: Option<String>,
+    dpop: Option<bool>,
+    grant_type: Option<GrantType>,
+    issuer: Option<String>,
+    login_hint: Option<String>,
+    par_uri: Option<String>,
+    redirect_uri: Option<String>,
     refresh_at_least: Option<Duration>,
     refresh_before_expiry: Option<Duration>,
     refresh_retry: Option<Duration>,
-    pub scopes: Vec<String>,
-    pub token_uri: String,
+    revocation_uri: Option<String>,
+    scopes: Option<Vec<String>>,
+    tls_ca_cert: Option<PathBuf>,
+    tls_client_cert: Option<PathBuf>,
+    tls_client_key: Option<PathBuf>,
+    token_endpoint_auth_method: Option<TokenEndpointAuthMethod>,
+    token_uri: Option<String>,
+    username: Option<String>,
 }
 
-impl Account {
+impl AccountSettings {
     fn from_fields(
-        name: String,
         lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
-        overall_span: Span,
         fields: Vec<config_ast::AccountField>,
     ) -> Result<Self, String> {
-        let mut auth_uri = None;
-        let mut auth_uri_fields = None;
-        let mut client_id = None;
-        let mut client_secret = None;
-        let mut login_hint = None;
-        let mut redirect_uri = None;
-        let mut refresh_at_least = None;
-        let mut refresh_before_expiry = None;
-        let mut refresh_retry = None;
-        let mut scopes = None;
-        let mut token_uri = None;
-
+        let mut s = Self::default();
         for f in fields {
             match f {
+                config_ast::AccountField::AllowExe(span, spans) => {
+                    if s.allow_exe.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'allow_exe' more than once",
+                        ));
+                    }
+                    if !cfg!(target_os = "linux") {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "'allow_exe' is only supported on Linux",
+                        ));
+                    }
+                    let mut exes = Vec::with_capacity(spans.len());
+                    for sp in spans {
+                        let exe = PathBuf::from(unescape_str(lexer.span_str(sp)));
+                        if !exe.is_absolute() {
+                            return Err(error_at_span(
+                                lexer,
+                                sp,
+                                "'allow_exe' paths must be absolute",
+                            ));
+                        }
+                        exes.push(exe);
+                    }
+                    s.allow_exe = Some(exes);
+                }
+                config_ast::AccountField::AllowUid(span, spans) => {
+                    if s.allow_uid.is_some() {
+                        return Err(error_at_span(
+                            lexer,
+                            span,
+                            "Mustn't specify 'allow_uid' more than once",
+                        ));
+                    }
+                    let mut uids = Vec::with_capacity(spans.len());
+                    for sp in spans {
+                        uids.push(
+                            lexer
+                                .span_str(sp)
+                                .parse::<u32>()
+                                .map_err(|_| error_at_span(lexer, sp, "Invalid uid"))?,
+                        );
+                    }
+                    s.allow_uid = Some(uids);
+                }
                 config_ast::AccountField::AuthUri(span) => {
-                    auth_uri = Some(check_not_assigned_uri(lexer, "auth_uri", span, auth_uri)?);
+                    s.auth_uri = Some(check_not_assigned_uri(
+                        lexer,
+                        "auth_uri",
+                        span,
+                        s.auth_uri.as_ref(),
+                    )?);
                 }
                 config_ast::AccountField::AuthUriFields(span, spans) => {
-                    if auth_uri_fields.is_some() {
+                    if s.auth_uri_fields.is_some() {
                         debug_assert!(!spans.is_empty());
                         return Err(error_at_span(
                             lexer,
@@ -432,57 +776,150 @@ impl Account {
                         }
                         fields.push((key, unescape_str(lexer.span_str(*val_sp))));
                     }
-                    auth_uri_fields = Some(fields);
+                    s.auth_uri_fields = Some(fields);
+                }
+                config_ast::AccountField::ClientAssertionKey(span) => {
+                    s.client_assertion_key = Some(check_not_assigned_abs_path(
+                        lexer,
+                        "client_assertion_key",
+                        span,
+                        s.client_assertion_key.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::ClientAssertionKid(span) => {
+                    s.client_assertion_kid = Some(check_not_assigned_str(
+                        lexer,
+                        "client_assertion_kid",
+                        span,
+                        s.client_assertion_kid.as_ref(),
+                    )?);
                 }
                 config_ast::AccountField::ClientId(span) => {
-                    client_id = Some(check_not_assigned_str(lexer, "client_id", span, client_id)?);
+                    s.client_id = Some(check_not_assigned_str(
+                        lexer,
+                        "client_id",
+                        span,
+                        s.client_id.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::ClientIdCmd(span) => {
+                    s.client_id_cmd = Some((
+                        check_not_assigned_str(
+                            lexer,
+                            "client_id_cmd",
+                            span,
+                            s.client_id_cmd.as_ref(),
+                        )?,
+                        Some(span),
+                    ));
                 }
                 config_ast::AccountField::ClientSecret(span) => {
-                    client_secret = Some(check_not_assigned_str(
+                    s.client_secret = Some(check_not_assigned_str(
                         lexer,
                         "client_secret",
                         span,
-                        client_secret,
+                        s.client_secret.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::ClientSecretCmd(span) => {
+                    s.client_secret_cmd = Some((
+                        check_not_assigned_str(
+                            lexer,
+                            "client_secret_cmd",
+                            span,
+                            s.client_secret_cmd.as_ref(),
+                        )?,
+                        Some(span),
+                    ));
+                }
+                config_ast::AccountField::DeviceAuthUri(span) => {
+                    s.device_auth_uri = Some(check_not_assigned_uri(
+                        lexer,
+                        "device_auth_uri",
+                        span,
+                        s.device_auth_uri.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::Dpop(span) => {
+                    check_not_assigned(lexer, "dpop", span, s.dpop.as_ref())?;
+                    s.dpop = Some(lexer.span_str(span) == "true");
+                }
+                config_ast::AccountField::GrantType(span) => {
+                    check_not_assigned(lexer, "grant_type", span, s.grant_type.as_ref())?;
+                    s.grant_type = Some(match lexer.span_str(span) {
+                        "authorization_code" => GrantType::AuthorizationCode,
+                        "client_credentials" => GrantType::ClientCredentials,
+                        "device_code" => GrantType::DeviceCode,
+                        _ => unreachable!(),
+                    });
+                }
+                config_ast::AccountField::Issuer(span) => {
+                    s.issuer = Some(check_not_assigned_uri(
+                        lexer,
+                        "issuer",
+                        span,
+                        s.issuer.as_ref(),
                     )?);
                 }
                 config_ast::AccountField::LoginHint(span) => {
-                    login_hint = Some(check_not_assigned_str(
+                    s.login_hint = Some(check_not_assigned_str(
                         lexer,
                         "login_hint",
                         span,
-                        login_hint,
+                        s.login_hint.as_ref(),
                     )?);
                 }
                 config_ast::AccountField::RedirectUri(span) => {
-                    let uri = check_not_assigned_uri(lexer, "redirect_uri", span, redirect_uri)?;
-                    redirect_uri = Some(uri);
+                    let uri = check_not_assigned_uri(
+                        lexer,
+                        "redirect_uri",
+                        span,
+                        s.redirect_uri.as_ref(),
+                    )?;
+                    s.redirect_uri = Some(uri);
                 }
                 config_ast::AccountField::RefreshAtLeast(span) => {
-                    refresh_at_least = Some(time_str_to_duration(check_not_assigned_time(
+                    s.refresh_at_least = Some(time_str_to_duration(check_not_assigned_time(
                         lexer,
                         "refresh_at_least",
                         span,
-                        refresh_at_least,
+                        s.refresh_at_least.as_ref(),
                     )?)?);
                 }
                 config_ast::AccountField::RefreshBeforeExpiry(span) => {
-                    refresh_before_expiry = Some(time_str_to_duration(check_not_assigned_time(
+                    s.refresh_before_expiry = Some(time_str_to_duration(check_not_assigned_time(
                         lexer,
                         "refresh_before_expiry",
                         span,
-                        refresh_before_expiry,
+                        s.refresh_before_expiry.as_ref(),
                     )?)?);
                 }
                 config_ast::AccountField::RefreshRetry(span) => {
-                    refresh_retry = Some(time_str_to_duration(check_not_assigned_time(
+                    s.refresh_retry = Some(time_str_to_duration(check_not_assigned_time(
                         lexer,
                         "refresh_retry",
                         span,
-                        refresh_retry,
+                        s.refresh_retry.as_ref(),
                     )?)?);
                 }
+                config_ast::AccountField::ParUri(span) => {
+                    s.par_uri = Some(check_not_assigned_uri(
+                        lexer,
+                        "par_uri",
+                        span,
+                        s.par_uri.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::RevocationUri(span) => {
+                    s.revocation_uri = Some(check_not_assigned_uri(
+                        lexer,
+                        "revocation_uri",
+                        span,
+                        s.revocation_uri.as_ref(),
+                    )?);
+                }
                 config_ast::AccountField::Scopes(span, spans) => {
-                    if scopes.is_some() {
+                    if s.scopes.is_some() {
                         debug_assert!(!spans.is_empty());
                         return Err(error_at_span(
                             lexer,
@@ -490,76 +927,566 @@ impl Account {
                             "Mustn't specify 'scopes' more than once",
                         ));
                     }
-                    scopes = Some(
+                    s.scopes = Some(
                         spans
                             .iter()
                             .map(|sp| unescape_str(lexer.span_str(*sp)))
                             .collect::<Vec<String>>(),
                     );
                 }
+                config_ast::AccountField::TlsCaCert(span) => {
+                    s.tls_ca_cert = Some(check_not_assigned_abs_path(
+                        lexer,
+                        "tls_ca_cert",
+                        span,
+                        s.tls_ca_cert.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::TlsClientCert(span) => {
+                    s.tls_client_cert = Some(check_not_assigned_abs_path(
+                        lexer,
+                        "tls_client_cert",
+                        span,
+                        s.tls_client_cert.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::TlsClientKey(span) => {
+                    s.tls_client_key = Some(check_not_assigned_abs_path(
+                        lexer,
+                        "tls_client_key",
+                        span,
+                        s.tls_client_key.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::TokenEndpointAuthMethod(span) => {
+                    check_not_assigned(
+                        lexer,
+                        "token_endpoint_auth_method",
+                        span,
+                        s.token_endpoint_auth_method.as_ref(),
+                    )?;
+                    s.token_endpoint_auth_method = Some(match lexer.span_str(span) {
+                        "basic" => TokenEndpointAuthMethod::Basic,
+                        "post" => TokenEndpointAuthMethod::Post,
+                        "private_key_jwt" => TokenEndpointAuthMethod::PrivateKeyJwt,
+                        _ => unreachable!(),
+                    });
+                }
                 config_ast::AccountField::TokenUri(span) => {
-                    token_uri = Some(check_not_assigned_uri(lexer, "token_uri", span, token_uri)?);
+                    s.token_uri = Some(check_not_assigned_uri(
+                        lexer,
+                        "token_uri",
+                        span,
+                        s.token_uri.as_ref(),
+                    )?);
+                }
+                config_ast::AccountField::Username(span) => {
+                    s.username = Some(check_not_assigned_str(
+                        lexer,
+                        "username",
+                        span,
+                        s.username.as_ref(),
+                    )?);
                 }
             }
         }
 
-        let auth_uri = check_assigned(lexer, "auth_uri", overall_span, auth_uri)?;
-        let client_id = check_assigned(lexer, "client_id", overall_span, client_id)?;
-        let token_uri = check_assigned(lexer, "token_uri", overall_span, token_uri)?;
-
-        // We allow the deprecated `login_hint` field through but don't want to allow it to clash
-        // with a field of the same name in `auth_uri_fields`.
-        if let (Some(_), Some(auth_uri_fields)) = (&login_hint, &auth_uri_fields) {
-            if auth_uri_fields.iter().any(|(k, _)| k == "login_hint") {
-                return Err(error_at_span(lexer, overall_span, "Both the 'login_hint' attribute and a 'auth_uri_fields' field with the name 'login_hint' are specified. The 'login_hint' attribute is deprecated so remove it."));
-            }
+        if let (Some(_), Some((_, Some(span)))) = (&s.client_id, &s.client_id_cmd) {
+            return Err(error_at_span(
+                lexer,
+                *span,
+                "Mustn't specify both 'client_id' and 'client_id_cmd'",
+            ));
         }
-
-        Ok(Self {
-            name,
-            auth_uri,
-            auth_uri_fields: auth_uri_fields.unwrap_or_default(),
-            client_id,
-            client_secret,
-            redirect_uri: redirect_uri.unwrap_or_else(|| "http://localhost/".to_owned()),
-            refresh_at_least,
-            refresh_before_expiry,
-            refresh_retry,
-            scopes: scopes.unwrap_or_default(),
-            token_uri,
-        })
+        if let (Some(_), Some((_, Some(span)))) = (&s.client_secret, &s.client_secret_cmd) {
+            return Err(error_at_span(
+                lexer,
+                *span,
+                "Mustn't specify both 'client_secret' and 'client_secret_cmd'",
+            ));
+        }
+        Ok(s)
     }
 
-    /// Are the security relevant parts of this `Account` the same as `other`?
-    ///
-    /// Note that this is a weaker condition than "is `self` equal to `other`" because there are
-    /// some parts of an `Account`'s configuration that are irrelevant from a security perspective.
-    /// If you add new fields to, or change the semantics of existing fields in, `Account`, you
-    /// must reconsider this function.
-    pub fn secure_eq(&self, other: &Self) -> bool {
-        // Our definition of "are the security relevant parts of this `Account` the same as
-        // `other`" is roughly: if anything here changes could we end up giving out an access token
-        // that the user might send to the wrong server? Note that it is better to be safe than
-        // sorry: if in doubt, it is better to have more, rather than fewer, fields compared here.
-        self.name == other.name
-            && self.auth_uri == other.auth_uri
-            && self.auth_uri_fields == other.auth_uri_fields
-            && self.client_id == other.client_id
-            && self.client_secret == other.client_secret
-            && self.redirect_uri == other.redirect_uri
-            && self.scopes == other.scopes
-            && self.token_uri == other.token_uri
+    /// Use the settings from the template `tmpl` for any settings not specified in `self`. Lists
+    /// (e.g. `scopes`) are not merged: if `self` specifies a list, it replaces `tmpl`'s entirely.
+    fn inherit(&mut self, tmpl: &Self) {
+        // `client_id` and `client_id_cmd` are alternatives, so specifying either overrides both
+        // in the template; similarly for `client_secret` and `client_secret_cmd`.
+        if self.client_id.is_none() && self.client_id_cmd.is_none() {
+            self.client_id.clone_from(&tmpl.client_id);
+            self.client_id_cmd = tmpl.client_id_cmd.as_ref().map(|(x, _)| (x.clone(), None));
+        }
+        if self.client_secret.is_none() && self.client_secret_cmd.is_none() {
+            self.client_secret.clone_from(&tmpl.client_secret);
+            self.client_secret_cmd = tmpl
+                .client_secret_cmd
+                .as_ref()
+                .map(|(x, _)| (x.clone(), None));
+        }
+        self.allow_exe = self.allow_exe.take().or_else(|| tmpl.allow_exe.clone());
+        self.allow_uid = self.allow_uid.take().or_else(|| tmpl.allow_uid.clone());
+        self.auth_uri = self.auth_uri.take().or_else(|| tmpl.auth_uri.clone());
+        self.auth_uri_fields = self
+            .auth_uri_fields
+            .take()
+            .or_else(|| tmpl.auth_uri_fields.clone());
+        self.client_assertion_key = self
+            .client_assertion_key
+            .take()
+            .or_else(|| tmpl.client_assertion_key.clone());
+        self.client_assertion_kid = self
+            .client_assertion_kid
+            .take()
+            .or_else(|| tmpl.client_assertion_kid.clone());
+        self.device_auth_uri = self
+            .device_auth_uri
+            .take()
+            .or_else(|| tmpl.device_auth_uri.clone());
+        self.dpop = self.dpop.or(tmpl.dpop);
+        self.grant_type = self.grant_type.or(tmpl.grant_type);
+        self.issuer = self.issuer.take().or_else(|| tmpl.issuer.clone());
+        self.login_hint = self.login_hint.take().or_else(|| tmpl.login_hint.clone());
+        self.par_uri = self.par_uri.take().or_else(|| tmpl.par_uri.clone());
+        self.redirect_uri = self
+            .redirect_uri
+            .take()
+            .or_else(|| tmpl.redirect_uri.clone());
+        self.refresh_at_least = self.refresh_at_least.or(tmpl.refresh_at_least);
+        self.refresh_before_expiry = self.refresh_before_expiry.or(tmpl.refresh_before_expiry);
+        self.refresh_retry = self.refresh_retry.or(tmpl.refresh_retry);
+        self.revocation_uri = self
+            .revocation_uri
+            .take()
+            .or_else(|| tmpl.revocation_uri.clone());
+        self.scopes = self.scopes.take().or_else(|| tmpl.scopes.clone());
+        self.tls_ca_cert = self.tls_ca_cert.take().or_else(|| tmpl.tls_ca_cert.clone());
+        self.tls_client_cert = self
+            .tls_client_cert
+            .take()
+            .or_else(|| tmpl.tls_client_cert.clone());
+        self.tls_client_key = self
+            .tls_client_key
+            .take()
+            .or_else(|| tmpl.tls_client_key.clone());
+        self.token_endpoint_auth_method = self
+            .token_endpoint_auth_method
+            .or(tmpl.token_endpoint_auth_method);
+        self.token_uri = self.token_uri.take().or_else(|| tmpl.token_uri.clone());
+        self.username = self.username.take().or_else(|| tmpl.username.clone());
+    }
+}
+
+/// Where the server persists token state so that it survives restarts.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateStorage {
+    /// Token state is not persisted.
+    None,
+    /// Token state is written to `state_file`.
+    File,
+    /// Token state is stored in the Linux kernel keyring specified by `kernel_keyring`.
+    KernelKeyring,
+    /// Token state is stored with a freedesktop Secret Service provider.
+    SecretService,
+}
+
+/// Which of the Linux kernel's keyrings token state is stored in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KernelKeyring {
+    /// The session keyring, which is discarded when the user's session ends.
+    Session,
+    /// The user keyring, which lasts until the machine is rebooted.
+    User,
+}
+
+/// The OAuth2 grant an account uses to obtain new access tokens.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, SchemaRead, SchemaWrite)]
+pub enum GrantType {
+    /// The authorization code grant (RFC 6749): the user authorises the request in a web browser,
+    /// which is then redirected to pizauth's HTTP(S) server.
+    AuthorizationCode,
+    /// The client credentials grant (RFC 6749 section 4.4): the client authenticates as itself,
+    /// with no user involvement, and new access tokens are requested directly from the token
+    /// endpoint rather than by using a refresh token.
+    ClientCredentials,
+    /// The device authorization grant (RFC 8628): the user authorises the request by visiting a
+    /// URL, possibly on another machine, and entering a code.
+    DeviceCode,
+}
+
+/// How an account authenticates itself, as a client, to the token endpoint.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenEndpointAuthMethod {
+    /// `client_secret`, if there is one, is sent in the request body (`client_secret_post`).
+    Post,
+    /// `client_id` and `client_secret` are sent using HTTP Basic authentication
+    /// (`client_secret_basic`).
+    Basic,
+    /// A JWT signed with `client_assertion_key` is sent in the request body (RFC 7523).
+    PrivateKeyJwt,
+}
+
+/// A client credential (`client_id` or `client_secret`).
+#[derive(Clone, Debug)]
+pub enum Credential {
+    /// A credential specified directly in the configuration.
+    Value(String),
+    /// A credential which is the output of `cmd`. `cmd` is only run the first time the credential
+    /// is needed, after which its output is cached in `output` until the configuration is
+    /// reloaded. `desc` describes the field, and where it was specified, for error messages.
+    Cmd {
+        desc: String,
+        cmd: String,
+        output: Arc<Mutex<Option<String>>>,
+    },
+}
+
+impl Credential {
+    fn cmd(desc: String, cmd: String) -> Self {
+        Self::Cmd {
+            desc,
+            cmd,
+            output: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Return the credential, running its command if it has not yet been run successfully. This
+    /// may block for as long as [`SECRET_CMD_TIMEOUT`] so must not be called with the server's lock
+    /// held. Returns `Err(String)` (containing a human readable message) if the command fails or
+    /// produces no output.
+    pub fn get(&self) -> Result<String, String> {
+        let (desc, cmd, output) = match self {
+            Self::Value(x) => return Ok(x.clone()),
+            Self::Cmd { desc, cmd, output } => (desc, cmd, output),
+        };
+        // Holding the lock while the command runs means that concurrent requests for the same
+        // credential wait for a single run of the command.
+        let mut output_lk = output.lock().unwrap();
+        if let Some(x) = &*output_lk {
+            return Ok(x.clone());
+        }
+        match shell_cmd_stdout(cmd, [], SECRET_CMD_TIMEOUT) {
+            Ok(x) if x.trim().is_empty() => Err(format!("{desc} produced no output")),
+            Ok(x) => Ok(output_lk.insert(x.trim().to_owned()).clone()),
+            Err(e) => Err(format!("{desc} failed: {e}")),
+        }
+    }
+
+    fn dump(&self) -> CredentialDump {
+        match self {
+            Self::Value(x) => CredentialDump::Value(x.clone()),
+            Self::Cmd { cmd, .. } => CredentialDump::Cmd(cmd.clone()),
+        }
+    }
+}
+
+/// Credentials are equal if they are specified in the same way: a command's output is not
+/// compared, so a changed secret does not, by itself, cause tokens to be discarded.
+impl PartialEq for Credential {
+    fn eq(&self, other: &Self) -> bool {
+        self.dump() == other.dump()
+    }
+}
+
+#[derive(Deserialize, Serialize, SchemaRead, SchemaWrite, PartialEq)]
+pub enum CredentialDump {
+    Value(String),
+    Cmd(String),
+}
+
+/// An OAuth2 server's metadata, as discovered from an account's `issuer` (OIDC Discovery or RFC
+/// 8414). Endpoints explicitly specified in an account take precedence over those found here.
+/// Field names are those used by the metadata specifications.
+#[allow(clippy::struct_field_names)]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ProviderMetadata {
+    /// Does the server include `iss` in authorization responses (RFC 9207)?
+    pub authorization_response_iss_parameter_supported: bool,
+    pub authorization_endpoint: Option<String>,
+    pub device_authorization_endpoint: Option<String>,
+    pub revocation_endpoint: Option<String>,
+    pub token_endpoint: Option<String>,
+}
+
+/// If you add to the, or alter the semantics of any existing, fields in this struct, you *must*
+/// check whether any of the following also need to be chnaged:
+///   * `Account::secure_eq`
+///   * `Account::dump`
+///   * `Account::secure_restoreable`
+///   * `AccountDump`
+///
+/// These functions are vital to the security guarantees pizauth makes when reloading/restoring
+/// configurations.
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub name: String,
+    /// If not `None`, only processes whose executable, or one of whose ancestors' executables, is
+    /// in this list can access this account over the control socket. This is not relevant to
+    /// `secure_eq` etc., since it does not influence where access tokens are sent.
+    pub allow_exe: Option<Vec<PathBuf>>,
+    /// If not `None`, only processes running as one of these uids can access this account over
+    /// the control socket; if `None`, only processes running as the server's uid can.
+    pub allow_uid: Option<Vec<u32>>,
+    auth_uri: Option<String>,
+    pub auth_uri_fields: Vec<(String, String)>,
+    /// The private key used to sign client assertions if `token_endpoint_auth_method` is
+    /// `PrivateKeyJwt`.
+    pub client_assertion_key: Option<PathBuf>,
+    /// The `kid` included in client assertions' headers.
+    pub client_assertion_kid: Option<String>,
+    pub client_id: Credential,
+    pub client_secret: Option<Credential>,
+    device_auth_uri: Option<String>,
+    /// Are access tokens bound to a key pair with DPoP (RFC 9449)?
+    pub dpop: bool,
+    pub grant_type: GrantType,
+    pub issuer: Option<String>,
+    /// The metadata discovered from `issuer`: `None` if `issuer` is `None` or if discovery has not
+    /// yet happened.
+    metadata: Option<ProviderMetadata>,
+    /// If not `None`, authorisation requests are pushed to this endpoint (RFC 9126), and only a
+    /// reference to the request is included in the URL given to the user. This is not relevant to
+    /// `secure_eq` etc., since tokens are still only obtained from, and sent to, `token_uri`.
+    pub par_uri: Option<String>,
+    redirect_uri: String,
+    refresh_at_least: Option<Duration>,
+    refresh_before_expiry: Option<Duration>,
+    refresh_retry: Option<Duration>,
+    revocation_uri: Option<String>,
+    pub scopes: Vec<String>,
+    /// If not `None`, the CA certificates trusted, instead of the default roots, when connecting
+    /// to the OAuth2 server. Since the file is read each time it is used, comparing its path in
+    /// `secure_eq` etc. would not tell us whether the trusted certificates have changed.
+    pub tls_ca_cert: Option<PathBuf>,
+    /// The certificate chain and private key presented to the OAuth2 server for mutual TLS (RFC
+    /// 8705). Like the other client credentials, these are not relevant to `secure_eq` etc., since
+    /// they do not influence where access tokens are sent.
+    pub tls_client_cert: Option<PathBuf>,
+    pub tls_client_key: Option<PathBuf>,
+    /// This is not relevant to `secure_eq` etc., since it does not influence where access tokens
+    /// are sent.
+    pub token_endpoint_auth_method: TokenEndpointAuthMethod,
+    token_uri: Option<String>,
+    /// The user name to pair with the access token in SASL strings (see `pizauth show --format`).
+    /// This is not security relevant, since it does not influence where access tokens are sent.
+    pub username: Option<String>,
+}
+
+impl Account {
+    fn from_fields(
+        name: String,
+        lexer: &LRNonStreamingLexer<DefaultLexerTypes<StorageT>>,
+        conf_path: Option<&Path>,
+        overall_span: Span,
+        template: Option<&AccountSettings>,
+        fields: Vec<config_ast::AccountField>,
+    ) -> Result<Self, String> {
+        let mut s = AccountSettings::from_fields(lexer, fields)?;
+        if let Some(tmpl) = template {
+            s.inherit(tmpl);
+        }
+        let AccountSettings {
+            allow_exe,
+            allow_uid,
+            auth_uri,
+            auth_uri_fields,
+            client_assertion_key,
+            client_assertion_kid,
+            client_id,
+            client_id_cmd,
+            client_secret,
+            client_secret_cmd,
+            device_auth_uri,
+            dpop,
+            grant_type,
+            issuer,
+            login_hint,
+            par_uri,
+            redirect_uri,
+            refresh_at_least,
+            refresh_before_expiry,
+            refresh_retry,
+            revocation_uri,
+            scopes,
+            tls_ca_cert,
+            tls_client_cert,
+            tls_client_key,
+            token_endpoint_auth_method,
+            token_uri,
+            username,
+        } = s;
+
+        let grant_type = grant_type.unwrap_or(GrantType::AuthorizationCode);
+        // If an issuer is specified, endpoints that aren't explicitly specified will be
+        // discovered later: see `Account::set_metadata`.
+        if issuer.is_none() {
+            match grant_type {
+                GrantType::AuthorizationCode => {
+                    check_assigned(lexer, "auth_uri", overall_span, auth_uri.as_ref())?;
+                }
+                GrantType::ClientCredentials => (),
+                GrantType::DeviceCode => {
+                    check_assigned(
+                        lexer,
+                        "device_auth_uri",
+                        overall_span,
+                        device_auth_uri.as_ref(),
+                    )?;
+                }
+            }
+            check_assigned(lexer, "token_uri", overall_span, token_uri.as_ref())?;
+        }
+        let token_endpoint_auth_method =
+            token_endpoint_auth_method.unwrap_or(TokenEndpointAuthMethod::Post);
+        match token_endpoint_auth_method {
+            TokenEndpointAuthMethod::Basic
+                if client_secret.is_none() && client_secret_cmd.is_none() =>
+            {
+                return Err(error_at_span(
+                    lexer,
+                    overall_span,
+                    "'token_endpoint_auth_method = basic' requires 'client_secret' or 'client_secret_cmd'",
+                ));
+            }
+            TokenEndpointAuthMethod::PrivateKeyJwt => {
+                check_assigned(
+                    lexer,
+                    "client_assertion_key",
+                    overall_span,
+                    client_assertion_key.as_ref(),
+                )?;
+            }
+            _ if client_assertion_key.is_some() || client_assertion_kid.is_some() => {
+                return Err(error_at_span(
+                    lexer,
+                    overall_span,
+                    "'client_assertion_key' and 'client_assertion_kid' require 'token_endpoint_auth_method = private_key_jwt'",
+                ));
+            }
+            _ => (),
+        }
+        if par_uri.is_some() && grant_type != GrantType::AuthorizationCode {
+            return Err(error_at_span(
+                lexer,
+                overall_span,
+                "'par_uri' can only be used with 'grant_type = authorization_code'",
+            ));
+        }
+        if tls_client_cert.is_some() != tls_client_key.is_some() {
+            return Err(error_at_span(
+                lexer,
+                overall_span,
+                "'tls_client_cert' and 'tls_client_key' must be specified together",
+            ));
+        }
+        // Commands are only run when their output is first needed, by which time the config's
+        // lexer is long gone, so we record where they were specified for any error messages.
+        let cmd_credential = |field: &str, cmd: String, span: Option<Span>| {
+            let location = match (span, conf_path) {
+                (Some(span), Some(p)) => {
+                    let ((line, col), _) = lexer.line_col(span);
+                    format!(" ({}: line {line}, column {col})", p.display())
+                }
+                (Some(span), None) => {
+                    let ((line, col), _) = lexer.line_col(span);
+                    format!(" (line {line}, column {col})")
+                }
+                (None, _) => " (inherited from template)".to_owned(),
+            };
+            Credential::cmd(format!("'{field}' for account '{name}'{location}"), cmd)
+        };
+        let client_id = match (client_id, client_id_cmd) {
+            (None, Some((cmd, span))) => Some(cmd_credential("client_id_cmd", cmd, span)),
+            (x, _) => x.map(Credential::Value),
+        };
+        let client_id = check_assigned(lexer, "client_id", overall_span, client_id)?;
+        let client_secret = match (client_secret, client_secret_cmd) {
+            (None, Some((cmd, span))) => Some(cmd_credential("client_secret_cmd", cmd, span)),
+            (x, _) => x.map(Credential::Value),
+        };
+
+        // We allow the deprecated `login_hint` field through but don't want to allow it to clash
+        // with a field of the same name in `auth_uri_fields`.
+        if let (Some(_), Some(auth_uri_fields)) = (&login_hint, &auth_uri_fields) {
+            if auth_uri_fields.iter().any(|(k, _)| k == "login_hint") {
+                return Err(error_at_span(lexer, overall_span, "Both the 'login_hint' attribute and a 'auth_uri_fields' field with the name 'login_hint' are specified. The 'login_hint' attribute is deprecated so remove it."));
+            }
+        }
+
+        Ok(Self {
+            name,
+            allow_exe,
+            allow_uid,
+            auth_uri,
+            auth_uri_fields: auth_uri_fields.unwrap_or_default(),
+            client_assertion_key,
+            client_assertion_kid,
+            client_id,
+            client_secret,
+            device_auth_uri,
+            dpop: dpop.unwrap_or(false),
+            grant_type,
+            issuer,
+            metadata: None,
+            par_uri,
+            redirect_uri: redirect_uri.unwrap_or_else(|| "http://localhost/".to_owned()),
+            refresh_at_least,
+            refresh_before_expiry,
+            refresh_retry,
+            revocation_uri,
+            scopes: scopes.unwrap_or_default(),
+            tls_ca_cert,
+            tls_client_cert,
+            tls_client_key,
+            token_endpoint_auth_method,
+            token_uri,
+            username,
+        })
+    }
+
+    /// Are the security relevant parts of this `Account` the same as `other`?
+    ///
+    /// Note that this is a weaker condition than "is `self` equal to `other`" because there are
+    /// some parts of an `Account`'s configuration that are irrelevant from a security perspective.
+    /// If you add new fields to, or change the semantics of existing fields in, `Account`, you
+    /// must reconsider this function.
+    pub fn secure_eq(&self, other: &Self) -> bool {
+        // Our definition of "are the security relevant parts of this `Account` the same as
+        // `other`" is roughly: if anything here changes could we end up giving out an access token
+        // that the user might send to the wrong server? Note that it is better to be safe than
+        // sorry: if in doubt, it is better to have more, rather than fewer, fields compared here.
+        //
+        // Endpoints are compared after discovery has filled them in, so a changed discovery
+        // document is treated in the same way as a changed configuration.
+        self.name == other.name
+            && self.auth_uri() == other.auth_uri()
+            && self.auth_uri_fields == other.auth_uri_fields
+            && self.client_id == other.client_id
+            && self.client_secret == other.client_secret
+            && self.device_auth_uri() == other.device_auth_uri()
+            && self.dpop == other.dpop
+            && self.grant_type == other.grant_type
+            && self.issuer == other.issuer
+            && self.redirect_uri == other.redirect_uri
+            && self.revocation_uri() == other.revocation_uri()
+            && self.scopes == other.scopes
+            && self.token_uri_opt() == other.token_uri_opt()
     }
 
     pub fn dump(&self) -> AccountDump {
         AccountDump {
-            auth_uri: self.auth_uri.clone(),
+            auth_uri: self.auth_uri().map(|x| x.to_owned()),
             auth_uri_fields: self.auth_uri_fields.clone(),
-            client_id: self.client_id.clone(),
-            client_secret: self.client_secret.clone(),
+            client_id: self.client_id.dump(),
+            client_secret: self.client_secret.as_ref().map(Credential::dump),
+            device_auth_uri: self.device_auth_uri().map(|x| x.to_owned()),
+            dpop: self.dpop,
+            grant_type: self.grant_type,
+            issuer: self.issuer.clone(),
             redirect_uri: self.redirect_uri.clone(),
+            revocation_uri: self.revocation_uri().map(|x| x.to_owned()),
             scopes: self.scopes.clone(),
-            token_uri: self.token_uri.clone(),
+            token_uri: self.token_uri_opt().map(|x| x.to_owned()),
         }
     }
 
@@ -568,13 +1495,119 @@ impl Account {
     /// equal with `secure_eq` to `self`? If `true`, then it is safe to restore `self`'s
     /// tokenstate from `act_dump`.
     pub fn secure_restorable(&self, act_dump: &AccountDump) -> bool {
-        self.auth_uri == act_dump.auth_uri
+        self.auth_uri() == act_dump.auth_uri.as_deref()
             && self.auth_uri_fields == act_dump.auth_uri_fields
-            && self.client_id == act_dump.client_id
-            && self.client_secret == act_dump.client_secret
+            && self.client_id.dump() == act_dump.client_id
+            && self.client_secret.as_ref().map(Credential::dump) == act_dump.client_secret
+            && self.device_auth_uri() == act_dump.device_auth_uri.as_deref()
+            && self.dpop == act_dump.dpop
+            && self.grant_type == act_dump.grant_type
+            && self.issuer == act_dump.issuer
             && self.redirect_uri == act_dump.redirect_uri
+            && self.revocation_uri() == act_dump.revocation_uri.as_deref()
             && self.scopes == act_dump.scopes
-            && self.token_uri == act_dump.token_uri
+            && self.token_uri_opt() == act_dump.token_uri.as_deref()
+    }
+
+    /// Set the metadata discovered from this account's `issuer`, checking that all the endpoints
+    /// this account needs are now known. Returns `Err(String)` (containing a human readable
+    /// message) if not.
+    pub fn set_metadata(&mut self, metadata: ProviderMetadata) -> Result<(), String> {
+        self.metadata = Some(metadata);
+        let mut missing = Vec::new();
+        match self.grant_type {
+            GrantType::AuthorizationCode if self.auth_uri().is_none() => missing.push("auth_uri"),
+            GrantType::DeviceCode if self.device_auth_uri().is_none() => {
+                missing.push("device_auth_uri");
+            }
+            _ => (),
+        }
+        if self.token_uri_opt().is_none() {
+            missing.push("token_uri");
+        }
+        if missing.is_empty() {
+            Ok(())
+        } else {
+            Err(format!(
+                "Account {}: {} neither specified nor discovered from issuer",
+                self.name,
+                missing.join(", ")
+            ))
+        }
+    }
+
+    /// The metadata discovered from `issuer`, if discovery has happened.
+    pub fn metadata(&self) -> Option<&ProviderMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Check the `iss` parameter `iss` of an authorization response against this account's issuer
+    /// (RFC 9207), guarding against mix-up attacks where a response from one server is passed off
+    /// as coming from another. Returns `Err(String)` (containing a human readable message) if the
+    /// response may not have come from this account's server.
+    pub fn check_response_issuer(&self, iss: Option<&str>) -> Result<(), String> {
+        let expected = self.issuer.as_deref();
+        let required = self
+            .metadata
+            .as_ref()
+            .is_some_and(|x| x.authorization_response_iss_parameter_supported);
+        match (iss, expected) {
+            // Issuer identifiers are compared as simple strings (RFC 9207 section 2.4).
+            (Some(x), Some(expected)) if x != expected => Err(format!(
+                "authorization response is from issuer '{x}' not '{expected}'"
+            )),
+            (None, Some(_)) if required => {
+                Err("authorization response does not contain 'iss'".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The authorization endpoint, if it was either specified or discovered.
+    pub fn auth_uri(&self) -> Option<&str> {
+        self.auth_uri.as_deref().or_else(|| {
+            self.metadata
+                .as_ref()
+                .and_then(|x| x.authorization_endpoint.as_deref())
+        })
+    }
+
+    /// The device authorization endpoint, if it was either specified or discovered.
+    pub fn device_auth_uri(&self) -> Option<&str> {
+        self.device_auth_uri.as_deref().or_else(|| {
+            self.metadata
+                .as_ref()
+                .and_then(|x| x.device_authorization_endpoint.as_deref())
+        })
+    }
+
+    /// The token revocation endpoint, if it was either specified or discovered.
+    pub fn revocation_uri(&self) -> Option<&str> {
+        self.revocation_uri.as_deref().or_else(|| {
+            self.metadata
+                .as_ref()
+                .and_then(|x| x.revocation_endpoint.as_deref())
+        })
+    }
+
+    /// The token endpoint.
+    ///
+    /// # Panics
+    ///
+    /// If the token endpoint was neither specified nor discovered. The former is checked when the
+    /// config is parsed and the latter by [`Account::set_metadata`], so this can only happen if an
+    /// account with an `issuer` is used before discovery has happened.
+    pub fn token_uri(&self) -> &str {
+        self.token_uri_opt()
+            .expect("token_uri neither specified nor discovered")
+    }
+
+    fn token_uri_opt(&self) -> Option<&str> {
+        self.token_uri.as_deref().or_else(|| {
+            self.metadata
+                .as_ref()
+                .and_then(|x| x.token_endpoint.as_deref())
+        })
     }
 
     pub fn redirect_uri(
@@ -615,6 +1648,24 @@ impl Account {
 
 #[derive(Deserialize, Serialize, SchemaRead, SchemaWrite)]
 pub struct AccountDump {
+    auth_uri: Option<String>,
+    auth_uri_fields: Vec<(String, String)>,
+    client_id: CredentialDump,
+    client_secret: Option<CredentialDump>,
+    device_auth_uri: Option<String>,
+    dpop: bool,
+    grant_type: GrantType,
+    issuer: Option<String>,
+    redirect_uri: String,
+    revocation_uri: Option<String>,
+    scopes: Vec<String>,
+    token_uri: Option<String>,
+}
+
+/// The format of [`AccountDump`] in version 1 dumps, from before accounts could use grants other
+/// than the authorization code grant or have their endpoints discovered.
+#[derive(Deserialize, Serialize, SchemaRead, SchemaWrite)]
+pub struct AccountDumpV1 {
     auth_uri: String,
     auth_uri_fields: Vec<(String, String)>,
     client_id: String,
@@ -624,6 +1675,38 @@ pub struct AccountDump {
     token_uri: String,
 }
 
+impl From<AccountDumpV1> for AccountDump {
+    fn from(d: AccountDumpV1) -> Self {
+        Self {
+            auth_uri: Some(d.auth_uri),
+            auth_uri_fields: d.auth_uri_fields,
+            client_id: CredentialDump::Value(d.client_id),
+            client_secret: d.client_secret.map(CredentialDump::Value),
+            device_auth_uri: None,
+            dpop: false,
+            grant_type: GrantType::AuthorizationCode,
+            issuer: None,
+            redirect_uri: d.redirect_uri,
+            revocation_uri: None,
+            scopes: d.scopes,
+            token_uri: Some(d.token_uri),
+        }
+    }
+}
+
+/// Parse a time given by the user outside the config file (e.g. on the command line) in the same
+/// `[0-9]+[dhms]` format used by the config file.
+pub fn parse_time(t: &str) -> Result<Duration, String> {
+    match t.char_indices().last() {
+        Some((i, 'd' | 'h' | 'm' | 's')) if i > 0 && t[..i].chars().all(|c| c.is_ascii_digit()) => {
+            time_str_to_duration(t)
+        }
+        _ => Err(format!(
+            "Invalid time '{t}': must be of the form [0-9]+[dhms]"
+        )),
+    }
+}
+
 /// Given a time duration in the format `[0-9]+[dhms]` return a [Duration].
 ///
 /// # Panics
@@ -729,6 +1812,18 @@ mod test {
         assert!(time_str_to_duration("9223372036854775808m").is_err());
     }
 
+    #[test]
+    fn test_parse_time() {
+        assert_eq!(parse_time("10m"), Ok(Duration::from_mins(10)));
+        assert_eq!(parse_time("0s"), Ok(Duration::from_secs(0)));
+        assert!(parse_time("").is_err());
+        assert!(parse_time("m").is_err());
+        assert!(parse_time("10").is_err());
+        assert!(parse_time("1x").is_err());
+        assert!(parse_time("-1s").is_err());
+        assert!(parse_time("99999999999999999999d").is_err());
+    }
+
     #[test]
     fn string_escapes() {
         let lexerdef = config_l::lexerdef();
@@ -776,7 +1871,8 @@ mod test {
         assert_eq!(c.token_event_cmd, Some("q".to_owned()));
 
         let act = &c.accounts["x"];
-        assert_eq!(act.auth_uri, "http://a.com");
+        assert_eq!(act.auth_uri(), Some("http://a.com"));
+        assert_eq!(act.grant_type, GrantType::AuthorizationCode);
         assert_eq!(
             &act.auth_uri_fields,
             &[
@@ -785,16 +1881,84 @@ mod test {
                 ("l".to_owned(), "p".to_owned())
             ]
         );
-        assert_eq!(act.client_id, "b");
-        assert_eq!(act.client_secret, Some("h".to_owned()));
+        assert_eq!(act.client_id.get().unwrap(), "b");
+        assert_eq!(act.client_secret.as_ref().unwrap().get().unwrap(), "h");
         assert_eq!(act.redirect_uri, "http://e.com");
-        assert_eq!(act.token_uri, "http://f.com");
+        assert_eq!(act.token_uri(), "http://f.com");
         assert_eq!(&act.scopes, &["c".to_owned(), "d".to_owned()]);
         assert_eq!(act.refresh_at_least, Some(Duration::from_mins(43)));
         assert_eq!(act.refresh_before_expiry, Some(Duration::from_secs(42)));
         assert_eq!(act.refresh_retry(&c), Duration::from_secs(33));
     }
 
+    #[test]
+    fn state_file() {
+        let c = Config::from_str(
+            r#"
+            state_file = "/a/b";
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                token_uri = "http://f.com";
+            }
+        "#,
+        )
+        .unwrap();
+        assert_eq!(c.state_file, Some(PathBuf::from("/a/b")));
+        assert_eq!(c.state_storage, StateStorage::File);
+
+        match Config::from_str(r#"state_file = "a/b";"#) {
+            Err(s) if s.contains("'state_file' must be an absolute path") => (),
+            _ => panic!(),
+        }
+        match Config::from_str(r#"state_file = "/a"; state_file = "/b";"#) {
+            Err(s) if s.contains("Mustn't specify 'state_file' more than once") => (),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn state_storage() {
+        let act = r#"account "x" { auth_uri = "http://a.com"; client_id = "b"; token_uri = "http://f.com"; }"#;
+        let c = Config::from_str(act).unwrap();
+        assert_eq!(c.state_storage, StateStorage::None);
+        let c = Config::from_str(&format!("state_storage = none; {act}")).unwrap();
+        assert_eq!(c.state_storage, StateStorage::None);
+        #[cfg(target_os = "linux")]
+        {
+            let c = Config::from_str(&format!("state_storage = kernel_keyring; {act}")).unwrap();
+            assert_eq!(c.state_storage, StateStorage::KernelKeyring);
+            assert_eq!(c.kernel_keyring, KernelKeyring::User);
+            let c = Config::from_str(&format!(
+                "state_storage = kernel_keyring; kernel_keyring = session; {act}"
+            ))
+            .unwrap();
+            assert_eq!(c.kernel_keyring, KernelKeyring::Session);
+        }
+        match Config::from_str(&format!("kernel_keyring = session; {act}")) {
+            Err(s)
+                if s.contains(
+                    "'kernel_keyring' can only be set if 'state_storage = kernel_keyring'",
+                ) => {}
+            _ => panic!(),
+        }
+
+        match Config::from_str(&format!("state_storage = file; {act}")) {
+            Err(s) if s.contains("'state_storage = file' requires 'state_file' to be set") => (),
+            _ => panic!(),
+        }
+        match Config::from_str(&format!(
+            r#"state_storage = none; state_file = "/a"; {act}"#
+        )) {
+            Err(s) if s.contains("'state_file' can only be set if 'state_storage = file'") => (),
+            _ => panic!(),
+        }
+        match Config::from_str("state_storage = none; state_storage = none;") {
+            Err(s) if s.contains("Mustn't specify 'state_storage' more than once") => (),
+            _ => panic!(),
+        }
+    }
+
     #[test]
     fn at_least_one_account() {
         assert_eq!(
@@ -813,6 +1977,10 @@ mod test {
 
     #[test]
     fn dup_fields() {
+        match Config::from_str(r#"audit_log = "/a"; audit_log = "/b";"#) {
+            Err(s) if s.contains("Mustn't specify 'audit_log' more than once") => (),
+            _ => panic!(),
+        }
         match Config::from_str(r#"auth_notify_cmd = "a"; auth_notify_cmd = "a";"#) {
             Err(s) if s.contains("Mustn't specify 'auth_notify_cmd' more than once") => (),
             _ => panic!(),
@@ -866,18 +2034,42 @@ mod test {
             }
         }
 
+        account_dup("allow_uid", &["[1]", "[2]"]);
         account_dup("auth_uri", &[r#""http://a.com/""#, r#""http://b.com/""#]);
         account_dup("auth_uri_fields", &[r#"{"a": "b"}"#, r#"{"c": "d"}"#]);
+        account_dup("client_assertion_key", &[r#""/a""#, r#""/b""#]);
+        account_dup("client_assertion_kid", &[r#""a""#, r#""b""#]);
         account_dup("client_id", &[r#""a""#, r#""b""#]);
+        account_dup("client_id_cmd", &[r#""a""#, r#""b""#]);
         account_dup("client_secret", &[r#""a""#, r#""b""#]);
+        account_dup("client_secret_cmd", &[r#""a""#, r#""b""#]);
+        account_dup(
+            "device_auth_uri",
+            &[r#""http://a.com/""#, r#""http://b.com/""#],
+        );
+        account_dup("dpop", &["true", "false"]);
+        account_dup(
+            "grant_type",
+            &["authorization_code", "client_credentials", "device_code"],
+        );
+        account_dup("issuer", &[r#""http://a.com/""#, r#""http://b.com/""#]);
         account_dup("login_hint", &[r#""a""#, r#""b""#]);
+        account_dup("par_uri", &[r#""http://a.com/""#, r#""http://b.com/""#]);
         account_dup(
             "redirect_uri",
             &[r#""http://a.com/""#, r#""http://b.com/""#],
         );
         account_dup("refresh_before_expiry", &["1m", "2m"]);
         account_dup("refresh_at_least", &["1m", "2m"]);
+        account_dup(
+            "revocation_uri",
+            &[r#""http://a.com/""#, r#""http://b.com/""#],
+        );
         account_dup("scopes", &[r#"["a"]"#, r#"["b"]"#]);
+        account_dup("tls_ca_cert", &[r#""/a""#, r#""/b""#]);
+        account_dup("tls_client_cert", &[r#""/a""#, r#""/b""#]);
+        account_dup("tls_client_key", &[r#""/a""#, r#""/b""#]);
+        account_dup("token_endpoint_auth_method", &["basic", "post"]);
         account_dup("token_uri", &[r#""http://a.com/""#, r#""http://b.com/""#]);
     }
 
@@ -984,7 +2176,11 @@ mod test {
         }
 
         invalid_uri("auth_uri");
+        invalid_uri("device_auth_uri");
+        invalid_uri("issuer");
+        invalid_uri("par_uri");
         invalid_uri("redirect_uri");
+        invalid_uri("revocation_uri");
         invalid_uri("token_uri");
     }
 
@@ -1043,6 +2239,246 @@ mod test {
         }
     }
 
+    #[test]
+    fn device_code_grant() {
+        let c = Config::from_str(
+            r#"
+            account "x" {
+                client_id = "a";
+                device_auth_uri = "http://b.com";
+                grant_type = device_code;
+                token_uri = "http://c.com";
+            }
+        "#,
+        )
+        .unwrap();
+        let act = &c.accounts["x"];
+        assert_eq!(act.grant_type, GrantType::DeviceCode);
+        assert_eq!(act.auth_uri(), None);
+        assert_eq!(act.device_auth_uri(), Some("http://b.com"));
+
+        match Config::from_str(
+            r#"
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                grant_type = device_code;
+                token_uri = "http://c.com";
+            }
+        "#,
+        ) {
+            Err(e) if e.contains("device_auth_uri not specified") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn issuer_metadata() {
+        let c = Config::from_str(
+            r#"
+            account "x" {
+                client_id = "a";
+                issuer = "https://b.com";
+                token_uri = "https://c.com";
+            }
+        "#,
+        )
+        .unwrap();
+        let act = &c.accounts["x"];
+        assert_eq!(act.auth_uri(), None);
+
+        let mut act2 = Account::clone(act);
+        assert!(act2
+            .set_metadata(ProviderMetadata::default())
+            .unwrap_err()
+            .contains("auth_uri neither specified nor discovered"));
+
+        let md = ProviderMetadata {
+            authorization_endpoint: Some("https://b.com/auth".to_owned()),
+            token_endpoint: Some("https://b.com/token".to_owned()),
+            ..ProviderMetadata::default()
+        };
+        let mut act2 = Account::clone(act);
+        act2.set_metadata(md.clone()).unwrap();
+        assert_eq!(act2.auth_uri(), Some("https://b.com/auth"));
+        // Explicitly specified endpoints take precedence over discovered endpoints.
+        assert_eq!(act2.token_uri(), "https://c.com");
+        assert!(!act.secure_eq(&act2));
+        assert!(act2.secure_restorable(&act2.dump()));
+
+        let mut act3 = Account::clone(act);
+        act3.set_metadata(ProviderMetadata {
+            authorization_endpoint: Some("https://d.com/auth".to_owned()),
+            ..md
+        })
+        .unwrap();
+        assert!(!act2.secure_eq(&act3));
+        assert!(!act3.secure_restorable(&act2.dump()));
+    }
+
+    #[test]
+    fn response_issuer() {
+        let c = Config::from_str(
+            r#"
+            account "x" {
+                client_id = "a";
+                issuer = "https://b.com";
+            }
+            account "y" {
+                auth_uri = "https://c.com/auth";
+                client_id = "a";
+                token_uri = "https://c.com/token";
+            }
+        "#,
+        )
+        .unwrap();
+        let md = ProviderMetadata {
+            authorization_endpoint: Some("https://b.com/auth".to_owned()),
+            token_endpoint: Some("https://b.com/token".to_owned()),
+            ..ProviderMetadata::default()
+        };
+
+        // Without an `issuer` there is nothing to check against.
+        let act = &c.accounts["y"];
+        assert!(act.check_response_issuer(None).is_ok());
+        assert!(act.check_response_issuer(Some("https://d.com")).is_ok());
+
+        // The issuer must match exactly.
+        let mut act = Account::clone(&c.accounts["x"]);
+        act.set_metadata(md.clone()).unwrap();
+        assert!(act.check_response_issuer(Some("https://b.com")).is_ok());
+        assert!(act
+            .check_response_issuer(Some("https://b.com/"))
+            .unwrap_err()
+            .contains("not 'https://b.com'"));
+        assert!(act.check_response_issuer(None).is_ok());
+
+        // If the server says it sends `iss`, its absence is an error.
+        act.set_metadata(ProviderMetadata {
+            authorization_response_iss_parameter_supported: true,
+            ..md
+        })
+        .unwrap();
+        assert!(act.check_response_issuer(Some("https://b.com")).is_ok());
+        assert!(act
+            .check_response_issuer(None)
+            .unwrap_err()
+            .contains("does not contain 'iss'"));
+    }
+
+    #[test]
+    fn secret_and_secret_cmd() {
+        match Config::from_str(
+            r#"account "x" {
+                auth_uri = "http://a.com";
+                client_id_cmd = "b";
+                client_id = "c";
+                token_uri = "http://d.com";
+            }"#,
+        ) {
+            Err(e) if e.contains("Mustn't specify both 'client_id' and 'client_id_cmd'") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        match Config::from_str(
+            r#"account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                client_secret_cmd = "d";
+                token_uri = "http://e.com";
+            }"#,
+        ) {
+            Err(e)
+                if e.contains("Mustn't specify both 'client_secret' and 'client_secret_cmd'") => {}
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn secret_cmds() {
+        let act = |fields: &str| {
+            Config::from_str(&format!(
+                r#"account "x" {{ auth_uri = "http://a.com"; client_id = "b"; token_uri = "http://c.com"; {fields} }}"#
+            ))
+            .unwrap()
+        };
+
+        // Commands aren't run when the configuration is loaded, so errors say where the command
+        // was specified.
+        let c = act(r#"client_secret_cmd = "false";"#);
+        assert!(c.accounts["x"]
+            .client_secret
+            .as_ref()
+            .unwrap()
+            .get()
+            .unwrap_err()
+            .starts_with("'client_secret_cmd' for account 'x' (line 1, column 107) failed"));
+        let c = act(r#"client_secret_cmd = "true";"#);
+        assert!(c.accounts["x"]
+            .client_secret
+            .as_ref()
+            .unwrap()
+            .get()
+            .unwrap_err()
+            .starts_with(
+                "'client_secret_cmd' for account 'x' (line 1, column 107) produced no output"
+            ));
+        let c = Config::from_str(
+            r#"
+            template "t" { auth_uri = "http://a.com"; token_uri = "http://c.com"; client_id_cmd = "true"; }
+            account "x" : "t" { }
+        "#,
+        )
+        .unwrap();
+        assert_eq!(
+            c.accounts["x"].client_id.get().unwrap_err(),
+            "'client_id_cmd' for account 'x' (inherited from template) produced no output"
+        );
+
+        // Commands' output isn't relevant to `secure_eq`, but the commands themselves are.
+        let c1 = act(r#"client_secret_cmd = "echo d";"#);
+        let c2 = act(r#"client_secret_cmd = "echo d";"#);
+        assert_eq!(
+            c1.accounts["x"]
+                .client_secret
+                .as_ref()
+                .unwrap()
+                .get()
+                .unwrap(),
+            "d"
+        );
+        assert!(c1.accounts["x"].secure_eq(&c2.accounts["x"]));
+        assert!(c2.accounts["x"].secure_restorable(&c1.accounts["x"].dump()));
+        let c3 = act(r#"client_secret_cmd = "echo e";"#);
+        assert!(!c1.accounts["x"].secure_eq(&c3.accounts["x"]));
+        assert!(!c3.accounts["x"].secure_restorable(&c1.accounts["x"].dump()));
+        let c4 = act(r#"client_secret = "d";"#);
+        assert!(!c1.accounts["x"].secure_eq(&c4.accounts["x"]));
+    }
+
+    #[test]
+    fn client_credentials_grant() {
+        let c = Config::from_str(
+            r#"
+            account "x" {
+                client_id = "a";
+                client_secret = "b";
+                grant_type = client_credentials;
+                token_uri = "http://c.com";
+            }
+        "#,
+        )
+        .unwrap();
+        let act = &c.accounts["x"];
+        assert_eq!(act.grant_type, GrantType::ClientCredentials);
+        assert_eq!(act.auth_uri(), None);
+        assert_eq!(act.device_auth_uri(), None);
+    }
+
     #[test]
     fn local_overrides() {
         // Defaults only
@@ -1262,4 +2698,377 @@ mod test {
             _ => panic!(),
         }
     }
+
+    #[test]
+    fn includes() {
+        let tmpdir = tempfile::TempDir::new().unwrap();
+        let act = |name: &str| {
+            format!(
+                r#"account "{name}" {{
+                    auth_uri = "http://a.com";
+                    client_id = "b";
+                    token_uri = "http://c.com";
+                }}"#
+            )
+        };
+        let dropin = tmpdir.path().join("accounts.d");
+        std::fs::create_dir(&dropin).unwrap();
+        std::fs::write(dropin.join("2.conf"), act("y")).unwrap();
+        std::fs::write(dropin.join("1.conf"), act("x")).unwrap();
+        std::fs::write(dropin.join(".3.conf"), "invalid").unwrap();
+        std::fs::write(dropin.join("4.conf~"), "invalid").unwrap();
+        std::fs::write(
+            tmpdir.path().join("common.conf"),
+            "refresh_retry = 7s;\ninclude_dir \"accounts.d\";",
+        )
+        .unwrap();
+        let main = tmpdir.path().join("pizauth.conf");
+        std::fs::write(&main, format!("include \"common.conf\";\n{}", act("z"))).unwrap();
+        let c = Config::from_path(&main).unwrap();
+        let mut names = c.accounts.keys().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, ["x", "y", "z"]);
+        assert_eq!(c.refresh_retry, Some(Duration::from_secs(7)));
+
+        // Errors in included files name the file in which they occur.
+        std::fs::write(dropin.join("2.conf"), "\nrefresh_retry = 1s;").unwrap();
+        match Config::from_path(&main) {
+            Err(e) if e.starts_with(&format!("{}: Line 2,", dropin.join("2.conf").display())) => {
+                assert!(e.contains("Mustn't specify 'refresh_retry' more than once"));
+            }
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        // Account names must be unique across files, though a file may redefine its own
+        // accounts.
+        std::fs::write(dropin.join("2.conf"), format!("{}\n{}", act("y"), act("y"))).unwrap();
+        assert!(Config::from_path(&main).is_ok());
+        std::fs::write(dropin.join("2.conf"), act("z")).unwrap();
+        match Config::from_path(&main) {
+            Err(e) if e.contains("Mustn't specify account 'z' more than once") => {
+                assert!(e.starts_with(&format!("{}: Line 2,", main.display())));
+                assert!(e.contains(&format!(
+                    "also specified in {}",
+                    dropin.join("2.conf").canonicalize().unwrap().display()
+                )));
+            }
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        // Include cycles are reported with the chain of files which led to them.
+        std::fs::write(dropin.join("2.conf"), "include \"../pizauth.conf\";").unwrap();
+        let chain = [
+            main.clone(),
+            tmpdir.path().join("common.conf"),
+            dropin.join("2.conf"),
+            main.clone(),
+        ]
+        .iter()
+        .map(|x| x.canonicalize().unwrap().display().to_string())
+        .collect::<Vec<_>>()
+        .join(" -> ");
+        match Config::from_path(&main) {
+            Err(e) if e.contains("includes itself") => assert!(e.ends_with(&chain), "{e}"),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        std::fs::write(dropin.join("2.conf"), "include \"missing.conf\";").unwrap();
+        match Config::from_path(&main) {
+            Err(e) if e.contains("Can't read") && e.contains("missing.conf") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn dup_accounts() {
+        // Within a file, the last definition of an account wins.
+        let c = Config::from_str(
+            r#"account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                token_uri = "http://c.com";
+            }
+            account "x" {
+                auth_uri = "http://d.com";
+                client_id = "e";
+                token_uri = "http://f.com";
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(c.accounts["x"].auth_uri(), Some("http://d.com"));
+    }
+
+    #[test]
+    fn templates() {
+        let c = Config::from_str(
+            r#"
+            template "t" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                client_secret = "c";
+                scopes = ["d", "e"];
+                token_uri = "http://f.com";
+            }
+            account "x" : "t" {
+                auth_uri_fields = { "login_hint": "g" };
+            }
+            account "y" : "t" {
+                client_id_cmd = "echo h";
+                scopes = ["i"];
+                token_uri = "http://j.com";
+            }
+        "#,
+        )
+        .unwrap();
+        let act = &c.accounts["x"];
+        assert_eq!(act.auth_uri(), Some("http://a.com"));
+        assert_eq!(act.client_id.get().unwrap(), "b");
+        assert_eq!(act.client_secret.as_ref().unwrap().get().unwrap(), "c");
+        assert_eq!(act.scopes, ["d", "e"]);
+        assert_eq!(act.token_uri(), "http://f.com");
+        assert_eq!(
+            act.auth_uri_fields,
+            [("login_hint".to_owned(), "g".to_owned())]
+        );
+        let act = &c.accounts["y"];
+        assert_eq!(act.auth_uri(), Some("http://a.com"));
+        assert_eq!(act.client_id.get().unwrap(), "h");
+        assert_eq!(act.client_secret.as_ref().unwrap().get().unwrap(), "c");
+        assert_eq!(act.scopes, ["i"]);
+        assert_eq!(act.token_uri(), "http://j.com");
+        assert!(!c.accounts.contains_key("t"));
+
+        // Templates needn't be complete, but the accounts using them must be.
+        match Config::from_str(
+            r#"
+            template "t" {
+                client_id = "a";
+            }
+            account "x" : "t" {
+                auth_uri = "http://b.com";
+            }
+        "#,
+        ) {
+            Err(e) if e.contains("token_uri not specified") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        match Config::from_str(
+            r#"
+            account "x" : "t" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                token_uri = "http://c.com";
+            }
+            template "t" { }
+        "#,
+        ) {
+            Err(e) if e.contains("Template 't' must be defined before it is used") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        match Config::from_str(
+            r#"
+            template "t" { }
+            template "t" { }
+        "#,
+        ) {
+            Err(e) if e.contains("Mustn't specify template 't' more than once") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        match Config::from_str(
+            r#"
+            template "t" {
+                client_id = "a";
+                client_id = "b";
+            }
+        "#,
+        ) {
+            Err(e) if e.contains("Mustn't specify 'client_id' more than once") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn access_rules() {
+        let c = Config::from_str(
+            r#"
+            account "x" {
+                auth_uri = "http://a.com";
+                client_id = "b";
+                token_uri = "http://c.com";
+                allow_uid = [1000, 1001];
+            }
+        "#,
+        )
+        .unwrap();
+        let act = &c.accounts["x"];
+        assert_eq!(act.allow_uid, Some(vec![1000, 1001]));
+        assert_eq!(act.allow_exe, None);
+
+        match Config::from_str(r#"account "x" { allow_uid = [4294967296]; }"#) {
+            Err(e) if e.contains("Invalid uid") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        if cfg!(target_os = "linux") {
+            let c = Config::from_str(
+                r#"
+                account "x" {
+                    auth_uri = "http://a.com";
+                    client_id = "b";
+                    token_uri = "http://c.com";
+                    allow_exe = ["/usr/bin/mbsync", "/usr/bin/msmtp"];
+                }
+            "#,
+            )
+            .unwrap();
+            assert_eq!(
+                c.accounts["x"].allow_exe,
+                Some(vec![
+                    PathBuf::from("/usr/bin/mbsync"),
+                    PathBuf::from("/usr/bin/msmtp")
+                ])
+            );
+
+            match Config::from_str(r#"account "x" { allow_exe = ["mbsync"]; }"#) {
+                Err(e) if e.contains("'allow_exe' paths must be absolute") => (),
+                Err(e) => panic!("{e:}"),
+                _ => panic!(),
+            }
+        }
+    }
+
+    #[test]
+    fn token_endpoint_auth_method() {
+        let act = |fields: &str| {
+            Config::from_str(&format!(
+                r#"account "x" {{ auth_uri = "http://a.com"; client_id = "b"; token_uri = "http://c.com"; {fields} }}"#
+            ))
+        };
+
+        let c = act("").unwrap();
+        assert_eq!(
+            c.accounts["x"].token_endpoint_auth_method,
+            TokenEndpointAuthMethod::Post
+        );
+        let c = act(r#"client_secret = "d"; token_endpoint_auth_method = basic;"#).unwrap();
+        assert_eq!(
+            c.accounts["x"].token_endpoint_auth_method,
+            TokenEndpointAuthMethod::Basic
+        );
+        let c = act(
+            r#"token_endpoint_auth_method = private_key_jwt; client_assertion_key = "/e"; client_assertion_kid = "f";"#,
+        )
+        .unwrap();
+        let a = &c.accounts["x"];
+        assert_eq!(
+            a.token_endpoint_auth_method,
+            TokenEndpointAuthMethod::PrivateKeyJwt
+        );
+        assert_eq!(a.client_assertion_key, Some(PathBuf::from("/e")));
+        assert_eq!(a.client_assertion_kid.as_deref(), Some("f"));
+
+        match act("token_endpoint_auth_method = basic;") {
+            Err(e) if e.contains("requires 'client_secret' or 'client_secret_cmd'") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+        match act("token_endpoint_auth_method = private_key_jwt;") {
+            Err(e) if e.contains("client_assertion_key not specified") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+        match act(r#"client_assertion_key = "/e";"#) {
+            Err(e) if e.contains("require 'token_endpoint_auth_method = private_key_jwt'") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+        match act(r#"token_endpoint_auth_method = private_key_jwt; client_assertion_key = "e";"#) {
+            Err(e) if e.contains("'client_assertion_key' must be an absolute path") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn dpop() {
+        let act = |fields: &str| {
+            Config::from_str(&format!(
+                r#"account "x" {{ auth_uri = "http://a.com"; client_id = "b"; token_uri = "http://c.com"; {fields} }}"#
+            ))
+            .unwrap()
+        };
+        assert!(!act("").accounts["x"].dpop);
+        assert!(!act("dpop = false;").accounts["x"].dpop);
+        assert!(act("dpop = true;").accounts["x"].dpop);
+        assert!(!act("").accounts["x"].secure_eq(&act("dpop = true;").accounts["x"]));
+    }
+
+    #[test]
+    fn par_uri() {
+        let act = |fields: &str| {
+            Config::from_str(&format!(
+                r#"account "x" {{ auth_uri = "http://a.com"; client_id = "b"; token_uri = "http://c.com"; {fields} }}"#
+            ))
+            .unwrap()
+        };
+        let with_par = act(r#"par_uri = "http://d.com";"#);
+        assert_eq!(
+            with_par.accounts["x"].par_uri.as_deref(),
+            Some("http://d.com")
+        );
+        // Pushed requests don't affect where tokens are sent, so existing tokens remain valid.
+        let without_par = act("");
+        assert!(without_par.accounts["x"].secure_eq(&with_par.accounts["x"]));
+        assert!(without_par.accounts["x"].secure_restorable(&with_par.accounts["x"].dump()));
+    }
+
+    #[test]
+    fn tls_client_cert() {
+        let act = |fields: &str| {
+            Config::from_str(&format!(
+                r#"account "x" {{ auth_uri = "http://a.com"; client_id = "b"; token_uri = "http://c.com"; {fields} }}"#
+            ))
+        };
+
+        let c = act(r#"tls_client_cert = "/d.pem"; tls_client_key = "/e.pem";"#).unwrap();
+        assert_eq!(
+            c.accounts["x"].tls_client_cert,
+            Some(PathBuf::from("/d.pem"))
+        );
+        assert_eq!(
+            c.accounts["x"].tls_client_key,
+            Some(PathBuf::from("/e.pem"))
+        );
+
+        match act(r#"tls_client_cert = "/d.pem";"#) {
+            Err(e) if e.contains("must be specified together") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+        match act(r#"tls_client_cert = "d.pem"; tls_client_key = "/e.pem";"#) {
+            Err(e) if e.contains("'tls_client_cert' must be an absolute path") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+
+        let c = act(r#"tls_ca_cert = "/f.pem";"#).unwrap();
+        assert_eq!(c.accounts["x"].tls_ca_cert, Some(PathBuf::from("/f.pem")));
+        match act(r#"tls_ca_cert = "f.pem";"#) {
+            Err(e) if e.contains("'tls_ca_cert' must be an absolute path") => (),
+            Err(e) => panic!("{e:}"),
+            _ => panic!(),
+        }
+    }
 }
@@ -11,7 +11,9 @@ TopLevels -> Result<Vec<TopLevel>, ()>:
   ;
 
 TopLevel -> Result<TopLevel, ()>:
-    "ACCOUNT" "STRING" "{" AccountFields "}" { Ok(TopLevel::Account($span, map_err($2)?, $4?)) }
+    "ACCOUNT" "STRING" "{" AccountFields "}" { Ok(TopLevel::Account($span, map_err($2)?, None, $4?)) }
+  | "ACCOUNT" "STRING" ":" "STRING" "{" AccountFields "}" { Ok(TopLevel::Account($span, map_err($2)?, Some(map_err($4)?), $6?)) }
+  | "AUDIT_LOG" "=" "STRING" ";" { Ok(TopLevel::AuditLog(map_err($3)?)) }
   | "AUTH_ERROR_CMD" "=" "STRING" ";" { Ok(TopLevel::AuthErrorCmd($span)) }
   | "AUTH_NOTIFY_CMD" "=" "STRING" ";" { Ok(TopLevel::AuthNotifyCmd(map_err($3)?)) }
   | "AUTH_NOTIFY_INTERVAL" "=" "TIME" ";" { Ok(TopLevel::AuthNotifyInterval(map_err($3)?)) }
@@ -20,11 +22,17 @@ TopLevel -> Result<TopLevel, ()>:
   | "HTTP_LISTEN" "=" "STRING" ";" { Ok(TopLevel::HttpListen(map_err($3)?)) }
   | "HTTPS_LISTEN" "=" "NONE" ";" { Ok(TopLevel::HttpsListenNone(map_err($3)?)) }
   | "HTTPS_LISTEN" "=" "STRING" ";" { Ok(TopLevel::HttpsListen(map_err($3)?)) }
+  | "INCLUDE" "STRING" ";" { Ok(TopLevel::Include(map_err($2)?)) }
+  | "INCLUDE_DIR" "STRING" ";" { Ok(TopLevel::IncludeDir(map_err($2)?)) }
+  | "KERNEL_KEYRING" "=" KernelKeyring ";" { Ok(TopLevel::KernelKeyring($3?)) }
   | "TRANSIENT_ERROR_IF_CMD" "=" "STRING" ";" { Ok(TopLevel::TransientErrorIfCmd(map_err($3)?)) }
   | "REFRESH_AT_LEAST" "=" "TIME" ";" { Ok(TopLevel::RefreshAtLeast(map_err($3)?)) }
   | "REFRESH_BEFORE_EXPIRY" "=" "TIME" ";" { Ok(TopLevel::RefreshBeforeExpiry(map_err($3)?)) }
   | "REFRESH_RETRY" "=" "TIME" ";" { Ok(TopLevel::RefreshRetry(map_err($3)?)) }
   | "STARTUP_CMD" "=" "STRING" ";" { Ok(TopLevel::StartupCmd(map_err($3)?)) }
+  | "STATE_FILE" "=" "STRING" ";" { Ok(TopLevel::StateFile(map_err($3)?)) }
+  | "STATE_STORAGE" "=" StateStorage ";" { Ok(TopLevel::StateStorage($3?)) }
+  | "TEMPLATE" "STRING" "{" AccountFields "}" { Ok(TopLevel::Template(map_err($2)?, $4?)) }
   | "TOKEN_EVENT_CMD" "=" "STRING" ";" { Ok(TopLevel::TokenEventCmd(map_err($3)?)) }
   ;
 
@@ -34,17 +42,63 @@ AccountFields -> Result<Vec<AccountField>, ()>:
   ;
 
 AccountField -> Result<AccountField, ()>:
-    "AUTH_URI" "=" "STRING" ";" { Ok(AccountField::AuthUri(map_err($3)?)) }
+    "ALLOW_EXE" "=" "[" Strings "]" ";" { Ok(AccountField::AllowExe($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "ALLOW_UID" "=" "[" Ints "]" ";" { Ok(AccountField::AllowUid($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "AUTH_URI" "=" "STRING" ";" { Ok(AccountField::AuthUri(map_err($3)?)) }
   | "AUTH_URI_FIELDS" "=" "{" AuthUriFields "}" ";" { Ok(AccountField::AuthUriFields($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "CLIENT_ASSERTION_KEY" "=" "STRING" ";" { Ok(AccountField::ClientAssertionKey(map_err($3)?)) }
+  | "CLIENT_ASSERTION_KID" "=" "STRING" ";" { Ok(AccountField::ClientAssertionKid(map_err($3)?)) }
   | "CLIENT_ID" "=" "STRING" ";" { Ok(AccountField::ClientId(map_err($3)?)) }
+  | "CLIENT_ID_CMD" "=" "STRING" ";" { Ok(AccountField::ClientIdCmd(map_err($3)?)) }
   | "CLIENT_SECRET" "=" "STRING" ";" { Ok(AccountField::ClientSecret(map_err($3)?)) }
+  | "CLIENT_SECRET_CMD" "=" "STRING" ";" { Ok(AccountField::ClientSecretCmd(map_err($3)?)) }
+  | "DEVICE_AUTH_URI" "=" "STRING" ";" { Ok(AccountField::DeviceAuthUri(map_err($3)?)) }
+  | "DPOP" "=" Bool ";" { Ok(AccountField::Dpop($3?)) }
+  | "GRANT_TYPE" "=" GrantType ";" { Ok(AccountField::GrantType($3?)) }
+  | "ISSUER" "=" "STRING" ";" { Ok(AccountField::Issuer(map_err($3)?)) }
   | "LOGIN_HINT" "=" "STRING" ";" { Ok(AccountField::LoginHint(map_err($3)?)) }
+  | "PAR_URI" "=" "STRING" ";" { Ok(AccountField::ParUri(map_err($3)?)) }
   | "REDIRECT_URI" "=" "STRING" ";" { Ok(AccountField::RedirectUri(map_err($3)?)) }
   | "REFRESH_AT_LEAST" "=" "TIME" ";" { Ok(AccountField::RefreshAtLeast(map_err($3)?)) }
   | "REFRESH_BEFORE_EXPIRY" "=" "TIME" ";" { Ok(AccountField::RefreshBeforeExpiry(map_err($3)?)) }
   | "REFRESH_RETRY" "=" "TIME" ";" { Ok(AccountField::RefreshRetry(map_err($3)?)) }
-  | "SCOPES" "=" "[" Scopes "]" ";" { Ok(AccountField::Scopes($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "REVOCATION_URI" "=" "STRING" ";" { Ok(AccountField::RevocationUri(map_err($3)?)) }
+  | "SCOPES" "=" "[" Strings "]" ";" { Ok(AccountField::Scopes($1.unwrap_or_else(|x| x).span(), $4?)) }
+  | "TLS_CA_CERT" "=" "STRING" ";" { Ok(AccountField::TlsCaCert(map_err($3)?)) }
+  | "TLS_CLIENT_CERT" "=" "STRING" ";" { Ok(AccountField::TlsClientCert(map_err($3)?)) }
+  | "TLS_CLIENT_KEY" "=" "STRING" ";" { Ok(AccountField::TlsClientKey(map_err($3)?)) }
+  | "TOKEN_ENDPOINT_AUTH_METHOD" "=" TokenEndpointAuthMethod ";" { Ok(AccountField::TokenEndpointAuthMethod($3?)) }
   | "TOKEN_URI" "=" "STRING" ";" { Ok(AccountField::TokenUri(map_err($3)?)) }
+  | "USERNAME" "=" "STRING" ";" { Ok(AccountField::Username(map_err($3)?)) }
+  ;
+
+StateStorage -> Result<Span, ()>:
+    "FILE" { map_err($1) }
+  | "KERNEL_KEYRING" { map_err($1) }
+  | "NONE" { map_err($1) }
+  | "SECRET_SERVICE" { map_err($1) }
+  ;
+
+KernelKeyring -> Result<Span, ()>:
+    "SESSION" { map_err($1) }
+  | "USER" { map_err($1) }
+  ;
+
+GrantType -> Result<Span, ()>:
+    "AUTHORIZATION_CODE" { map_err($1) }
+  | "CLIENT_CREDENTIALS" { map_err($1) }
+  | "DEVICE_CODE" { map_err($1) }
+  ;
+
+TokenEndpointAuthMethod -> Result<Span, ()>:
+    "BASIC" { map_err($1) }
+  | "POST" { map_err($1) }
+  | "PRIVATE_KEY_JWT" { map_err($1) }
+  ;
+
+Bool -> Result<Span, ()>:
+    "FALSE" { map_err($1) }
+  | "TRUE" { map_err($1) }
   ;
 
 AuthUriFields -> Result<Vec<(Span, Span)>, ()>:
@@ -57,8 +111,8 @@ AuthUriFields -> Result<Vec<(Span, Span)>, ()>:
   | { Ok(vec![]) }
   ;
 
-Scopes -> Result<Vec<Span>, ()>:
-    Scopes "," "STRING" {
+Strings -> Result<Vec<Span>, ()>:
+    Strings "," "STRING" {
       let mut spans = $1?;
       spans.push(map_err($3)?);
       Ok(spans)
@@ -67,6 +121,16 @@ Scopes -> Result<Vec<Span>, ()>:
   | { Ok(vec![]) }
   ;
 
+Ints -> Result<Vec<Span>, ()>:
+    Ints "," "INT" {
+      let mut spans = $1?;
+      spans.push(map_err($3)?);
+      Ok(spans)
+    }
+  | "INT" { Ok(vec![map_err($1)?]) }
+  | { Ok(vec![]) }
+  ;
+
 // This rule helps turn lexing errors into parsing errors.
 Unmatched -> ():
     "UNMATCHED" { }
@@ -1,7 +1,8 @@
 use lrpar::Span;
 
 pub enum TopLevel {
-    Account(Span, Span, Vec<AccountField>),
+    Account(Span, Span, Option<Span>, Vec<AccountField>),
+    AuditLog(Span),
     AuthErrorCmd(Span),
     AuthNotifyCmd(Span),
     AuthNotifyInterval(Span),
@@ -10,24 +11,47 @@ pub enum TopLevel {
     HttpListenNone(Span),
     HttpsListen(Span),
     HttpsListenNone(Span),
+    Include(Span),
+    IncludeDir(Span),
+    KernelKeyring(Span),
     TransientErrorIfCmd(Span),
     RefreshAtLeast(Span),
     RefreshBeforeExpiry(Span),
     RefreshRetry(Span),
     StartupCmd(Span),
+    StateFile(Span),
+    StateStorage(Span),
+    Template(Span, Vec<AccountField>),
     TokenEventCmd(Span),
 }
 
 pub enum AccountField {
+    AllowExe(Span, Vec<Span>),
+    AllowUid(Span, Vec<Span>),
     AuthUri(Span),
     AuthUriFields(Span, Vec<(Span, Span)>),
+    ClientAssertionKey(Span),
+    ClientAssertionKid(Span),
     ClientId(Span),
+    ClientIdCmd(Span),
     ClientSecret(Span),
+    ClientSecretCmd(Span),
+    DeviceAuthUri(Span),
+    Dpop(Span),
+    GrantType(Span),
+    Issuer(Span),
     LoginHint(Span),
+    ParUri(Span),
     RedirectUri(Span),
     RefreshAtLeast(Span),
     RefreshBeforeExpiry(Span),
     RefreshRetry(Span),
+    RevocationUri(Span),
     Scopes(Span, Vec<Span>),
+    TlsCaCert(Span),
+    TlsClientCert(Span),
+    TlsClientKey(Span),
+    TokenEndpointAuthMethod(Span),
     TokenUri(Span),
+    Username(Span),
 }
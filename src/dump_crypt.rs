@@ -0,0 +1,205 @@
+//! Encrypting `pizauth dump` output with a user supplied key.
+//!
+//! The server's dump output is only obfuscated (see [`crate::server::AuthenticatorState::dump`]).
+//! When the user supplies a key file or a passphrase command, the client wraps that output in a
+//! second layer of encryption whose key is derived with Argon2id. Such dumps start with a header
+//! recording the scheme and the KDF parameters; dumps without a header are passed through to the
+//! server unchanged, so dumps from older versions of pizauth still restore.
+//!
+//! The header format is:
+//!
+//! ```text
+//! MAGIC | scheme: u8 | m_cost: u32 | t_cost: u32 | p_cost: u32 | salt | nonce
+//! ```
+//!
+//! with integers in little endian format, followed by the `ChaCha20Poly1305` ciphertext. The whole
+//! header is authenticated as associated data.
+
+use std::{error::Error, fs, path::PathBuf, time::Duration};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use rand::{rng, RngExt};
+
+use crate::shell_cmd::shell_cmd_stdout;
+
+/// The bytes that start an encrypted dump. Unencrypted dumps start with a random nonce, so the
+/// chances of one accidentally starting with these bytes are negligible.
+const MAGIC: &[u8] = b"PIZAUTH\x00";
+/// The scheme byte for dumps whose key is derived from the contents of a key file.
+const SCHEME_KEY_FILE: u8 = 1;
+/// The scheme byte for dumps whose key is derived from the output of a passphrase command.
+const SCHEME_PASSPHRASE: u8 = 2;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const HEADER_LEN: usize = MAGIC.len() + 1 + 3 * 4 + SALT_LEN + NONCE_LEN;
+/// Argon2id memory cost (in KiB) for new dumps.
+const M_COST: u32 = 64 * 1024;
+/// Argon2id iterations for new dumps.
+const T_COST: u32 = 3;
+/// Argon2id parallelism for new dumps.
+const P_COST: u32 = 1;
+/// The largest Argon2id memory cost (in KiB) we are prepared to use when restoring a dump: this
+/// stops a corrupt header from making us allocate absurd amounts of memory.
+const MAX_M_COST: u32 = 1024 * 1024;
+/// How long to run a `--passphrase-cmd` before killing it? This is generous, as the command may
+/// be prompting the user.
+const PASSPHRASE_CMD_TIMEOUT: Duration = Duration::from_mins(2);
+
+/// Where the secret from which a dump's key is derived comes from.
+pub enum DumpKey {
+    /// The contents of a file.
+    KeyFile(PathBuf),
+    /// The stdout of a shell command, minus a single trailing newline (if present).
+    PassphraseCmd(String),
+}
+
+impl DumpKey {
+    fn scheme(&self) -> u8 {
+        match self {
+            Self::KeyFile(_) => SCHEME_KEY_FILE,
+            Self::PassphraseCmd(_) => SCHEME_PASSPHRASE,
+        }
+    }
+
+    fn secret(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let secret = match self {
+            Self::KeyFile(p) => {
+                fs::read(p).map_err(|e| format!("Can't read key file {p:?}: {e}"))?
+            }
+            Self::PassphraseCmd(cmd) => {
+                let mut s = shell_cmd_stdout(cmd, [], PASSPHRASE_CMD_TIMEOUT)?;
+                if s.ends_with('\n') {
+                    s.pop();
+                }
+                s.into_bytes()
+            }
+        };
+        if secret.is_empty() {
+            return Err("Key file or passphrase is empty")?;
+        }
+        Ok(secret)
+    }
+}
+
+fn derive_key(
+    secret: &[u8],
+    salt: &[u8],
+    m_cost: u32,
+    t_cost: u32,
+    p_cost: u32,
+) -> Result<[u8; 32], Box<dyn Error>> {
+    if m_cost > MAX_M_COST {
+        return Err("Dump's key derivation parameters are too large".into());
+    }
+    let params = Params::new(m_cost, t_cost, p_cost, Some(32))
+        .map_err(|e| format!("Invalid key derivation parameters: {e}"))?;
+    let mut key = [0u8; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(secret, salt, &mut key)
+        .map_err(|e| format!("Key derivation failed: {e}"))?;
+    Ok(key)
+}
+
+/// Encrypt the dump `d` with a key derived from `key`.
+pub fn encrypt(d: &[u8], key: &DumpKey) -> Result<Vec<u8>, Box<dyn Error>> {
+    let secret = key.secret()?;
+    let mut salt = [0u8; SALT_LEN];
+    rng().fill(&mut salt[..]);
+    let mut nonce = [0u8; NONCE_LEN];
+    rng().fill(&mut nonce[..]);
+
+    let mut buf = Vec::with_capacity(HEADER_LEN + d.len());
+    buf.extend(MAGIC);
+    buf.push(key.scheme());
+    for x in [M_COST, T_COST, P_COST] {
+        buf.extend(x.to_le_bytes());
+    }
+    buf.extend(salt);
+    buf.extend(nonce);
+
+    let dkey = derive_key(&secret, &salt, M_COST, T_COST, P_COST)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&dkey));
+    let bytes = cipher
+        .encrypt(Nonce::from_slice(&nonce), Payload { msg: d, aad: &buf })
+        .map_err(|_| "Encrypting dump failed")?;
+    buf.extend(bytes);
+    Ok(buf)
+}
+
+/// If `d` was created by [encrypt], decrypt it with a key derived from `key`; otherwise return
+/// `d` unchanged.
+pub fn decrypt(d: Vec<u8>, key: Option<&DumpKey>) -> Result<Vec<u8>, Box<dyn Error>> {
+    if !d.starts_with(MAGIC) {
+        return Ok(d);
+    }
+    if d.len() < HEADER_LEN {
+        return Err("Dump too short".into());
+    }
+    let (hdr, encrypted) = d.split_at(HEADER_LEN);
+    let scheme = hdr[MAGIC.len()];
+    let key = match (scheme, key) {
+        (SCHEME_KEY_FILE | SCHEME_PASSPHRASE, None) => {
+            return Err("Dump is encrypted: use --key-file or --passphrase-cmd")?;
+        }
+        (SCHEME_KEY_FILE, Some(key @ DumpKey::KeyFile(_)))
+        | (SCHEME_PASSPHRASE, Some(key @ DumpKey::PassphraseCmd(_))) => key,
+        (SCHEME_KEY_FILE, Some(_)) => return Err("Dump was encrypted with --key-file")?,
+        (SCHEME_PASSPHRASE, Some(_)) => return Err("Dump was encrypted with --passphrase-cmd")?,
+        _ => return Err(format!("Unknown dump encryption scheme {scheme}"))?,
+    };
+
+    let u32_at = |i: usize| {
+        let off = MAGIC.len() + 1 + i * 4;
+        u32::from_le_bytes(hdr[off..off + 4].try_into().unwrap())
+    };
+    let salt_off = MAGIC.len() + 1 + 3 * 4;
+    let salt = &hdr[salt_off..salt_off + SALT_LEN];
+    let nonce = &hdr[salt_off + SALT_LEN..];
+
+    let dkey = derive_key(&key.secret()?, salt, u32_at(0), u32_at(1), u32_at(2))?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(&dkey));
+    Ok(cipher
+        .decrypt(
+            Nonce::from_slice(nonce),
+            Payload {
+                msg: encrypted,
+                aad: hdr,
+            },
+        )
+        .map_err(|_| "Decrypting dump failed: wrong key or passphrase?")?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let keyp = dir.path().join("key");
+        fs::write(&keyp, "secret").unwrap();
+        let key = DumpKey::KeyFile(keyp.clone());
+
+        let d = encrypt(b"dump", &key).unwrap();
+        assert!(d.starts_with(MAGIC));
+        assert_eq!(decrypt(d.clone(), Some(&key)).unwrap(), b"dump");
+        assert!(decrypt(d.clone(), None).is_err());
+        assert!(decrypt(d.clone(), Some(&DumpKey::PassphraseCmd("true".into()))).is_err());
+
+        // Tampering with the header must be detected.
+        let mut t = d.clone();
+        t[MAGIC.len() + 1 + 3 * 4] ^= 1;
+        assert!(decrypt(t, Some(&key)).is_err());
+
+        fs::write(&keyp, "other").unwrap();
+        assert!(decrypt(d, Some(&key)).is_err());
+
+        // Dumps without a header are passed through unchanged.
+        assert_eq!(decrypt(b"old".to_vec(), None).unwrap(), b"old");
+        assert_eq!(decrypt(b"old".to_vec(), Some(&key)).unwrap(), b"old");
+    }
+}
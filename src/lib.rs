@@ -0,0 +1,79 @@
+//! A library for talking to a running pizauth server.
+//!
+//! Programs which need access tokens can use a [Client] rather than running `pizauth show`:
+//!
+//! ```no_run
+//! let mut client = pizauth::Client::connect().unwrap();
+//! let token = client.show_token("officesmtp", false, None, None).unwrap();
+//! println!("{}", token.access_token);
+//! ```
+
+#![allow(clippy::derive_partial_eq_without_eq)]
+// Every fallible `Client` method can fail for the same reasons, which are described by
+// `ClientError`.
+#![allow(clippy::missing_errors_doc)]
+
+mod client;
+pub mod protocol;
+
+use std::{
+    env,
+    ffi::OsString,
+    fs::DirBuilder,
+    io,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+};
+
+use whoami::username;
+
+pub use client::{AccessToken, Client, ClientError, Subscription};
+
+/// Name of cache directory within `$XDG_RUNTIME_DIR`.
+const PIZAUTH_CACHE_LEAF: &str = "pizauth";
+/// Name of socket file within `$XDG_RUNTIME_DIR/PIZAUTH_CACHE_LEAF`.
+const PIZAUTH_CACHE_SOCK_LEAF: &str = "pizauth.sock";
+
+/// Return the directory in which the pizauth server places its socket.
+///
+/// This is `$XDG_RUNTIME_DIR/pizauth` or, if `$XDG_RUNTIME_DIR` is not set,
+/// `$TMPDIR/runtime-<user>/pizauth`. The directory is created, with permissions restricting it
+/// to the current user, if necessary. The permissions of an existing directory are left alone, so
+/// that users can give other users (see `allow_uid`) access to it.
+pub fn cache_path() -> io::Result<PathBuf> {
+    let mut p = PathBuf::new();
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(s) => p.push(s),
+        None => {
+            p.push(env::var_os("TMPDIR").unwrap_or_else(|| OsString::from("/tmp")));
+            p.push(format!(
+                "runtime-{}",
+                username().unwrap_or_else(|_| "unknown-user".to_owned())
+            ));
+        }
+    }
+
+    let md = |p: &PathBuf| {
+        if p.exists() {
+            return Ok(());
+        }
+        DirBuilder::new()
+            .mode(0o700)
+            .create(p)
+            .map_err(|e| io::Error::new(e.kind(), format!("Can't create cache dir: {e}")))
+    };
+
+    md(&p)?;
+    p.push(PIZAUTH_CACHE_LEAF);
+    md(&p)?;
+
+    Ok(p)
+}
+
+/// Return the path of the server's socket within `cache_path`.
+#[must_use]
+pub fn sock_path(cache_path: &Path) -> PathBuf {
+    let mut p = cache_path.to_owned();
+    p.push(PIZAUTH_CACHE_SOCK_LEAF);
+    p
+}
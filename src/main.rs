@@ -5,16 +5,17 @@
 mod compat;
 mod config;
 mod config_ast;
+mod dump_crypt;
 mod server;
 mod shell_cmd;
 mod user_sender;
 
 use std::{
+    collections::HashMap,
     env::{self, current_exe},
-    ffi::OsString,
     fs,
     io::{stdout, Write},
-    os::unix::{fs::PermissionsExt, net::UnixStream},
+    os::unix::{fs::MetadataExt, net::UnixStream},
     path::PathBuf,
     process, thread,
     time::Duration,
@@ -28,21 +29,18 @@ use nix::{
         stat::{utimensat, UtimensatFlags},
         time::TimeSpec,
     },
+    unistd::geteuid,
 };
+use pizauth::{cache_path, sock_path};
 #[cfg(target_os = "openbsd")]
 use pledge::pledge;
 use serde_json::json;
-use server::sock_path;
-use whoami::username;
 
 use compat::daemon;
-use config::Config;
-use user_sender::show_token;
+use config::{parse_time, Config};
+use dump_crypt::DumpKey;
+use user_sender::{show_token, TokenFormat};
 
-/// Name of cache directory within `$XDG_DATA_HOME`.
-const PIZAUTH_CACHE_LEAF: &str = "pizauth";
-/// Name of socket file within `$XDG_DATA_HOME/PIZAUTH_CACHE_LEAF`.
-const PIZAUTH_CACHE_SOCK_LEAF: &str = "pizauth.sock";
 /// Name of `pizauth.conf` file relative to `$XDG_CONFIG_HOME`.
 const PIZAUTH_CONF_LEAF: &str = "pizauth.conf";
 
@@ -66,44 +64,11 @@ fn fatal(msg: &str) -> ! {
 fn usage() -> ! {
     let pn = progname();
     eprintln!(
-        "Usage:\n  {pn:} dump\n  {pn:} info [-j]\n  {pn:} refresh [-u] <account>\n  {pn:} restore\n  {pn:} reload\n  {pn:} revoke <account>\n  {pn:} server [-c <config-path>] [-dv]\n  {pn:} show [-u] <account>\n  {pn:} shutdown\n  {pn:} status"
+        "Usage:\n  {pn:} dump [--key-file <path> | --passphrase-cmd <cmd>]\n  {pn:} info [-j]\n  {pn:} refresh [-u] <account>\n  {pn:} restore [--key-file <path> | --passphrase-cmd <cmd>]\n  {pn:} reload\n  {pn:} revoke <account>\n  {pn:} server [-c <config-path>] [-dv]\n  {pn:} show [-u] [--format <format>] [--user <user>] [--wait[=<timeout>]] [--min-valid <time>] [--dpop-proof <method> <url>] <account>\n  {pn:} shutdown\n  {pn:} status [-j]\n  {pn:} watch [-j]"
     );
     process::exit(1)
 }
 
-fn cache_path() -> PathBuf {
-    let mut p = PathBuf::new();
-    match env::var_os("XDG_RUNTIME_DIR") {
-        Some(s) => p.push(s),
-        None => {
-            p.push(env::var_os("TMPDIR").unwrap_or_else(|| OsString::from("/tmp")));
-            p.push(format!(
-                "runtime-{}",
-                username().unwrap_or_else(|_| "unknown-user".to_owned())
-            ));
-        }
-    }
-
-    let md = |p: &PathBuf| {
-        if !p.exists() {
-            fs::create_dir(p).unwrap_or_else(|e| fatal(&format!("Can't create cache dir: {e}")));
-        }
-        fs::set_permissions(p, PermissionsExt::from_mode(0o700)).unwrap_or_else(|_| {
-            fatal(&format!(
-                "Can't set permissions for {} to 0700 (octal)",
-                p.to_str()
-                    .unwrap_or("<path cannot be represented as UTF-8>")
-            ))
-        });
-    };
-
-    md(&p);
-    p.push(PIZAUTH_CACHE_LEAF);
-    md(&p);
-
-    p
-}
-
 fn conf_path(matches: &getopts::Matches) -> PathBuf {
     match matches.opt_str("c") {
         Some(p) => PathBuf::from(&p),
@@ -131,6 +96,19 @@ fn conf_path(matches: &getopts::Matches) -> PathBuf {
     }
 }
 
+/// Return the key specified by `--key-file` or `--passphrase-cmd`, if either was specified.
+fn dump_key(matches: &getopts::Matches) -> Option<DumpKey> {
+    match (
+        matches.opt_str("key-file"),
+        matches.opt_str("passphrase-cmd"),
+    ) {
+        (Some(p), None) => Some(DumpKey::KeyFile(PathBuf::from(p))),
+        (None, Some(cmd)) => Some(DumpKey::PassphraseCmd(cmd)),
+        (None, None) => None,
+        (Some(_), Some(_)) => usage(),
+    }
+}
+
 fn main() {
     // Generic pledge support for all pizauth's commands. Note that the server later restricts
     // these further.
@@ -149,10 +127,14 @@ fn main() {
     opts.optflag("h", "help", "")
         .optflagmulti("v", "verbose", "");
 
-    let cache_path = cache_path();
+    let cache_path = cache_path().unwrap_or_else(|e| fatal(&e.to_string()));
     match args[1].as_str() {
         "dump" => {
-            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            let matches = opts
+                .optopt("", "key-file", "", "")
+                .optopt("", "passphrase-cmd", "", "")
+                .parse(&args[2..])
+                .unwrap_or_else(|_| usage());
             if matches.opt_present("h") || !matches.free.is_empty() {
                 usage();
             }
@@ -161,7 +143,7 @@ fn main() {
                 .verbosity(matches.opt_count("v"))
                 .init()
                 .unwrap();
-            match user_sender::dump(&cache_path) {
+            match user_sender::dump(&cache_path, dump_key(&matches).as_ref()) {
                 Ok(d) => {
                     stdout().write_all(&d).ok();
                 }
@@ -257,7 +239,11 @@ fn main() {
             }
         }
         "restore" => {
-            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            let matches = opts
+                .optopt("", "key-file", "", "")
+                .optopt("", "passphrase-cmd", "", "")
+                .parse(&args[2..])
+                .unwrap_or_else(|_| usage());
             if matches.opt_present("h") || !matches.free.is_empty() {
                 usage();
             }
@@ -266,7 +252,7 @@ fn main() {
                 .verbosity(matches.opt_count("v"))
                 .init()
                 .unwrap();
-            if let Err(e) = user_sender::restore(&cache_path) {
+            if let Err(e) = user_sender::restore(&cache_path, dump_key(&matches).as_ref()) {
                 error!("{e:}");
                 process::exit(1);
             }
@@ -296,6 +282,16 @@ fn main() {
                 usage();
             }
 
+            // `cache_path` doesn't alter the permissions of an existing directory, so we make sure
+            // that no other user can replace our socket.
+            match fs::metadata(&cache_path) {
+                Ok(md) if md.uid() == geteuid().as_raw() => (),
+                Ok(_) => fatal(&format!(
+                    "{} is not owned by the current user",
+                    cache_path.display()
+                )),
+                Err(e) => fatal(&e.to_string()),
+            }
             let sock_path = sock_path(&cache_path);
             if sock_path.exists() {
                 // Is an existing authenticator running?
@@ -323,7 +319,10 @@ fn main() {
             });
 
             let conf_path = conf_path(&matches);
-            let conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(&m));
+            let mut conf = Config::from_path(&conf_path).unwrap_or_else(|m| fatal(&m));
+            // Discovery involves network requests which may fail: do it before daemonising so that
+            // the user sees any errors.
+            server::discover(&mut conf, &HashMap::new()).unwrap_or_else(|e| fatal(&e.to_string()));
 
             let daemonise = !matches.opt_present("d");
             if daemonise {
@@ -360,12 +359,45 @@ fn main() {
         "show" => {
             let matches = opts
                 .optflag("u", "", "Don't display authorisation URLs.")
+                .optopt(
+                    "",
+                    "format",
+                    "Output format: xoauth2, oauthbearer, header, or raw.",
+                    "<format>",
+                )
+                .optopt(
+                    "",
+                    "user",
+                    "User name for the xoauth2 and oauthbearer formats.",
+                    "<user>",
+                )
+                .optflagopt(
+                    "",
+                    "wait",
+                    "Wait until an access token is available.",
+                    "<timeout>",
+                )
+                .optopt(
+                    "",
+                    "min-valid",
+                    "Refresh the access token if it expires sooner than this.",
+                    "<time>",
+                )
+                .optopt(
+                    "",
+                    "dpop-proof",
+                    "Also output a DPoP proof for a <method> request to the URL which follows.",
+                    "<method>",
+                )
                 .parse(&args[2..])
                 .unwrap_or_else(|_| usage());
             if matches.opt_present("h") {
                 usage();
             }
-            if matches.free.len() != 1 {
+            // `--dpop-proof` takes two arguments: getopts gives us the method, and the URL is
+            // the first free argument.
+            let dpop_method = matches.opt_str("dpop-proof");
+            if matches.free.len() != 1 + usize::from(dpop_method.is_some()) {
                 usage();
             }
             stderrlog::new()
@@ -373,8 +405,34 @@ fn main() {
                 .verbosity(matches.opt_count("v"))
                 .init()
                 .unwrap();
-            let account = matches.free[0].as_str();
-            if let Err(e) = show_token(cache_path.as_path(), account, !matches.opt_present("u")) {
+            let account = matches.free.last().unwrap().as_str();
+            let dpop_proof = dpop_method
+                .as_deref()
+                .map(|x| (x, matches.free[0].as_str()));
+            let format = match matches.opt_str("format").map(|x| x.parse::<TokenFormat>()) {
+                Some(Ok(x)) => x,
+                Some(Err(e)) => fatal(&e),
+                None => TokenFormat::Raw,
+            };
+            // `--wait` without a timeout waits indefinitely.
+            let wait = match (matches.opt_present("wait"), matches.opt_str("wait")) {
+                (false, _) => None,
+                (true, None) => Some(Duration::MAX),
+                (true, Some(x)) => Some(parse_time(&x).unwrap_or_else(|e| fatal(&e))),
+            };
+            let min_valid = matches
+                .opt_str("min-valid")
+                .map(|x| parse_time(&x).unwrap_or_else(|e| fatal(&e)));
+            if let Err(e) = show_token(
+                cache_path.as_path(),
+                account,
+                !matches.opt_present("u"),
+                format,
+                matches.opt_str("user").as_deref(),
+                wait,
+                min_valid,
+                dpop_proof,
+            ) {
                 error!("{e:}");
                 process::exit(1);
             }
@@ -395,7 +453,10 @@ fn main() {
             }
         }
         "status" => {
-            let matches = opts.parse(&args[2..]).unwrap_or_else(|_| usage());
+            let matches = opts
+                .optflag("j", "", "JSON output.")
+                .parse(&args[2..])
+                .unwrap_or_else(|_| usage());
             if matches.opt_present("h") {
                 usage();
             }
@@ -407,7 +468,25 @@ fn main() {
                 .verbosity(matches.opt_count("v"))
                 .init()
                 .unwrap();
-            if let Err(e) = user_sender::status(cache_path.as_path()) {
+            if let Err(e) = user_sender::status(cache_path.as_path(), matches.opt_present("j")) {
+                error!("{e:}");
+                process::exit(1);
+            }
+        }
+        "watch" => {
+            let matches = opts
+                .optflag("j", "", "JSON output.")
+                .parse(&args[2..])
+                .unwrap_or_else(|_| usage());
+            if matches.opt_present("h") || !matches.free.is_empty() {
+                usage();
+            }
+            stderrlog::new()
+                .module(module_path!())
+                .verbosity(matches.opt_count("v"))
+                .init()
+                .unwrap();
+            if let Err(e) = user_sender::watch(&cache_path, matches.opt_present("j")) {
                 error!("{e:}");
                 process::exit(1);
             }
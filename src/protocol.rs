@@ -0,0 +1,466 @@
+//! The protocol spoken over pizauth's control socket.
+//!
+//! Each message is a JSON object preceded by its length in bytes as a big-endian `u32`. A
+//! connection starts with the client sending a [`ClientMsg::Hello`] listing the protocol versions
+//! it understands, to which the server replies with a [`ServerMsg::Hello`] naming the version
+//! that will be used, or a [`ServerMsg::Error`] (after which the server closes the connection) if
+//! there is no version in common. The client can then send any number of [`ClientMsg::Request`]s,
+//! each of which receives a [`ServerMsg::Response`] with the same `id`.
+//!
+//! The server also accepts the older text protocol (`cmd:rest`, terminated by the client shutting
+//! down its side of the socket), which will be removed in the next release. Messages are never
+//! longer than [`MAX_MSG_LEN`] bytes, so the first byte sent on a connection using this protocol
+//! is always 0, which can never start a text command.
+
+use std::{
+    error::Error,
+    fmt::{self, Display, Formatter},
+    io::{self, Read, Write},
+};
+
+use chrono::DateTime;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+/// The protocol version spoken by this version of pizauth. Increment this if the semantics of the
+/// protocol change in an incompatible manner.
+pub const PROTOCOL_VERSION: u32 = 1;
+/// The maximum length of a message in bytes.
+pub const MAX_MSG_LEN: u32 = 16 * 1024 * 1024;
+
+/// A message sent by a client to the server.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientMsg {
+    /// The first message on a connection: the protocol versions the client understands.
+    Hello { versions: Vec<u32> },
+    /// A request to execute `command`. The response will have the same `id`.
+    Request { id: u64, command: Command },
+}
+
+/// A command that a client can ask the server to execute.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "cmd", rename_all = "snake_case")]
+pub enum Command {
+    /// Dump the server's token state: replies with [`Reply::Dump`].
+    Dump,
+    /// Describe the server: replies with [`Reply::Info`].
+    Info,
+    /// Refresh `account`'s access token, or request a new one if there is no access token:
+    /// replies with [`Reply::Scheduled`] or [`Reply::Pending`].
+    Refresh { account: String, with_url: bool },
+    /// Reload the server's configuration: replies with [`Reply::Ok`].
+    Reload,
+    /// Restore a base64 encoded dump previously produced by [`Command::Dump`]: replies with
+    /// [`Reply::Ok`].
+    Restore { dump: String },
+    /// Revoke, and forget, `account`'s tokens: replies with [`Reply::Ok`].
+    Revoke { account: String },
+    /// Show `account`'s access token: replies with [`Reply::AccessToken`] or [`Reply::Pending`].
+    /// If `wait` is `Some`, wait up to that many seconds for an access token to become available.
+    /// If `min_valid` is `Some`, the access token must be valid for at least that many seconds
+    /// (refreshing it if necessary). If `dpop_proof` is `Some`, the reply includes a DPoP proof
+    /// for the request it describes, which is an error if the account does not use DPoP.
+    #[serde(rename = "showtoken")]
+    ShowToken {
+        account: String,
+        with_url: bool,
+        #[serde(default)]
+        wait: Option<u64>,
+        #[serde(default)]
+        min_valid: Option<u64>,
+        #[serde(default)]
+        dpop_proof: Option<DpopProofRequest>,
+    },
+    /// Shut the server down: replies with [`Reply::Ok`].
+    Shutdown,
+    /// Describe the state of every account: replies with [`Reply::Status`].
+    Status,
+    /// Stream events: replies with [`Reply::Subscribed`], after which the server sends a
+    /// [`ServerMsg::Event`] for each event until the client closes the connection. No further
+    /// requests can be made on the connection.
+    Subscribe,
+}
+
+impl Command {
+    /// The name of this command, as used in the `cmd` field of requests.
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::Dump => "dump",
+            Self::Info => "info",
+            Self::Refresh { .. } => "refresh",
+            Self::Reload => "reload",
+            Self::Restore { .. } => "restore",
+            Self::Revoke { .. } => "revoke",
+            Self::ShowToken { .. } => "showtoken",
+            Self::Shutdown => "shutdown",
+            Self::Status => "status",
+            Self::Subscribe => "subscribe",
+        }
+    }
+}
+
+/// The HTTP request a DPoP proof (RFC 9449) will accompany.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DpopProofRequest {
+    /// The HTTP method (e.g. `GET`).
+    pub method: String,
+    pub url: String,
+}
+
+/// A message sent by the server to a client.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServerMsg {
+    /// The reply to [`ClientMsg::Hello`]: the protocol version that will be used.
+    Hello { version: u32 },
+    /// The response to the [`ClientMsg::Request`] with the same `id`.
+    Response {
+        id: u64,
+        #[serde(flatten)]
+        outcome: Outcome,
+    },
+    /// An error not associated with a specific request, after which the server closes the
+    /// connection.
+    Error(ProtocolError),
+    /// Something happened to an account (see [`Command::Subscribe`]).
+    Event(Event),
+}
+
+/// The outcome of executing a [Command].
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok(Reply),
+    Error(ProtocolError),
+}
+
+/// The successful result of executing a [Command].
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "reply", rename_all = "snake_case")]
+pub enum Reply {
+    /// The command succeeded and has nothing else to report.
+    Ok,
+    AccessToken {
+        access_token: String,
+        /// The account's `username`, if it has one.
+        username: Option<String>,
+        /// The DPoP proof the client asked for, if any.
+        #[serde(default)]
+        dpop_proof: Option<String>,
+    },
+    /// A base64 encoded dump.
+    Dump {
+        dump: String,
+    },
+    Info(ServerInfo),
+    /// An access token will be available once the user has authorised the request at `url`
+    /// (entering `user_code`, for accounts using the device authorization grant). Both are `None`
+    /// if the client asked not to be given the URL.
+    Pending {
+        url: Option<String>,
+        user_code: Option<String>,
+    },
+    /// The access token will be refreshed in the background.
+    Scheduled,
+    /// Events will be streamed to the client.
+    Subscribed,
+    Status {
+        accounts: Vec<AccountStatus>,
+    },
+}
+
+/// The kinds of event that are sent to subscribers.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// A new access token has been obtained.
+    TokenNew,
+    /// The access token has been refreshed.
+    TokenRefreshed,
+    /// The access token has become invalid and a new one will need to be requested.
+    TokenInvalidated,
+    /// The tokens have been revoked.
+    TokenRevoked,
+    /// A new access token has been requested and is waiting for the user to authorise it.
+    AuthPending,
+    /// Refreshing the access token failed. This may be followed by
+    /// [`EventKind::TokenInvalidated`] if the failure was permanent.
+    RefreshFailed,
+}
+
+impl Display for EventKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TokenNew => write!(f, "token_new"),
+            Self::TokenRefreshed => write!(f, "token_refreshed"),
+            Self::TokenInvalidated => write!(f, "token_invalidated"),
+            Self::TokenRevoked => write!(f, "token_revoked"),
+            Self::AuthPending => write!(f, "auth_pending"),
+            Self::RefreshFailed => write!(f, "refresh_failed"),
+        }
+    }
+}
+
+/// An event, as sent to subscribers.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Event {
+    pub account: String,
+    pub event: EventKind,
+    /// When the event happened, as an RFC 3339 string.
+    pub timestamp: String,
+}
+
+/// The reply to [`Command::Info`].
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct ServerInfo {
+    /// Port of the HTTP server, if it is running.
+    pub http_port: Option<u16>,
+    /// Port of the HTTPS server, if it is running.
+    pub https_port: Option<u16>,
+    /// If the HTTPS server is running, its raw public key formatted in hex with each byte
+    /// separated by `:`.
+    pub https_pub_key: Option<String>,
+}
+
+/// The state of an account's tokens.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccountState {
+    /// There is no access token, and none has been requested.
+    None,
+    /// An access token has been requested, but the user has not yet authorised it.
+    Pending,
+    /// There is an access token which has not expired.
+    Active,
+    /// There is an access token which has expired.
+    Expired,
+}
+
+/// The status of an account. Times are RFC 3339 strings, and are `None` if they are not relevant
+/// to `state` or cannot be represented.
+#[derive(Debug, Deserialize, PartialEq, Serialize)]
+pub struct AccountStatus {
+    pub account: String,
+    pub state: AccountState,
+    pub last_notification: Option<String>,
+    pub access_token_obtained: Option<String>,
+    pub access_token_expiry: Option<String>,
+    pub last_refresh_attempt: Option<String>,
+    pub consecutive_refresh_fails: u64,
+    pub refresh_in_progress: bool,
+}
+
+impl AccountStatus {
+    /// Return a human readable description of this account's state.
+    #[must_use]
+    pub fn describe(&self) -> String {
+        // Times are shown in RFC 2822 format, which is easier for humans to read.
+        let fmt = |t: &Option<String>| {
+            t.as_deref()
+                .and_then(|x| DateTime::parse_from_rfc3339(x).ok())
+                .map_or_else(|| "<unknown time>".into(), |x| x.to_rfc2822())
+        };
+        match self.state {
+            AccountState::None => "No access token".into(),
+            AccountState::Pending if self.last_notification.is_some() => format!(
+                "Access token pending authentication (last notification {})",
+                fmt(&self.last_notification)
+            ),
+            AccountState::Pending => "Access token pending authentication".into(),
+            AccountState::Active => format!(
+                "Active access token (obtained {}; expires {})",
+                fmt(&self.access_token_obtained),
+                fmt(&self.access_token_expiry)
+            ),
+            AccountState::Expired if self.last_refresh_attempt.is_some() => format!(
+                "Access token expired (last refresh attempt {})",
+                fmt(&self.last_refresh_attempt)
+            ),
+            AccountState::Expired => "Access token expired (refresh not yet attempted)".into(),
+        }
+    }
+}
+
+/// The kinds of error that the server can report.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+    /// A message could not be parsed, or a command's arguments were invalid.
+    InvalidRequest,
+    /// The client and server have no protocol version in common.
+    UnsupportedVersion,
+    /// The named account does not exist.
+    NoAccount,
+    /// The user's authorisation of a new token failed or was cancelled.
+    AuthFailed,
+    /// The access token has expired, or will expire sooner than the client asked for, and could
+    /// not (yet) be refreshed.
+    TokenExpired,
+    /// Requesting a new token from the OAuth server failed.
+    RequestFailed,
+    /// Tokens were forgotten, but the OAuth server failed to revoke them.
+    RevokeFailed,
+    /// The configuration could not be reloaded.
+    ReloadFailed,
+    /// A dump could not be restored.
+    RestoreFailed,
+    /// The client is not allowed to execute the command.
+    PermissionDenied,
+    /// Something went wrong inside the server.
+    Internal,
+}
+
+/// An error reported by the server.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ProtocolError {
+    pub kind: ErrorKind,
+    /// A human readable description of the error.
+    pub message: String,
+}
+
+impl ProtocolError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl From<ProtocolError> for Outcome {
+    fn from(e: ProtocolError) -> Self {
+        Self::Error(e)
+    }
+}
+
+/// Write `msg` to `w` as a length-prefixed JSON message.
+pub fn write_msg<T: Serialize>(mut w: impl Write, msg: &T) -> io::Result<()> {
+    let buf = serde_json::to_vec(msg)?;
+    let len = u32::try_from(buf.len())
+        .ok()
+        .filter(|x| *x <= MAX_MSG_LEN)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Message too long"))?;
+    // Write the message in one go so that it isn't split across multiple packets unnecessarily.
+    let mut framed = Vec::with_capacity(4 + buf.len());
+    framed.extend(len.to_be_bytes());
+    framed.extend(buf);
+    w.write_all(&framed)?;
+    w.flush()
+}
+
+/// Read a length-prefixed JSON message from `r`, returning `Ok(None)` if `r` is at EOF.
+pub fn read_msg<T: DeserializeOwned>(mut r: impl Read) -> io::Result<Option<T>> {
+    let mut len = [0u8; 4];
+    match r.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u32::from_be_bytes(len);
+    if len > MAX_MSG_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message too long",
+        ));
+    }
+    let mut buf = vec![0u8; len as usize];
+    r.read_exact(&mut buf)?;
+    Ok(Some(serde_json::from_slice(&buf)?))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Vec::new();
+        let msgs = [
+            ServerMsg::Hello {
+                version: PROTOCOL_VERSION,
+            },
+            ServerMsg::Response {
+                id: 3,
+                outcome: Outcome::Ok(Reply::AccessToken {
+                    access_token: "a".into(),
+                    username: None,
+                    dpop_proof: None,
+                }),
+            },
+            ServerMsg::Response {
+                id: 4,
+                outcome: Outcome::Error(ProtocolError::new(ErrorKind::NoAccount, "b")),
+            },
+            ServerMsg::Error(ProtocolError::new(ErrorKind::UnsupportedVersion, "c")),
+            ServerMsg::Event(Event {
+                account: "d".into(),
+                event: EventKind::RefreshFailed,
+                timestamp: "2026-01-01T00:00:00+00:00".into(),
+            }),
+        ];
+        for m in &msgs {
+            write_msg(&mut buf, m).unwrap();
+        }
+        // Framed messages must start with a 0 byte to distinguish them from text commands.
+        assert_eq!(buf[0], 0);
+        let mut r = buf.as_slice();
+        for m in msgs {
+            assert_eq!(read_msg::<ServerMsg>(&mut r).unwrap(), Some(m));
+        }
+        assert_eq!(read_msg::<ServerMsg>(&mut r).unwrap(), None);
+    }
+
+    #[test]
+    fn wire_format() {
+        assert_eq!(
+            serde_json::from_str::<ClientMsg>(
+                r#"{"type": "request", "id": 1, "command": {"cmd": "showtoken", "account": "x", "with_url": false}}"#
+            )
+            .unwrap(),
+            ClientMsg::Request {
+                id: 1,
+                command: Command::ShowToken {
+                    account: "x".into(),
+                    with_url: false,
+                    wait: None,
+                    min_valid: None,
+                    dpop_proof: None
+                }
+            }
+        );
+        assert_eq!(
+            serde_json::to_value(ServerMsg::Response {
+                id: 2,
+                outcome: Outcome::Ok(Reply::Scheduled)
+            })
+            .unwrap(),
+            serde_json::json!({"type": "response", "id": 2, "ok": {"reply": "scheduled"}})
+        );
+        assert_eq!(
+            serde_json::to_value(ServerMsg::Response {
+                id: 3,
+                outcome: ProtocolError::new(ErrorKind::NoAccount, "No account 'x'").into()
+            })
+            .unwrap(),
+            serde_json::json!({"type": "response", "id": 3, "error": {"kind": "no_account", "message": "No account 'x'"}})
+        );
+        assert_eq!(
+            serde_json::to_value(ServerMsg::Event(Event {
+                account: "x".into(),
+                event: EventKind::TokenNew,
+                timestamp: "2026-01-01T00:00:00+00:00".into(),
+            }))
+            .unwrap(),
+            serde_json::json!({"type": "event", "account": "x", "event": "token_new", "timestamp": "2026-01-01T00:00:00+00:00"})
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Recording which processes accessed which tokens.
+
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+use chrono::Local;
+use pizauth::protocol::{Command, Outcome, Reply};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use super::peer::Peer;
+
+/// How many hex digits of a secret's SHA-256 hash are recorded? 16 digits (64 bits) are enough to
+/// tell tokens apart without giving away anything useful about them.
+const FINGERPRINT_LEN: usize = 16;
+
+/// An audit log entry for a command, which is completed once the command's outcome is known.
+pub struct AuditEntry {
+    cmd: &'static str,
+    account: Option<String>,
+    fingerprint: Option<String>,
+}
+
+impl AuditEntry {
+    /// Start an entry for `cmd`, or return `None` if `cmd` is not audited.
+    pub fn new(cmd: &Command) -> Option<Self> {
+        let (account, fingerprint) = match cmd {
+            Command::Refresh { account, .. }
+            | Command::Revoke { account }
+            | Command::ShowToken { account, .. } => (Some(account.clone()), None),
+            Command::Dump => (None, None),
+            Command::Restore { dump } => (None, Some(fingerprint(dump))),
+            Command::Info
+            | Command::Reload
+            | Command::Shutdown
+            | Command::Status
+            | Command::Subscribe => return None,
+        };
+        Some(Self {
+            cmd: cmd.name(),
+            account,
+            fingerprint,
+        })
+    }
+
+    /// Append this entry, for a command executed by `peer` with result `outcome`, to the audit
+    /// log `log` (as returned by [open]).
+    pub fn write(self, log: &mut File, peer: &Peer, outcome: &Outcome) -> io::Result<()> {
+        let (result, fingerprint) = match outcome {
+            Outcome::Ok(Reply::AccessToken { access_token, .. }) => {
+                ("ok".to_owned(), Some(fingerprint(access_token)))
+            }
+            Outcome::Ok(Reply::Dump { dump }) => ("ok".to_owned(), Some(fingerprint(dump))),
+            Outcome::Ok(Reply::Pending { .. }) => ("pending".to_owned(), self.fingerprint),
+            Outcome::Ok(_) => ("ok".to_owned(), self.fingerprint),
+            Outcome::Error(e) => (
+                serde_json::to_value(e.kind)
+                    .ok()
+                    .and_then(|x| x.as_str().map(str::to_owned))
+                    .unwrap_or_default(),
+                self.fingerprint,
+            ),
+        };
+        let mut line = json!({
+            "timestamp": Local::now().to_rfc3339(),
+            "cmd": self.cmd,
+            "account": self.account,
+            "pid": peer.pid,
+            "uid": peer.uid,
+            "exe": peer.exes.first(),
+            "result": result,
+            "fingerprint": fingerprint,
+        })
+        .to_string();
+        line.push('\n');
+        // Entries are small enough to be written with a single `write` to a file opened for
+        // appending, so entries from concurrent requests are not interleaved.
+        log.write_all(line.as_bytes())
+    }
+}
+
+/// Open the audit log at `path` for appending, creating it if necessary.
+pub fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(path)
+}
+
+/// Return a fingerprint of `secret` which identifies it without revealing it.
+fn fingerprint(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes())
+        .iter()
+        .take(FINGERPRINT_LEN / 2)
+        .fold(String::new(), |mut s, x| {
+            write!(s, "{x:02x}").ok();
+            s
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fingerprints() {
+        assert_eq!(fingerprint("test_access_token"), "4993552f2cc6c4e5");
+        assert_ne!(
+            fingerprint("test_access_token"),
+            fingerprint("test_renewed_access_token")
+        );
+    }
+}
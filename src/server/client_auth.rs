@@ -0,0 +1,281 @@
+//! Authenticating pizauth, as an OAuth2 client, to the OAuth2 server (RFC 6749 section 2.3, RFC
+//! 7523, and RFC 8705).
+
+use std::{
+    error::Error,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use rand::{rng, Rng};
+use serde_json::json;
+use ureq::{
+    http::Response,
+    tls::{parse_pem, Certificate, ClientCert, PemItem, PrivateKey, RootCerts, TlsConfig},
+    typestate::WithBody,
+    Agent, Body, RequestBuilder,
+};
+use url::form_urlencoded::byte_serialize;
+
+use super::{dpop::DpopKey, jwt::SigningKey};
+use crate::config::{Account, Credential, TokenEndpointAuthMethod};
+
+const CLIENT_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+/// How many seconds is a client assertion valid for? Assertions are created immediately before
+/// they are sent, so this only needs to allow for clock skew.
+const CLIENT_ASSERTION_LIFETIME: u64 = 5 * 60;
+/// Length in bytes of a client assertion's `jti`.
+const JTI_LEN: usize = 16;
+
+/// An account's client credentials, and how they are sent to the server. Credentials specified by
+/// commands are only obtained when a request is made.
+pub struct ClientAuth {
+    client_id: Credential,
+    client_secret: Option<Credential>,
+    method: TokenEndpointAuthMethod,
+    key: Option<PathBuf>,
+    kid: Option<String>,
+    /// The CA certificates trusted instead of the default roots.
+    tls_ca_cert: Option<PathBuf>,
+    /// The certificate chain and private key used for mutual TLS.
+    tls_client: Option<(PathBuf, PathBuf)>,
+    /// The audience of client assertions.
+    token_uri: String,
+}
+
+impl ClientAuth {
+    pub fn new(act: &Account) -> Self {
+        Self {
+            client_id: act.client_id.clone(),
+            client_secret: act.client_secret.clone(),
+            method: act.token_endpoint_auth_method,
+            key: act.client_assertion_key.clone(),
+            kid: act.client_assertion_kid.clone(),
+            tls_ca_cert: act.tls_ca_cert.clone(),
+            tls_client: act.tls_client_cert.clone().zip(act.tls_client_key.clone()),
+            token_uri: act.token_uri().to_owned(),
+        }
+    }
+
+    /// POST `pairs`, along with `client_id` and the client's credentials, as a form to `uri`
+    /// using `agent`. If `dpop` is `Some`, the request is accompanied by a DPoP proof signed with
+    /// that key.
+    pub fn post_form(
+        &self,
+        agent: &Agent,
+        uri: &str,
+        pairs: &[(&str, &str)],
+        dpop: Option<&DpopKey>,
+    ) -> Result<Response<Body>, ureq::Error> {
+        let Some(dpop) = dpop else {
+            let (req, form) = self.request(agent, uri, pairs)?;
+            return req.send_form(form);
+        };
+        let mut nonce = None;
+        loop {
+            let (req, form) = self.request(agent, uri, pairs)?;
+            let proof = dpop
+                .proof("POST", uri, nonce.as_deref(), None)
+                .map_err(|e| ureq::Error::Other(e.to_string().into()))?;
+            let response = req
+                .header("DPoP", proof)
+                .config()
+                .http_status_as_error(false)
+                .build()
+                .send_form(form)?;
+            let status = response.status();
+            // A server which requires nonces rejects proofs without one, telling us which nonce
+            // to use (RFC 9449 section 8). We retry once with that nonce.
+            if nonce.is_none() && (status == 400 || status == 401) {
+                if let Some(x) = response
+                    .headers()
+                    .get("DPoP-Nonce")
+                    .and_then(|x| x.to_str().ok())
+                {
+                    nonce = Some(x.to_owned());
+                    continue;
+                }
+            }
+            if agent.config().http_status_as_error() && status.as_u16() >= 400 {
+                return Err(ureq::Error::StatusCode(status.as_u16()));
+            }
+            return Ok(response);
+        }
+    }
+
+    /// Create a POST request to `uri` which authenticates the client, returning it and the form
+    /// (containing `pairs`) that should be sent with it.
+    fn request<'a>(
+        &self,
+        agent: &Agent,
+        uri: &str,
+        pairs: &[(&'a str, &str)],
+    ) -> Result<(RequestBuilder<WithBody>, Vec<(&'a str, String)>), ureq::Error> {
+        let client_id = self
+            .client_id
+            .get()
+            .map_err(|e| ureq::Error::Other(e.into()))?;
+        let client_secret = self
+            .client_secret
+            .as_ref()
+            .map(Credential::get)
+            .transpose()
+            .map_err(|e| ureq::Error::Other(e.into()))?;
+        let mut form = vec![("client_id", client_id.clone())];
+        form.extend(pairs.iter().map(|(k, v)| (*k, (*v).to_owned())));
+        let mut req = agent.post(uri);
+        if self.tls_client.is_some() || self.tls_ca_cert.is_some() {
+            // Certificates and keys are reloaded for every request so that they can be rotated
+            // without restarting pizauth.
+            let tls_config = tls_config(self.tls_client.as_ref(), self.tls_ca_cert.as_deref())
+                .map_err(|e| ureq::Error::Other(e.to_string().into()))?;
+            req = req.config().tls_config(tls_config).build();
+        }
+        match (self.method, client_secret) {
+            (TokenEndpointAuthMethod::Post, Some(secret)) => {
+                form.push(("client_secret", secret));
+            }
+            (TokenEndpointAuthMethod::Post, None) => (),
+            (TokenEndpointAuthMethod::Basic, secret) => {
+                // The client ID and secret are form encoded before being base64 encoded (RFC 6749
+                // section 2.3.1).
+                let credentials = format!(
+                    "{}:{}",
+                    byte_serialize(client_id.as_bytes()).collect::<String>(),
+                    byte_serialize(secret.as_deref().unwrap_or("").as_bytes()).collect::<String>()
+                );
+                req = req.header(
+                    "Authorization",
+                    format!("Basic {}", STANDARD.encode(credentials)),
+                );
+            }
+            (TokenEndpointAuthMethod::PrivateKeyJwt, _) => {
+                // Each request gets a fresh assertion, since servers may reject reused `jti`s.
+                let assertion = self
+                    .client_assertion(&client_id)
+                    .map_err(|e| ureq::Error::Other(e.to_string().into()))?;
+                form.push(("client_assertion_type", CLIENT_ASSERTION_TYPE.to_owned()));
+                form.push(("client_assertion", assertion));
+            }
+        }
+        Ok((req, form))
+    }
+
+    /// Create a signed client assertion (RFC 7523 section 3) for `client_id`.
+    fn client_assertion(&self, client_id: &str) -> Result<String, Box<dyn Error>> {
+        // Config parsing guarantees that `private_key_jwt` accounts have a key.
+        let key = SigningKey::from_pem_file(self.key.as_ref().unwrap())?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut jti = [0u8; JTI_LEN];
+        rng().fill_bytes(&mut jti);
+        let mut header = json!({"typ": "JWT"});
+        if let Some(kid) = &self.kid {
+            header["kid"] = kid.as_str().into();
+        }
+        key.sign(
+            header,
+            &json!({
+                "iss": client_id,
+                "sub": client_id,
+                "aud": self.token_uri,
+                "jti": URL_SAFE_NO_PAD.encode(jti),
+                "iat": now,
+                "exp": now + CLIENT_ASSERTION_LIFETIME,
+            }),
+        )
+    }
+}
+
+/// Return a TLS configuration which, if `client` is `Some((cert, key))`, presents the PEM encoded
+/// certificate chain in `cert`, whose private key is in `key`, to the server (RFC 8705 section 2)
+/// and which, if `ca_cert` is not `None`, trusts only the PEM encoded certificates in `ca_cert`.
+pub(super) fn tls_config(
+    client: Option<&(PathBuf, PathBuf)>,
+    ca_cert: Option<&Path>,
+) -> Result<TlsConfig, Box<dyn Error>> {
+    let mut builder = TlsConfig::builder();
+    if let Some((cert, key)) = client {
+        let certs = read_certs(cert)?;
+        let key = PrivateKey::from_pem(&fs::read(key).map_err(|e| tls_err(key, &e))?)
+            .map_err(|e| tls_err(key, &e))?;
+        builder = builder.client_cert(Some(ClientCert::new_with_certs(&certs, key)));
+    }
+    if let Some(ca_cert) = ca_cert {
+        builder = builder.root_certs(RootCerts::new_with_certs(&read_certs(ca_cert)?));
+    }
+    Ok(builder.build())
+}
+
+/// Read the PEM encoded certificates in `path`, of which there must be at least one.
+fn read_certs(path: &Path) -> Result<Vec<Certificate<'static>>, Box<dyn Error>> {
+    let certs = parse_pem(&fs::read(path).map_err(|e| tls_err(path, &e))?)
+        .filter_map(|x| match x {
+            Ok(PemItem::Certificate(x)) => Some(Ok(x)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| tls_err(path, &e))?;
+    if certs.is_empty() {
+        return Err(tls_err(path, &"no certificates found").into());
+    }
+    Ok(certs)
+}
+
+fn tls_err(path: &Path, e: &dyn Display) -> String {
+    format!("Can't load TLS {path:?}: {e}")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rcgen::{generate_simple_self_signed, CertifiedKey};
+    use tempfile::TempDir;
+
+    #[test]
+    fn tls_client_cert() {
+        let CertifiedKey { cert, signing_key } =
+            generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+        let pem = |label: &str, der: &[u8]| {
+            format!(
+                "-----BEGIN {label}-----\n{}\n-----END {label}-----\n",
+                STANDARD.encode(der)
+            )
+        };
+        let dir = TempDir::new().unwrap();
+        let certp = dir.path().join("cert.pem");
+        let keyp = dir.path().join("key.pem");
+        fs::write(&certp, pem("CERTIFICATE", cert.der())).unwrap();
+        fs::write(&keyp, pem("PRIVATE KEY", &signing_key.serialize_der())).unwrap();
+
+        let tls = tls_config(Some(&(certp.clone(), keyp.clone())), None).unwrap();
+        let client_cert = tls.client_cert().unwrap();
+        assert_eq!(client_cert.certs().len(), 1);
+        assert_eq!(client_cert.certs()[0].der(), cert.der().as_ref());
+        assert!(matches!(tls.root_certs(), RootCerts::WebPki));
+
+        let tls = tls_config(None, Some(&certp)).unwrap();
+        assert!(tls.client_cert().is_none());
+        match tls.root_certs() {
+            RootCerts::Specific(certs) => {
+                assert_eq!(certs.len(), 1);
+                assert_eq!(certs[0].der(), cert.der().as_ref());
+            }
+            _ => panic!(),
+        }
+
+        // A file without certificates, or a missing file, is an error.
+        assert!(tls_config(Some(&(keyp.clone(), keyp.clone())), None)
+            .unwrap_err()
+            .to_string()
+            .contains("no certificates found"));
+        assert!(tls_config(None, Some(&keyp)).is_err());
+        assert!(tls_config(Some(&(certp, dir.path().join("missing.pem"))), None).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+//! The client credentials grant (RFC 6749 section 4.4). There is no user involvement: we simply ask
+//! the token endpoint for an access token, authenticating as the client itself.
+
+use std::{error::Error, sync::Arc};
+
+use boot_time::Instant;
+use serde_json::Value;
+
+use super::{
+    client_auth::ClientAuth,
+    dpop::{token_type_ok, DpopKey},
+    eventer::TokenEvent,
+    expiry_instant, AccountId, AuthenticatorState, CTGuard, TokenState, UREQ_TIMEOUT,
+};
+
+/// Request a new access token for `act_id`, whose tokenstate must be `Empty`, blocking until the
+/// token has been obtained or an error occurred. Note that this function drops `ct_lk` while it
+/// makes a request to the token endpoint. If successful, the new access token is returned.
+pub fn request_client_credentials(
+    pstate: Arc<AuthenticatorState>,
+    ct_lk: CTGuard,
+    act_id: AccountId,
+) -> Result<String, Box<dyn Error>> {
+    assert!(matches!(ct_lk.tokenstate(act_id), TokenState::Empty));
+
+    let act = ct_lk.account(act_id);
+    let act_name = act.name.clone();
+    let token_uri = act.token_uri().to_owned();
+    let client_auth = ClientAuth::new(act);
+    let dpop_key = act.dpop.then(DpopKey::generate).transpose()?;
+    let scopes_join = act.scopes.join(" ");
+    let mut pairs = vec![("grant_type", "client_credentials")];
+    if !act.scopes.is_empty() {
+        pairs.push(("scope", scopes_join.as_str()));
+    }
+    drop(ct_lk);
+
+    let agent_conf = ureq::Agent::config_builder()
+        .timeout_global(Some(UREQ_TIMEOUT))
+        .build();
+    let body = match client_auth.post_form(
+        &ureq::Agent::new_with_config(agent_conf),
+        &token_uri,
+        &pairs,
+        dpop_key.as_ref(),
+    ) {
+        Ok(response) => response.into_body().read_to_string()?,
+        Err(ureq::Error::StatusCode(code)) => {
+            return Err(
+                format!("Requesting a token for {act_name} failed: HTTP code {code}").into(),
+            )
+        }
+        Err(e) => return Err(format!("Requesting a token for {act_name} failed: {e}").into()),
+    };
+    let parsed = serde_json::from_str::<Value>(&body)
+        .map_err(|e| format!("Requesting a token for {act_name} failed: invalid JSON: {e}"))?;
+    if let Some(err_msg) = parsed["error"].as_str() {
+        return Err(format!("Requesting a token for {act_name} failed: {err_msg}").into());
+    }
+
+    match (
+        parsed["token_type"].as_str(),
+        parsed["expires_in"].as_u64(),
+        parsed["access_token"].as_str(),
+    ) {
+        (token_type, Some(expires_in), Some(access_token))
+            if token_type_ok(token_type, dpop_key.as_ref()) =>
+        {
+            let now = Instant::now();
+            let mut ct_lk = pstate.ct_lock();
+            if !ct_lk.is_act_id_valid(act_id) {
+                return Err(format!(
+                    "Account {act_name} changed while requesting a token: request a fresh token"
+                )
+                .into());
+            }
+            let expiry = expiry_instant(&ct_lk, act_id, now, expires_in)?;
+            ct_lk.tokenstate_replace(
+                act_id,
+                TokenState::Active {
+                    access_token: access_token.to_owned(),
+                    access_token_obtained: now,
+                    access_token_expiry: expiry,
+                    ongoing_refresh: false,
+                    consecutive_refresh_fails: 0,
+                    last_refresh_attempt: None,
+                    refresh_token: None,
+                    dpop_key,
+                },
+            );
+            drop(ct_lk);
+            pstate.refresher.notify_changes();
+            pstate.eventer.token_event(act_name, TokenEvent::New);
+            Ok(access_token.to_owned())
+        }
+        _ => Err(
+            format!("Requesting a token for {act_name} failed: invalid response received").into(),
+        ),
+    }
+}
@@ -0,0 +1,325 @@
+//! The device authorization grant (RFC 8628). Rather than redirecting a web browser to pizauth's
+//! HTTP(S) server, the user visits a verification URI (possibly on another machine) and enters a
+//! user code. In the meantime, we poll the token endpoint until the user has completed (or
+//! refused) authorisation or the device code expires.
+
+use std::{error::Error, sync::Arc, thread, time::Duration};
+
+use boot_time::Instant;
+use log::warn;
+use serde_json::Value;
+use url::Url;
+
+use super::{
+    client_auth::ClientAuth,
+    dpop::{token_type_ok, DpopKey},
+    eventer::TokenEvent,
+    expiry_instant,
+    request_token::PendingAuth,
+    AccountId, AuthenticatorState, CTGuard, DeviceCodes, TokenState, UREQ_TIMEOUT,
+};
+
+/// The `grant_type` used when polling the token endpoint.
+const DEVICE_CODE_GRANT_TYPE: &str = "urn:ietf:params:oauth:grant-type:device_code";
+/// If the device authorization endpoint doesn't tell us how often to poll, how many seconds
+/// should we wait between polls? RFC 8628 section 3.2 mandates this default.
+const POLL_INTERVAL_DEFAULT: u64 = 5;
+/// How many seconds should we add to the polling interval each time the server tells us to
+/// `slow_down`? RFC 8628 section 3.5 mandates this value. We also use it to back off after
+/// network errors, which section 3.5 says should reduce the polling frequency.
+const SLOW_DOWN_INCREMENT: u64 = 5;
+
+/// Request a device code for `act_id`, whose tokenstate must be `Empty` or `Pending`, and start
+/// polling the token endpoint for a token. Note that this function drops `ct_lk` while it makes a
+/// request to the device authorization endpoint.
+pub fn request_device_code(
+    pstate: Arc<AuthenticatorState>,
+    ct_lk: CTGuard,
+    act_id: AccountId,
+) -> Result<PendingAuth, Box<dyn Error>> {
+    let act = ct_lk.account(act_id);
+    let act_name = act.name.clone();
+    // Config parsing or discovery guarantees device code grant accounts have a `device_auth_uri`.
+    let device_auth_uri = act.device_auth_uri().unwrap().to_owned();
+    let token_uri = act.token_uri().to_owned();
+    let client_auth = ClientAuth::new(act);
+    let dpop_key = act.dpop.then(DpopKey::generate).transpose()?;
+    let scopes_join = act.scopes.join(" ");
+    let mut pairs = Vec::new();
+    if !act.scopes.is_empty() {
+        pairs.push(("scope", scopes_join.as_str()));
+    }
+    drop(ct_lk);
+
+    let parsed = match device_authorization(&device_auth_uri, &client_auth, &pairs) {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!("Authentication for {act_name} failed: {e}");
+            pstate
+                .notifier
+                .notify_error(&pstate, act_name, msg.clone())?;
+            return Err(msg.into());
+        }
+    };
+
+    let device_code = parsed.device_code.clone();
+    let user_code = parsed.user_code.clone();
+    let url = parsed.url.clone();
+    let mut ct_lk = pstate.ct_lock();
+    if !ct_lk.is_act_id_valid(act_id) {
+        return Err(format!(
+            "Account {act_name} changed while requesting a device code: request a fresh token"
+        )
+        .into());
+    }
+    ct_lk.tokenstate_replace(
+        act_id,
+        TokenState::Pending {
+            code_verifier: String::new(),
+            last_notification: None,
+            state: String::new(),
+            url: url.clone(),
+            device_codes: Some(DeviceCodes {
+                device_code: device_code.clone(),
+                user_code: user_code.clone(),
+            }),
+        },
+    );
+    drop(ct_lk);
+    pstate.notifier.notify_changes();
+
+    let expiry = Instant::now()
+        .checked_add(Duration::from_secs(parsed.expires_in))
+        .ok_or("Can't represent device code expiry")?;
+    let interval = Duration::from_secs(parsed.interval);
+    thread::spawn(move || {
+        if let Err(e) = poll(
+            &pstate,
+            &token_uri,
+            &client_auth,
+            dpop_key,
+            &device_code,
+            interval,
+            expiry,
+        ) {
+            warn!("{e:}");
+        }
+    });
+
+    Ok(PendingAuth {
+        url,
+        user_code: Some(user_code),
+    })
+}
+
+/// A successful response from a device authorization endpoint.
+struct DeviceAuthorization {
+    device_code: String,
+    user_code: String,
+    /// The URI the user should visit: if the server gave us a `verification_uri_complete` (which
+    /// embeds the user code) we prefer that to the plain `verification_uri`.
+    url: Url,
+    expires_in: u64,
+    interval: u64,
+}
+
+/// Make a device authorization request (RFC 8628 section 3.1) to `device_auth_uri`.
+fn device_authorization(
+    device_auth_uri: &str,
+    client_auth: &ClientAuth,
+    pairs: &[(&str, &str)],
+) -> Result<DeviceAuthorization, Box<dyn Error>> {
+    let agent_conf = ureq::Agent::config_builder()
+        .timeout_global(Some(UREQ_TIMEOUT))
+        .build();
+    let body = match client_auth.post_form(
+        &ureq::Agent::new_with_config(agent_conf),
+        device_auth_uri,
+        pairs,
+        None,
+    ) {
+        Ok(response) => response.into_body().read_to_string()?,
+        Err(ureq::Error::StatusCode(code)) => {
+            return Err(format!("HTTP code {code}").into());
+        }
+        Err(e) => return Err(format!("couldn't connect to {device_auth_uri}: {e}").into()),
+    };
+    let parsed = serde_json::from_str::<Value>(&body).map_err(|e| format!("Invalid JSON: {e}"))?;
+    if let Some(err_msg) = parsed["error"].as_str() {
+        return Err(err_msg.into());
+    }
+
+    // Some providers (notably Google) use the non-standard `verification_url`.
+    let verification_uri = parsed["verification_uri"]
+        .as_str()
+        .or_else(|| parsed["verification_url"].as_str());
+    match (
+        parsed["device_code"].as_str(),
+        parsed["user_code"].as_str(),
+        parsed["verification_uri_complete"]
+            .as_str()
+            .or(verification_uri),
+        parsed["expires_in"].as_u64(),
+    ) {
+        (Some(device_code), Some(user_code), Some(url), Some(expires_in)) => {
+            Ok(DeviceAuthorization {
+                device_code: device_code.to_owned(),
+                user_code: user_code.to_owned(),
+                url: Url::parse(url)?,
+                expires_in,
+                interval: parsed["interval"].as_u64().unwrap_or(POLL_INTERVAL_DEFAULT),
+            })
+        }
+        _ => Err("invalid response received".into()),
+    }
+}
+
+/// Poll the token endpoint (RFC 8628 section 3.4) until the user has authorised the device code
+/// `device_code`, the device code expires, or the tokenstate changes.
+fn poll(
+    pstate: &Arc<AuthenticatorState>,
+    token_uri: &str,
+    client_auth: &ClientAuth,
+    dpop_key: Option<DpopKey>,
+    device_code: &str,
+    mut interval: Duration,
+    expiry: Instant,
+) -> Result<(), Box<dyn Error>> {
+    let pairs = [
+        ("device_code", device_code),
+        ("grant_type", DEVICE_CODE_GRANT_TYPE),
+    ];
+    // Error responses from the token endpoint have a 400 status code, but contain information we
+    // need to act on, so we can't treat status codes as errors.
+    let agent_conf = ureq::Agent::config_builder()
+        .timeout_global(Some(UREQ_TIMEOUT))
+        .http_status_as_error(false)
+        .build();
+
+    loop {
+        thread::sleep(interval);
+        if Instant::now() >= expiry {
+            return fail(pstate, device_code, "device code expired");
+        }
+        if pstate
+            .ct_lock()
+            .act_id_matching_device_code(device_code)
+            .is_none()
+        {
+            // The user has requested a new token, revoked this one, or the config has changed.
+            return Ok(());
+        }
+
+        let body = match client_auth
+            .post_form(
+                &ureq::Agent::new_with_config(agent_conf.clone()),
+                token_uri,
+                &pairs,
+                dpop_key.as_ref(),
+            )
+            .and_then(|response| response.into_body().read_to_string())
+        {
+            Ok(s) => s,
+            Err(e) => {
+                // Probably a temporary network error, so we keep polling, but less often.
+                interval += Duration::from_secs(SLOW_DOWN_INCREMENT);
+                warn!(
+                    "Polling {token_uri} failed: {e} (retrying in {}s)",
+                    interval.as_secs()
+                );
+                continue;
+            }
+        };
+        let parsed = match serde_json::from_str::<Value>(&body) {
+            Ok(x) => x,
+            Err(e) => return fail(pstate, device_code, &format!("Invalid JSON: {e}")),
+        };
+
+        match parsed["error"].as_str() {
+            Some("authorization_pending") => continue,
+            Some("slow_down") => {
+                interval += Duration::from_secs(SLOW_DOWN_INCREMENT);
+                continue;
+            }
+            Some(err_msg) => return fail(pstate, device_code, err_msg),
+            None => (),
+        }
+
+        let ct_lk = pstate.ct_lock();
+        let act_id = match ct_lk.act_id_matching_device_code(device_code) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        match (
+            parsed["token_type"].as_str(),
+            parsed["expires_in"].as_u64(),
+            parsed["access_token"].as_str(),
+            parsed["refresh_token"].as_str(),
+        ) {
+            (token_type, Some(expires_in), Some(access_token), refresh_token)
+                if token_type_ok(token_type, dpop_key.as_ref()) =>
+            {
+                activate(
+                    pstate,
+                    ct_lk,
+                    act_id,
+                    access_token,
+                    expires_in,
+                    refresh_token,
+                    dpop_key,
+                )?;
+            }
+            _ => {
+                drop(ct_lk);
+                fail(pstate, device_code, "invalid response received")?;
+            }
+        }
+        return Ok(());
+    }
+}
+
+/// Move `act_id` to [`TokenState::Active`] with the token details we've received.
+fn activate(
+    pstate: &AuthenticatorState,
+    mut ct_lk: CTGuard,
+    act_id: AccountId,
+    access_token: &str,
+    expires_in: u64,
+    refresh_token: Option<&str>,
+    dpop_key: Option<DpopKey>,
+) -> Result<(), Box<dyn Error>> {
+    let now = Instant::now();
+    let expiry = expiry_instant(&ct_lk, act_id, now, expires_in)?;
+    let act_name = ct_lk.account(act_id).name.clone();
+    ct_lk.tokenstate_replace(
+        act_id,
+        TokenState::Active {
+            access_token: access_token.to_owned(),
+            access_token_obtained: now,
+            access_token_expiry: expiry,
+            ongoing_refresh: false,
+            consecutive_refresh_fails: 0,
+            last_refresh_attempt: None,
+            refresh_token: refresh_token.map(|x| x.to_owned()),
+            dpop_key,
+        },
+    );
+    drop(ct_lk);
+    pstate.refresher.notify_changes();
+    pstate.eventer.token_event(act_name, TokenEvent::New);
+    Ok(())
+}
+
+/// If the account with `device_code` is still pending, notify the user of failure and mark the
+/// tokenstate as [`TokenState::Empty`].
+fn fail(pstate: &AuthenticatorState, device_code: &str, msg: &str) -> Result<(), Box<dyn Error>> {
+    let mut ct_lk = pstate.ct_lock();
+    if let Some(act_id) = ct_lk.act_id_matching_device_code(device_code) {
+        let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+        let act_name = ct_lk.account(act_id).name.clone();
+        let msg = format!("Authentication for {act_name} failed: {msg:}");
+        drop(ct_lk);
+        pstate.notifier.notify_error(pstate, act_name, msg)?;
+    }
+    Ok(())
+}
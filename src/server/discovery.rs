@@ -0,0 +1,155 @@
+//! Discovering an OAuth2 server's endpoints from its issuer identifier, using either OIDC
+//! Discovery or RFC 8414 (OAuth 2.0 Authorization Server Metadata).
+
+use std::{collections::HashMap, error::Error, sync::Arc};
+
+use log::warn;
+use serde_json::Value;
+use url::Url;
+
+use super::{client_auth::tls_config, UREQ_TIMEOUT};
+use crate::config::{Account, Config, ProviderMetadata};
+
+/// For each account in `conf` with an `issuer`, fetch the issuer's metadata and fill in any
+/// endpoints that weren't explicitly specified. If fetching fails for an account whose `issuer` is
+/// the same as in `old_accounts` (i.e. the accounts in use before a reload), the previously
+/// discovered metadata is reused, so that one unreachable server doesn't stop the configuration
+/// being reloaded. Returns `Err` with a string suitable for reporting to the user if discovery
+/// fails for any other account.
+pub fn discover(
+    conf: &mut Config,
+    old_accounts: &HashMap<String, Arc<Account>>,
+) -> Result<(), Box<dyn Error>> {
+    for act in conf.accounts.values_mut() {
+        if let Some(issuer) = &act.issuer {
+            let metadata = match fetch_metadata(act, issuer) {
+                Ok(x) => x,
+                Err(e) => match old_accounts
+                    .get(&act.name)
+                    .filter(|x| x.issuer == act.issuer)
+                    .and_then(|x| x.metadata())
+                {
+                    Some(x) => {
+                        warn!(
+                            "Discovery for account {} failed, using previous metadata: {e}",
+                            act.name
+                        );
+                        x.clone()
+                    }
+                    None => {
+                        return Err(format!("Discovery for account {} failed: {e}", act.name).into())
+                    }
+                },
+            };
+            Arc::make_mut(act).set_metadata(metadata)?;
+        }
+    }
+    Ok(())
+}
+
+/// Fetch the metadata for `act`'s `issuer`, trying OIDC Discovery's location first and then RFC
+/// 8414's.
+fn fetch_metadata(act: &Account, issuer: &str) -> Result<ProviderMetadata, Box<dyn Error>> {
+    let issuer_url = Url::parse(issuer)?;
+    let trimmed = issuer.trim_end_matches('/');
+    let oidc_uri = format!("{trimmed}/.well-known/openid-configuration");
+    // RFC 8414 section 3: the well-known path is inserted between the host and any path
+    // component of the issuer.
+    let mut rfc8414_url = issuer_url.clone();
+    rfc8414_url.set_path(&format!(
+        "/.well-known/oauth-authorization-server{}",
+        issuer_url.path().trim_end_matches('/')
+    ));
+    rfc8414_url.set_query(None);
+
+    let body = match get(act, &oidc_uri) {
+        Ok(x) => x,
+        Err(oidc_err) => get(act, rfc8414_url.as_str()).map_err(|rfc8414_err| {
+            format!("couldn't fetch {oidc_uri} ({oidc_err}) or {rfc8414_url} ({rfc8414_err})")
+        })?,
+    };
+    let parsed = serde_json::from_str::<Value>(&body).map_err(|e| format!("Invalid JSON: {e}"))?;
+
+    // Both OIDC Discovery (section 4.3) and RFC 8414 (section 3.3) require the issuer in the
+    // metadata to be identical to the one we asked for: if it isn't, the metadata may have been
+    // served by an attacker. Even a trailing `/` counts as a difference.
+    match parsed["issuer"].as_str() {
+        Some(x) if x == issuer => (),
+        Some(x) => return Err(format!("metadata is for issuer '{x}' not '{issuer}'").into()),
+        None => return Err("metadata does not contain 'issuer'".into()),
+    }
+
+    Ok(ProviderMetadata {
+        authorization_response_iss_parameter_supported: parsed
+            ["authorization_response_iss_parameter_supported"]
+            .as_bool()
+            .unwrap_or(false),
+        authorization_endpoint: endpoint(&parsed, "authorization_endpoint")?,
+        device_authorization_endpoint: endpoint(&parsed, "device_authorization_endpoint")?,
+        revocation_endpoint: endpoint(&parsed, "revocation_endpoint")?,
+        token_endpoint: endpoint(&parsed, "token_endpoint")?,
+    })
+}
+
+/// Return the endpoint `name` from `parsed`, if it exists, checking it against the same rules as
+/// endpoints specified in the config.
+fn endpoint(parsed: &Value, name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    let Some(x) = parsed[name].as_str() else {
+        return Ok(None);
+    };
+    match Url::parse(x) {
+        Ok(url) if url.fragment().is_some() => {
+            Err(format!("'{name}': URI fragments ('#...') are not allowed").into())
+        }
+        Ok(url) if url.scheme() == "http" || url.scheme() == "https" => Ok(Some(x.to_owned())),
+        Ok(_) => Err(format!("'{name}': not a valid HTTP or HTTPS URI").into()),
+        Err(e) => Err(format!("Invalid URI for '{name}': {e}").into()),
+    }
+}
+
+/// GET `uri` with `act`'s TLS settings.
+fn get(act: &Account, uri: &str) -> Result<String, Box<dyn Error>> {
+    let tls_client = act.tls_client_cert.clone().zip(act.tls_client_key.clone());
+    let agent_conf = ureq::Agent::config_builder()
+        .timeout_global(Some(UREQ_TIMEOUT))
+        .tls_config(tls_config(tls_client.as_ref(), act.tls_ca_cert.as_deref())?)
+        .build();
+    Ok(ureq::Agent::new_with_config(agent_conf)
+        .get(uri)
+        .call()?
+        .into_body()
+        .read_to_string()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn endpoints() {
+        let parsed = json!({
+            "authorization_endpoint": "https://a.com/auth",
+            "device_authorization_endpoint": "https://a.com/device#x",
+            "revocation_endpoint": "file:///revoke",
+            "token_endpoint": "not a uri",
+        });
+        assert_eq!(
+            endpoint(&parsed, "authorization_endpoint").unwrap(),
+            Some("https://a.com/auth".to_owned())
+        );
+        assert_eq!(endpoint(&parsed, "par_endpoint").unwrap(), None);
+        assert!(endpoint(&parsed, "device_authorization_endpoint")
+            .unwrap_err()
+            .to_string()
+            .contains("URI fragments ('#...') are not allowed"));
+        assert!(endpoint(&parsed, "revocation_endpoint")
+            .unwrap_err()
+            .to_string()
+            .contains("not a valid HTTP or HTTPS URI"));
+        assert!(endpoint(&parsed, "token_endpoint")
+            .unwrap_err()
+            .to_string()
+            .contains("Invalid URI for 'token_endpoint'"));
+    }
+}
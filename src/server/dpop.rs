@@ -0,0 +1,179 @@
+//! Demonstrating Proof of Possession (DPoP, RFC 9449): access tokens are bound to a key pair
+//! generated by pizauth, and each use of a token must be accompanied by a proof signed with that
+//! key.
+
+use std::{
+    error::Error,
+    fmt::{self, Debug, Formatter},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use rand::{rng, Rng};
+use ring::{
+    rand::SystemRandom,
+    signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use super::jwt::SigningKey;
+
+/// The `token_type` of DPoP-bound access tokens.
+pub const DPOP_TOKEN_TYPE: &str = "DPoP";
+/// Length in bytes of a proof's `jti`.
+const JTI_LEN: usize = 16;
+
+/// An ECDSA P-256 key pair to which an account's tokens are bound. The key is stored in PKCS#8
+/// format so that it can be dumped and restored along with the tokens.
+#[derive(Clone, PartialEq)]
+pub struct DpopKey {
+    pkcs8: Vec<u8>,
+}
+
+impl DpopKey {
+    pub fn generate() -> Result<Self, Box<dyn Error>> {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .map_err(|_| "Can't generate DPoP key")?;
+        Ok(Self {
+            pkcs8: pkcs8.as_ref().to_owned(),
+        })
+    }
+
+    pub fn from_pkcs8(pkcs8: Vec<u8>) -> Self {
+        Self { pkcs8 }
+    }
+
+    pub fn pkcs8(&self) -> &[u8] {
+        &self.pkcs8
+    }
+
+    /// Return a DPoP proof for an HTTP request with method `htm` to `htu`. `nonce` is the most
+    /// recent `DPoP-Nonce` the server sent us, if any. If the request is to a resource server,
+    /// `access_token` is the token that the proof accompanies.
+    pub fn proof(
+        &self,
+        htm: &str,
+        htu: &str,
+        nonce: Option<&str>,
+        access_token: Option<&str>,
+    ) -> Result<String, Box<dyn Error>> {
+        let key = EcdsaKeyPair::from_pkcs8(
+            &ECDSA_P256_SHA256_FIXED_SIGNING,
+            &self.pkcs8,
+            &SystemRandom::new(),
+        )
+        .map_err(|_| "Invalid DPoP key")?;
+        // The public key is an uncompressed point: 0x04 followed by the x and y coordinates.
+        let (x, y) = key.public_key().as_ref()[1..].split_at(32);
+        let header = json!({
+            "typ": "dpop+jwt",
+            "jwk": {
+                "kty": "EC",
+                "crv": "P-256",
+                "x": URL_SAFE_NO_PAD.encode(x),
+                "y": URL_SAFE_NO_PAD.encode(y),
+            },
+        });
+
+        // The proof covers the URI without its query and fragment (RFC 9449 section 4.2).
+        let mut htu = Url::parse(htu)?;
+        htu.set_query(None);
+        htu.set_fragment(None);
+        let mut jti = [0u8; JTI_LEN];
+        rng().fill_bytes(&mut jti);
+        let mut claims = json!({
+            "jti": URL_SAFE_NO_PAD.encode(jti),
+            "htm": htm,
+            "htu": htu.as_str(),
+            "iat": SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+        });
+        if let Some(nonce) = nonce {
+            claims["nonce"] = Value::from(nonce);
+        }
+        if let Some(access_token) = access_token {
+            claims["ath"] = URL_SAFE_NO_PAD
+                .encode(Sha256::digest(access_token.as_bytes()))
+                .into();
+        }
+        SigningKey::Ecdsa(key).sign(header, &claims)
+    }
+}
+
+/// Is `token_type`, as returned by a token endpoint, the one we expect? If the request was
+/// accompanied by a proof signed with `dpop_key`, the server must have bound the token to that key:
+/// silently accepting a bearer token would defeat the point of DPoP.
+pub fn token_type_ok(token_type: Option<&str>, dpop_key: Option<&DpopKey>) -> bool {
+    let expected = match dpop_key {
+        Some(_) => DPOP_TOKEN_TYPE,
+        None => "Bearer",
+    };
+    // Token types are case insensitive (RFC 6749 section 5.1).
+    token_type.is_some_and(|x| x.eq_ignore_ascii_case(expected))
+}
+
+impl Debug for DpopKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        // Don't accidentally log the private key.
+        write!(f, "DpopKey")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    #[test]
+    fn proof() {
+        let key = DpopKey::generate().unwrap();
+        let proof = key
+            .proof(
+                "GET",
+                "https://a.com/b?c=d#e",
+                Some("n"),
+                Some("test_access_token"),
+            )
+            .unwrap();
+        let parts = proof.split('.').collect::<Vec<_>>();
+        let decode =
+            |x: &str| serde_json::from_slice::<Value>(&URL_SAFE_NO_PAD.decode(x).unwrap()).unwrap();
+        let header = decode(parts[0]);
+        assert_eq!(header["typ"], "dpop+jwt");
+        assert_eq!(header["alg"], "ES256");
+        let claims = decode(parts[1]);
+        assert_eq!(claims["htm"], "GET");
+        assert_eq!(claims["htu"], "https://a.com/b");
+        assert_eq!(claims["nonce"], "n");
+        assert_eq!(
+            claims["ath"],
+            URL_SAFE_NO_PAD.encode(Sha256::digest(b"test_access_token"))
+        );
+
+        // The proof must be verifiable with the public key in its header.
+        let mut pub_key = vec![4];
+        pub_key.extend(
+            URL_SAFE_NO_PAD
+                .decode(header["jwk"]["x"].as_str().unwrap())
+                .unwrap(),
+        );
+        pub_key.extend(
+            URL_SAFE_NO_PAD
+                .decode(header["jwk"]["y"].as_str().unwrap())
+                .unwrap(),
+        );
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, pub_key)
+            .verify(
+                format!("{}.{}", parts[0], parts[1]).as_bytes(),
+                &URL_SAFE_NO_PAD.decode(parts[2]).unwrap(),
+            )
+            .unwrap();
+
+        // Keys survive a dump and restore.
+        let restored = DpopKey::from_pkcs8(key.pkcs8().to_owned());
+        assert_eq!(restored, key);
+        assert!(restored.proof("POST", "https://a.com/", None, None).is_ok());
+    }
+}
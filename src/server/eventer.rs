@@ -2,12 +2,17 @@ use std::{
     collections::VecDeque,
     error::Error,
     fmt::{self, Display, Formatter},
-    sync::{Arc, Condvar, Mutex},
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Condvar, Mutex,
+    },
     thread,
     time::Duration,
 };
 
+use chrono::Local;
 use log::error;
+use pizauth::protocol::{Event, EventKind};
 
 use crate::{server::AuthenticatorState, shell_cmd::shell_cmd};
 
@@ -21,6 +26,17 @@ pub enum TokenEvent {
     Revoked,
 }
 
+impl TokenEvent {
+    const fn kind(&self) -> EventKind {
+        match self {
+            Self::Invalidated => EventKind::TokenInvalidated,
+            Self::New => EventKind::TokenNew,
+            Self::Refresh => EventKind::TokenRefreshed,
+            Self::Revoked => EventKind::TokenRevoked,
+        }
+    }
+}
+
 impl Display for TokenEvent {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
         match self {
@@ -36,6 +52,9 @@ pub struct Eventer {
     pred: Mutex<bool>,
     condvar: Condvar,
     event_queue: Mutex<VecDeque<(String, TokenEvent)>>,
+    /// Clients streaming events (see [`pizauth::protocol::Command::Subscribe`]). Subscribers
+    /// which have gone away are removed when the next event is sent.
+    subscribers: Mutex<Vec<Sender<Event>>>,
 }
 
 impl Eventer {
@@ -44,6 +63,7 @@ impl Eventer {
             pred: Mutex::new(false),
             condvar: Condvar::new(),
             event_queue: Mutex::new(VecDeque::new()),
+            subscribers: Mutex::new(Vec::new()),
         })
     }
 
@@ -56,6 +76,10 @@ impl Eventer {
             *eventer_lk = false;
             drop(eventer_lk);
 
+            if let Err(e) = pstate.store_state() {
+                error!("{e}");
+            }
+
             loop {
                 let Some((act_name, event)) = self.event_queue.lock().unwrap().pop_front() else {
                     break;
@@ -79,7 +103,29 @@ impl Eventer {
         Ok(())
     }
 
+    /// Return a [Receiver] which will be sent every subsequent event.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (tx, rx) = channel();
+        self.subscribers.lock().unwrap().push(tx);
+        rx
+    }
+
+    /// Send an event to subscribers. Unlike [`Eventer::token_event`], this does not run
+    /// `token_event_cmd`.
+    pub fn event(&self, act_name: &str, kind: EventKind) {
+        let ev = Event {
+            account: act_name.to_owned(),
+            event: kind,
+            timestamp: Local::now().to_rfc3339(),
+        };
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|tx| tx.send(ev.clone()).is_ok());
+    }
+
     pub fn token_event(&self, act_name: String, kind: TokenEvent) {
+        self.event(&act_name, kind.kind());
         self.event_queue.lock().unwrap().push_back((act_name, kind));
         let mut event_lk = self.pred.lock().unwrap();
         *event_lk = true;
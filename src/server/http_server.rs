@@ -19,8 +19,10 @@ use rustls::{
 };
 
 use super::{
-    eventer::TokenEvent, expiry_instant, AccountId, AuthenticatorState, Config, TokenState,
-    UREQ_TIMEOUT,
+    client_auth::ClientAuth,
+    dpop::{token_type_ok, DpopKey},
+    eventer::TokenEvent,
+    expiry_instant, AccountId, AuthenticatorState, Config, TokenState, UREQ_TIMEOUT,
 };
 
 /// How often should we try making a request to an OAuth server for possibly-temporary transport
@@ -89,6 +91,22 @@ fn request<T: Read + Write>(
         return Ok(());
     }
 
+    // Check that the response came from the server we sent the user to (RFC 9207). This applies
+    // to error responses too, since they could otherwise be used to make us abandon a request.
+    let iss = uri
+        .query_pairs()
+        .find(|(k, _)| k == "iss")
+        .map(|(_, x)| x.into_owned());
+    if let Err(e) = act.check_response_issuer(iss.as_deref()) {
+        let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+        let act_name = ct_lk.account(act_id).name.clone();
+        let msg = format!("Authentication for {act_name} failed: {e}");
+        drop(ct_lk);
+        http_400(stream);
+        pstate.notifier.notify_error(&pstate, act_name, msg)?;
+        return Ok(());
+    }
+
     // Did authentication fail?
     if let Some((_, reason)) = uri.query_pairs().find(|(k, _)| k == "error") {
         let act_id = ct_lk.tokenstate_replace(act_id, TokenState::Empty);
@@ -120,22 +138,18 @@ fn request<T: Read + Write>(
         TokenState::Pending { code_verifier, .. } => code_verifier.clone(),
         _ => unreachable!(),
     };
-    let token_uri = act.token_uri.clone();
-    let client_id = act.client_id.clone();
+    let token_uri = act.token_uri().to_owned();
+    let client_auth = ClientAuth::new(act);
+    let dpop_key = act.dpop.then(DpopKey::generate).transpose()?;
     let redirect_uri = act
         .redirect_uri(pstate.http_port, pstate.https_port)?
         .to_string();
-    let mut pairs = vec![
+    let pairs = [
         ("code", code.as_str()),
-        ("client_id", client_id.as_str()),
         ("code_verifier", code_verifier.as_str()),
         ("redirect_uri", redirect_uri.as_str()),
         ("grant_type", "authorization_code"),
     ];
-    let client_secret = act.client_secret.clone();
-    if let Some(ref x) = client_secret {
-        pairs.push(("client_secret", x));
-    }
 
     // At this point we know we've got a sensible looking query, so we complete the HTTP request,
     // because we don't know how long we'll spend going through the rest of the OAuth process, and
@@ -154,10 +168,12 @@ fn request<T: Read + Write>(
         .timeout_global(Some(UREQ_TIMEOUT))
         .build();
     for _ in 0..RETRY_POST {
-        match ureq::Agent::new_with_config(agent_conf.clone())
-            .post(token_uri.as_str())
-            .send_form(pairs.clone())
-        {
+        match client_auth.post_form(
+            &ureq::Agent::new_with_config(agent_conf.clone()),
+            &token_uri,
+            &pairs,
+            dpop_key.as_ref(),
+        ) {
             Ok(response) => {
                 if let Ok(s) = response.into_body().read_to_string() {
                     body = Some(s);
@@ -169,6 +185,12 @@ fn request<T: Read + Write>(
                 fail(pstate, act_id, &reason)?;
                 return Ok(());
             }
+            // We couldn't create the request (e.g. the TLS client certificate couldn't be loaded):
+            // retrying won't help.
+            Err(ureq::Error::Other(e)) => {
+                fail(pstate, act_id, &e.to_string())?;
+                return Ok(());
+            }
             Err(_) => (), // Temporary network error or the like
         }
         thread::sleep(Duration::from_secs(RETRY_DELAY));
@@ -206,7 +228,9 @@ fn request<T: Read + Write>(
         parsed["access_token"].as_str(),
         parsed["refresh_token"].as_str(),
     ) {
-        (Some("Bearer"), Some(expires_in), Some(access_token), refresh_token) => {
+        (token_type, Some(expires_in), Some(access_token), refresh_token)
+            if token_type_ok(token_type, dpop_key.as_ref()) =>
+        {
             let now = Instant::now();
             let expiry = expiry_instant(&ct_lk, act_id, now, expires_in)?;
             let act_name = ct_lk.account(act_id).name.clone();
@@ -220,6 +244,7 @@ fn request<T: Read + Write>(
                     consecutive_refresh_fails: 0,
                     last_refresh_attempt: None,
                     refresh_token: refresh_token.map(|x| x.to_owned()),
+                    dpop_key,
                 },
             );
             drop(ct_lk);
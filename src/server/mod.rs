@@ -1,42 +1,96 @@
+mod audit;
+mod client_auth;
+mod client_credentials;
+mod device_code;
+mod discovery;
+mod dpop;
 mod eventer;
 mod http_server;
+mod jwt;
 mod notifier;
+mod par;
+mod peer;
 mod refresher;
 mod request_token;
+mod revoke;
 mod state;
+mod storage;
 
 use std::{
     collections::HashMap,
     env,
     error::Error,
-    io::{Read, Write},
-    os::unix::net::{UnixListener, UnixStream},
+    fs,
+    io::{Cursor, Read, Write},
+    os::{
+        fd::AsRawFd,
+        unix::{
+            fs::PermissionsExt,
+            net::{UnixListener, UnixStream},
+        },
+    },
     path::{Path, PathBuf},
-    process::Command,
-    sync::Arc,
+    process,
+    sync::{
+        mpsc::{sync_channel, Receiver, RecvTimeoutError},
+        Arc, Mutex,
+    },
     thread,
     time::{Duration, SystemTime},
 };
 
+use base64::{engine::general_purpose::STANDARD, Engine};
 use boot_time::Instant;
 use chrono::{DateTime, Local};
 use log::{error, warn};
-use nix::sys::signal::{raise, Signal};
+use nix::{
+    errno::Errno,
+    sys::{
+        signal::{raise, Signal},
+        socket::{recv, MsgFlags},
+    },
+};
+use pizauth::{
+    protocol::{
+        read_msg, write_msg, AccountState, AccountStatus, ClientMsg, Command, DpopProofRequest,
+        ErrorKind, Event, Outcome, ProtocolError, Reply, ServerInfo, ServerMsg, PROTOCOL_VERSION,
+    },
+    sock_path,
+};
 #[cfg(target_os = "openbsd")]
 use pledge::pledge;
 #[cfg(target_os = "openbsd")]
 use unveil::unveil;
 
-use crate::{config::Config, PIZAUTH_CACHE_SOCK_LEAF};
+use crate::config::{Config, GrantType};
+use audit::AuditEntry;
+use client_auth::ClientAuth;
+use client_credentials::request_client_credentials;
+pub use discovery::discover;
 use eventer::{Eventer, TokenEvent};
 use notifier::Notifier;
+use peer::{authorise, Peer};
 use refresher::Refresher;
-use request_token::request_token;
+use request_token::{request_token, PendingAuth};
+use revoke::revoke_tokens;
 #[cfg(feature = "systemd")]
 use sd_notify::{notify, NotifyState};
 use serde_json::json;
-use state::{AccountId, AuthenticatorState, CTGuard, TokenState};
+use state::{AccountId, AuthenticatorState, CTGuard, DeviceCodes, TokenState};
 
+/// How many threads serve control socket clients? Requests which can take an arbitrary amount of
+/// time, and structured protocol connections waiting for their next request, are handed off to
+/// their own threads, so this only bounds the number of clients whose requests are being actively
+/// processed.
+const SOCKET_WORKERS: usize = 16;
+/// How long does a worker wait for the next request on a structured protocol connection before
+/// handing the connection off to its own thread?
+const SOCKET_HANDOFF: Duration = Duration::from_millis(250);
+/// How long can a control socket client take to send a request, or to read our reply, before we
+/// give up on it? This also bounds how long a structured protocol connection can be idle.
+const SOCKET_TIMEOUT: Duration = Duration::from_secs(10);
+/// How often should a connection streaming events check whether its client has gone away?
+const SUBSCRIBER_POLL: Duration = Duration::from_secs(5);
 /// Length of the PKCE code verifier in bytes.
 const CODE_VERIFIER_LEN: usize = 64;
 /// The timeout for ureq HTTP requests. It is recommended to make this value lower than
@@ -62,12 +116,6 @@ const STATE_LEN: usize = 32;
 /// even less likely, we choose a prime number.
 const MAX_WAIT_SECS: u64 = 37;
 
-pub fn sock_path(cache_path: &Path) -> PathBuf {
-    let mut p = cache_path.to_owned();
-    p.push(PIZAUTH_CACHE_SOCK_LEAF);
-    p
-}
-
 /// Calculate the [Instant] that a token will expire at. Returns `Err` if [Instant] cannot
 /// represent the expiry.
 pub fn expiry_instant(
@@ -84,249 +132,921 @@ pub fn expiry_instant(
         .ok_or_else(|| "Can't represent expiry".into())
 }
 
+/// Handle a connection on the control socket. Depending on its first byte, the connection uses
+/// either the structured protocol or the text protocol (see [`pizauth::protocol`]). This is called
+/// on a worker thread: requests which can take an arbitrary amount of time are handed off to a
+/// dedicated thread so that they don't stop the worker serving other clients.
 fn request(pstate: Arc<AuthenticatorState>, mut stream: UnixStream) -> Result<(), Box<dyn Error>> {
-    let mut buf = Vec::new();
+    // A client which stops sending, or stops reading our replies, can only tie up a worker for a
+    // bounded amount of time.
+    stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+    stream.set_write_timeout(Some(SOCKET_TIMEOUT))?;
+    let peer = Peer::new(&stream)?;
+    let mut first = [0u8; 1];
+    if stream.read(&mut first)? == 0 {
+        return Ok(());
+    }
+    if first[0] != 0 {
+        return text_request(pstate, peer, first[0], stream);
+    }
+    structured_request(pstate, peer, first[0], stream)
+}
+
+/// Handle a connection using the structured protocol, whose first byte `first` has already been
+/// read from `stream`.
+fn structured_request(
+    pstate: Arc<AuthenticatorState>,
+    peer: Peer,
+    first: u8,
+    stream: UnixStream,
+) -> Result<(), Box<dyn Error>> {
+    match read_msg::<ClientMsg>(Cursor::new([first]).chain(&stream)) {
+        Ok(Some(ClientMsg::Hello { versions })) if versions.contains(&PROTOCOL_VERSION) => {
+            write_msg(
+                &stream,
+                &ServerMsg::Hello {
+                    version: PROTOCOL_VERSION,
+                },
+            )?;
+        }
+        Ok(Some(ClientMsg::Hello { versions })) => {
+            return protocol_error(
+                &stream,
+                ErrorKind::UnsupportedVersion,
+                format!("Protocol versions {versions:?} not supported: only version {PROTOCOL_VERSION} is supported"),
+            );
+        }
+        Ok(Some(ClientMsg::Request { .. })) => {
+            return protocol_error(
+                &stream,
+                ErrorKind::InvalidRequest,
+                "Expected 'hello'".into(),
+            );
+        }
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            return protocol_error(
+                &stream,
+                ErrorKind::InvalidRequest,
+                format!("Invalid message: {e:}"),
+            )
+        }
+    }
+    serve_requests(pstate, stream, peer, true)
+}
+
+/// Report an error not associated with a specific request to the client, returning `Err`.
+fn protocol_error(w: &UnixStream, kind: ErrorKind, msg: String) -> Result<(), Box<dyn Error>> {
+    write_msg(w, &ServerMsg::Error(ProtocolError::new(kind, msg.as_str())))?;
+    Err(msg.into())
+}
+
+/// Serve structured protocol requests on `stream` until the client closes the connection. If
+/// `pooled` is `true`, this is running on a worker thread.
+fn serve_requests(
+    pstate: Arc<AuthenticatorState>,
+    stream: UnixStream,
+    peer: Peer,
+    pooled: bool,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        if pooled && !request_ready(&stream)? {
+            // A persistent connection may be idle for a long time: move it to its own thread so
+            // that it doesn't stop the worker serving other clients.
+            thread::spawn(move || {
+                if let Err(e) = serve_requests(pstate, stream, peer, false) {
+                    warn!("{e:}");
+                }
+            });
+            return Ok(());
+        }
+        let (id, command) = match read_msg::<ClientMsg>(&stream) {
+            Ok(Some(ClientMsg::Request { id, command })) => (id, command),
+            Ok(Some(ClientMsg::Hello { .. })) => {
+                return protocol_error(
+                    &stream,
+                    ErrorKind::InvalidRequest,
+                    "Unexpected 'hello'".into(),
+                );
+            }
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                return protocol_error(
+                    &stream,
+                    ErrorKind::InvalidRequest,
+                    format!("Invalid message: {e:}"),
+                )
+            }
+        };
+        if pooled && is_long_running(&command) {
+            // Move the rest of this connection to its own thread.
+            thread::spawn(move || {
+                let r = match respond(&pstate, &peer, &stream, id, command) {
+                    Ok(true) => serve_requests(pstate, stream, peer, false),
+                    Ok(false) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = r {
+                    warn!("{e:}");
+                }
+            });
+            return Ok(());
+        }
+        if !respond(&pstate, &peer, &stream, id, command)? {
+            return Ok(());
+        }
+    }
+}
+
+/// Wait up to [`SOCKET_HANDOFF`] for the client to send something (or close the connection) on
+/// `stream`, returning `Ok(true)` if it did.
+fn request_ready(stream: &UnixStream) -> Result<bool, Box<dyn Error>> {
+    stream.set_read_timeout(Some(SOCKET_HANDOFF))?;
+    let r = recv(stream.as_raw_fd(), &mut [0u8; 1], MsgFlags::MSG_PEEK);
+    stream.set_read_timeout(Some(SOCKET_TIMEOUT))?;
+    match r {
+        Ok(_) => Ok(true),
+        Err(Errno::EAGAIN) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Can `command` take an arbitrary amount of time to execute?
+fn is_long_running(command: &Command) -> bool {
+    matches!(
+        command,
+        Command::Subscribe
+            | Command::ShowToken { wait: Some(_), .. }
+            | Command::ShowToken {
+                min_valid: Some(_),
+                ..
+            }
+    )
+}
+
+/// Execute `command` and send the response, with ID `id`, to `w`. Returns `Ok(false)` if no
+/// further requests should be read from the connection.
+fn respond(
+    pstate: &Arc<AuthenticatorState>,
+    peer: &Peer,
+    w: &UnixStream,
+    id: u64,
+    command: Command,
+) -> Result<bool, Box<dyn Error>> {
+    match command {
+        Command::Subscribe => {
+            if let Err(e) = permit(pstate, peer, &command) {
+                write_msg(
+                    w,
+                    &ServerMsg::Response {
+                        id,
+                        outcome: e.into(),
+                    },
+                )?;
+                return Ok(true);
+            }
+            let rx = pstate.eventer.subscribe();
+            write_msg(
+                w,
+                &ServerMsg::Response {
+                    id,
+                    outcome: Outcome::Ok(Reply::Subscribed),
+                },
+            )?;
+            stream_events(&rx, w)?;
+            Ok(false)
+        }
+        Command::Shutdown => {
+            let outcome = exec(pstate, peer, Command::Shutdown);
+            let shutdown = matches!(outcome, Outcome::Ok(_));
+            write_msg(w, &ServerMsg::Response { id, outcome })?;
+            if shutdown {
+                raise(Signal::SIGTERM).ok();
+            }
+            Ok(!shutdown)
+        }
+        command => {
+            let outcome = exec(pstate, peer, command);
+            write_msg(w, &ServerMsg::Response { id, outcome })?;
+            Ok(true)
+        }
+    }
+}
+
+/// Send each event received on `rx` to `w` until the client closes the connection.
+fn stream_events(rx: &Receiver<Event>, w: &UnixStream) -> Result<(), Box<dyn Error>> {
+    loop {
+        match rx.recv_timeout(SUBSCRIBER_POLL) {
+            Ok(ev) => write_msg(w, &ServerMsg::Event(ev))?,
+            Err(RecvTimeoutError::Timeout) => {
+                // We only find out that a client has gone away when we try to write to it, which
+                // might be a long time in the future if events are rare, so periodically check if
+                // the client has closed the connection.
+                let mut buf = [0u8; 1];
+                match recv(
+                    w.as_raw_fd(),
+                    &mut buf,
+                    MsgFlags::MSG_PEEK | MsgFlags::MSG_DONTWAIT,
+                ) {
+                    Ok(0) => return Ok(()),
+                    Ok(_) | Err(Errno::EAGAIN) => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Handle a connection using the deprecated text protocol, whose first byte `first` has already
+/// been read from `stream`.
+fn text_request(
+    pstate: Arc<AuthenticatorState>,
+    peer: Peer,
+    first: u8,
+    mut stream: UnixStream,
+) -> Result<(), Box<dyn Error>> {
+    let mut buf = vec![first];
     stream.read_to_end(&mut buf)?;
-    let (cmd, rest) = {
-        let len = buf
-            .iter()
-            .map(|b| *b as char)
-            .take_while(|c| *c != ':')
-            .count();
-        if len == buf.len() {
+    let (cmd, status_json) = match parse_text_request(&buf) {
+        Ok(x) => x,
+        Err(e) => {
+            stream.write_all(format!("error:{e:}").as_bytes())?;
+            return Err(e.into());
+        }
+    };
+
+    match cmd {
+        Command::Shutdown => match exec(&pstate, &peer, Command::Shutdown) {
+            Outcome::Ok(_) => {
+                raise(Signal::SIGTERM).ok();
+            }
+            outcome @ Outcome::Error(_) => {
+                stream.write_all(&text_response(outcome, status_json))?;
+            }
+        },
+        cmd if is_long_running(&cmd) => {
+            thread::spawn(move || {
+                let response = text_response(exec(&pstate, &peer, cmd), status_json);
+                if let Err(e) = stream.write_all(&response) {
+                    warn!("{e:}");
+                }
+            });
+        }
+        cmd => stream.write_all(&text_response(exec(&pstate, &peer, cmd), status_json))?,
+    }
+    Ok(())
+}
+
+/// Parse a text protocol request into a [Command]. The `bool` is `true` if the request was
+/// `status:json`.
+fn parse_text_request(buf: &[u8]) -> Result<(Command, bool), String> {
+    let (cmd, rest) = match buf.iter().position(|b| *b == b':') {
+        Some(i) => (&buf[..i], &buf[i + 1..]),
+        None => {
             return Err(format!(
                 "Syntactically invalid request '{}'",
-                std::str::from_utf8(&buf).unwrap_or("<can't represent as UTF-8>")
-            )
-            .into());
+                std::str::from_utf8(buf).unwrap_or("<can't represent as UTF-8>")
+            ));
+        }
+    };
+    let cmd = std::str::from_utf8(cmd).map_err(|e| e.to_string())?;
+    if cmd == "restore" {
+        return Ok((
+            Command::Restore {
+                dump: STANDARD.encode(rest),
+            },
+            false,
+        ));
+    }
+    let rest = std::str::from_utf8(rest).map_err(|e| e.to_string())?;
+    let parsed = match cmd {
+        "dump" if rest.is_empty() => Command::Dump,
+        "info" if rest.is_empty() => Command::Info,
+        "reload" if rest.is_empty() => Command::Reload,
+        "refresh" => match rest.split_once(' ') {
+            Some((with_url, act_name)) => Command::Refresh {
+                account: act_name.to_owned(),
+                with_url: with_url == "withurl",
+            },
+            None => return Err(format!("Invalid request '{cmd}:{rest}'")),
+        },
+        "revoke" => Command::Revoke {
+            account: rest.to_owned(),
+        },
+        "showtoken" => {
+            let Some((with_url, rest)) = rest.split_once(' ') else {
+                return Err(format!("Invalid request '{cmd}:{rest}'"));
+            };
+            // Requests are either `<withurl> <opts> <account>` or, from older clients,
+            // `<withurl> <account>`. Since account names can contain spaces, the middle field is
+            // only treated as options if it is syntactically valid as such.
+            let ((wait, min_valid), act_name) = match rest
+                .split_once(' ')
+                .and_then(|(opts, act_name)| Some((parse_showtoken_opts(opts)?, act_name)))
+            {
+                Some(x) => x,
+                None => ((None, None), rest),
+            };
+            Command::ShowToken {
+                account: act_name.to_owned(),
+                with_url: with_url == "withurl",
+                wait,
+                min_valid,
+                dpop_proof: None,
+            }
         }
-        (std::str::from_utf8(&buf[..len])?, &buf[len + 1..])
+        "shutdown" if rest.is_empty() => Command::Shutdown,
+        "status" if rest.is_empty() => Command::Status,
+        "status" if rest == "json" => return Ok((Command::Status, true)),
+        x => return Err(format!("Unknown command '{x}'")),
     };
+    Ok((parsed, false))
+}
 
-    match cmd {
-        "dump" if rest.is_empty() => {
-            stream.write_all(&pstate.dump()?)?;
-            return Ok(());
+/// Parse the options of a text protocol `showtoken` request, which are either `-` or a comma
+/// separated list of options, returning `(wait, min_valid)`. Returns `None` if `opts` is not a
+/// valid list of options.
+fn parse_showtoken_opts(opts: &str) -> Option<(Option<u64>, Option<u64>)> {
+    let mut wait = None;
+    let mut min_valid = None;
+    if opts != "-" {
+        for opt in opts.split(',') {
+            let secs = |x: &str| x.parse::<u64>().ok();
+            match opt.split_once('=') {
+                None if opt == "wait" => wait = Some(u64::MAX),
+                Some(("wait", x)) => wait = Some(secs(x)?),
+                Some(("minvalid", x)) => min_valid = Some(secs(x)?),
+                _ => return None,
+            }
         }
-        "info" if rest.is_empty() => {
+    }
+    Some((wait, min_valid))
+}
+
+/// Encode `outcome` as a text protocol response.
+fn text_response(outcome: Outcome, status_json: bool) -> Vec<u8> {
+    let reply = match outcome {
+        Outcome::Ok(x) => x,
+        Outcome::Error(e) => return format!("error:{}", e.message).into_bytes(),
+    };
+    match reply {
+        Reply::Ok => b"ok:".to_vec(),
+        // Existing clients treat everything after `access_token:` as the token, so the account's
+        // `username` is only available via the structured protocol.
+        Reply::AccessToken { access_token, .. } => {
+            format!("access_token:{access_token:}").into_bytes()
+        }
+        Reply::Dump { dump } => STANDARD.decode(dump).unwrap(),
+        Reply::Info(info) => {
+            let port = |x: Option<u16>| x.map_or_else(|| "none".to_owned(), |x| x.to_string());
             let mut m = HashMap::new();
-            m.insert(
-                "http_port",
-                match pstate.http_port {
-                    Some(x) => x.to_string(),
-                    None => "none".to_string(),
-                },
-            );
-            m.insert(
-                "https_port",
-                match pstate.https_port {
-                    Some(x) => x.to_string(),
-                    None => "none".to_string(),
-                },
-            );
-            if let Some(x) = &pstate.https_pub_key {
-                m.insert("https_pub_key", x.clone());
+            m.insert("http_port", port(info.http_port));
+            m.insert("https_port", port(info.https_port));
+            if let Some(x) = info.https_pub_key {
+                m.insert("https_pub_key", x);
             }
-            stream.write_all(json!(m).to_string().as_bytes())?;
-            return Ok(());
+            json!(m).to_string().into_bytes()
+        }
+        // URLs cannot contain spaces, so the user code, if present, is separated from the URL by
+        // a single space.
+        Reply::Pending {
+            url: Some(url),
+            user_code: Some(user_code),
+        } => format!("pending:{url} {user_code}").into_bytes(),
+        Reply::Pending {
+            url: Some(url),
+            user_code: None,
+        } => format!("pending:{url}").into_bytes(),
+        Reply::Pending { url: None, .. } => b"pending:".to_vec(),
+        Reply::Scheduled => b"scheduled:".to_vec(),
+        // The text protocol has no way of subscribing to events.
+        Reply::Subscribed => unreachable!(),
+        Reply::Status { accounts } if status_json => format!("ok:{}", json!(accounts)).into_bytes(),
+        Reply::Status { accounts } if accounts.is_empty() => {
+            b"error:No accounts configured".to_vec()
+        }
+        Reply::Status { accounts } => format!(
+            "ok:{}",
+            accounts
+                .iter()
+                .map(|x| format!("{}: {}", x.account, x.describe()))
+                .collect::<Vec<_>>()
+                .join("\n")
+        )
+        .into_bytes(),
+    }
+}
+
+/// Check that `peer` may execute `cmd`, logging the request if not.
+fn permit(
+    pstate: &Arc<AuthenticatorState>,
+    peer: &Peer,
+    cmd: &Command,
+) -> Result<(), ProtocolError> {
+    authorise(&pstate.ct_lock(), peer, cmd).map_err(|e| {
+        warn!("Denied '{}' request from {peer}: {e}", cmd.name());
+        e
+    })
+}
+
+/// Execute `cmd` on behalf of `peer`, returning the [Outcome] to report to the client. If
+/// `audit_log` is set, audited commands are only executed if the audit log can be opened, and are
+/// recorded there, with their outcome, before the client sees that outcome. If the entry can't be
+/// written once the command has been executed, the failure is logged, but the client is still
+/// sent the real outcome, since the command can't be undone.
+fn exec(pstate: &Arc<AuthenticatorState>, peer: &Peer, cmd: Command) -> Outcome {
+    let audit_path = pstate.ct_lock().config().audit_log.clone();
+    let audit_log = match (AuditEntry::new(&cmd), audit_path) {
+        (Some(entry), Some(path)) => match audit::open(&path) {
+            Ok(log) => Some((entry, log, path)),
+            Err(e) => {
+                let msg = format!("Can't open audit log {path:?}: {e}");
+                error!("{msg}");
+                return ProtocolError::new(ErrorKind::Internal, msg).into();
+            }
+        },
+        _ => None,
+    };
+    let outcome = match permit(pstate, peer, &cmd) {
+        Ok(()) => run(pstate, cmd),
+        Err(e) => e.into(),
+    };
+    if let Some((entry, mut log, path)) = audit_log {
+        if let Err(e) = entry.write(&mut log, peer, &outcome) {
+            error!("Can't write to audit log {path:?}: {e}");
         }
-        "reload" if rest.is_empty() => {
-            match Config::from_path(&pstate.conf_path) {
+    }
+    outcome
+}
+
+/// Execute `cmd`, which the client has been permitted to execute.
+fn run(pstate: &Arc<AuthenticatorState>, cmd: Command) -> Outcome {
+    match cmd {
+        Command::Dump => match pstate.dump() {
+            Ok(d) => Outcome::Ok(Reply::Dump {
+                dump: STANDARD.encode(d),
+            }),
+            Err(e) => ProtocolError::new(ErrorKind::Internal, e.to_string()).into(),
+        },
+        Command::Info => Outcome::Ok(Reply::Info(ServerInfo {
+            http_port: pstate.http_port,
+            https_port: pstate.https_port,
+            https_pub_key: pstate.https_pub_key.clone(),
+        })),
+        Command::Reload => {
+            let r = Config::from_path(&pstate.conf_path).and_then(|mut new_conf| {
+                let old_accounts = pstate.ct_lock().config().accounts.clone();
+                discover(&mut new_conf, &old_accounts).map_err(|e| e.to_string())?;
+                Ok(new_conf)
+            });
+            match r {
                 Ok(new_conf) => {
                     pstate.update_conf(new_conf);
-                    stream.write_all(b"ok:")?;
+                    Outcome::Ok(Reply::Ok)
                 }
-                Err(e) => stream.write_all(format!("error:{e:}").as_bytes())?,
+                Err(e) => ProtocolError::new(ErrorKind::ReloadFailed, e).into(),
+            }
+        }
+        Command::Refresh { account, with_url } => refresh(pstate, &account, with_url),
+        Command::Restore { dump } => {
+            let r = STANDARD
+                .decode(dump)
+                .map_err(|e| ProtocolError::new(ErrorKind::InvalidRequest, e.to_string()))
+                .and_then(|d| {
+                    pstate
+                        .restore(d)
+                        .map_err(|e| ProtocolError::new(ErrorKind::RestoreFailed, e.to_string()))
+                });
+            match r {
+                Ok(()) => Outcome::Ok(Reply::Ok),
+                Err(e) => e.into(),
             }
-            return Ok(());
         }
-        "refresh" => {
-            let rest = std::str::from_utf8(rest)?;
-            if let [with_url, act_name] = &rest.splitn(2, ' ').collect::<Vec<_>>()[..] {
+        Command::Revoke { account } => revoke(pstate, &account),
+        Command::ShowToken {
+            account,
+            with_url,
+            wait,
+            min_valid,
+            dpop_proof,
+        } => {
+            if dpop_proof.is_some() {
                 let ct_lk = pstate.ct_lock();
-                let act_id = match ct_lk.validate_act_name(act_name) {
-                    Some(x) => x,
-                    None => {
-                        drop(ct_lk);
-                        stream.write_all(format!("error:No account '{act_name:}'").as_bytes())?;
-                        return Ok(());
-                    }
-                };
-                match ct_lk.tokenstate(act_id) {
-                    TokenState::Empty | TokenState::Pending { .. } => {
-                        let url = request_token(Arc::clone(&pstate), ct_lk, act_id)?;
-                        if *with_url == "withurl" {
-                            stream.write_all(format!("pending:{url:}").as_bytes())?;
-                        } else {
-                            stream.write_all(b"pending:")?;
-                        }
-                    }
-                    TokenState::Active { .. } => {
-                        drop(ct_lk);
-                        pstate.refresher.sched_refresh(Arc::clone(&pstate), act_id);
-                        stream.write_all(b"scheduled:")?;
+                match ct_lk.validate_act_name(&account) {
+                    Some(act_id) if !ct_lk.account(act_id).dpop => {
+                        return ProtocolError::new(
+                            ErrorKind::InvalidRequest,
+                            format!("Account '{account}' does not use DPoP"),
+                        )
+                        .into();
                     }
+                    Some(_) => (),
+                    None => return no_account(&account),
                 }
-                return Ok(());
             }
-        }
-        "restore" => {
-            match pstate.restore(rest.to_vec()) {
-                Ok(()) => stream.write_all(b"ok:")?,
-                Err(e) => stream.write_all(format!("error:{e:}").as_bytes())?,
+            let outcome = show_token(
+                pstate,
+                with_url,
+                wait.map(Duration::from_secs),
+                min_valid.map(Duration::from_secs),
+                &account,
+            );
+            match dpop_proof {
+                Some(req) => with_dpop_proof(pstate, &account, outcome, &req),
+                None => outcome,
             }
-            return Ok(());
         }
-        "revoke" => {
-            let act_name = std::str::from_utf8(rest)?;
-            let mut ct_lk = pstate.ct_lock();
-            match ct_lk.validate_act_name(act_name) {
-                Some(act_id) => {
-                    ct_lk.tokenstate_replace(act_id, TokenState::Empty);
-                    drop(ct_lk);
+        // The caller is responsible for shutting down once it has responded to the client.
+        Command::Shutdown => Outcome::Ok(Reply::Ok),
+        Command::Status => Outcome::Ok(Reply::Status {
+            accounts: account_statuses(&pstate.ct_lock()),
+        }),
+        // Subscriptions take over the connection, so the caller must handle them itself.
+        Command::Subscribe => ProtocolError::new(
+            ErrorKind::InvalidRequest,
+            "'subscribe' is not supported on this connection",
+        )
+        .into(),
+    }
+}
 
-                    pstate
-                        .eventer
-                        .token_event(act_name.to_owned(), TokenEvent::Revoked);
-                    stream.write_all(b"ok:")?;
-                    return Ok(());
-                }
-                None => {
-                    drop(ct_lk);
-                    stream.write_all(format!("error:No account '{act_name:}'").as_bytes())?;
-                    return Ok(());
-                }
-            };
+/// If `outcome` contains `act_name`'s access token, add to it a DPoP proof, bound to that token, for
+/// the request `req`.
+fn with_dpop_proof(
+    pstate: &AuthenticatorState,
+    act_name: &str,
+    outcome: Outcome,
+    req: &DpopProofRequest,
+) -> Outcome {
+    let (access_token, username) = match outcome {
+        Outcome::Ok(Reply::AccessToken {
+            access_token,
+            username,
+            ..
+        }) => (access_token, username),
+        x => return x,
+    };
+    let ct_lk = pstate.ct_lock();
+    let Some(act_id) = ct_lk.validate_act_name(act_name) else {
+        return no_account(act_name);
+    };
+    match ct_lk.tokenstate(act_id) {
+        // The token may have been refreshed, or the account changed, since `outcome` was created,
+        // in which case we can't be sure which key the token is bound to.
+        TokenState::Active {
+            access_token: x,
+            dpop_key: Some(dpop_key),
+            ..
+        } if *x == access_token => {
+            match dpop_key.proof(&req.method, &req.url, None, Some(&access_token)) {
+                Ok(proof) => Outcome::Ok(Reply::AccessToken {
+                    access_token,
+                    username,
+                    dpop_proof: Some(proof),
+                }),
+                Err(e) => ProtocolError::new(
+                    ErrorKind::InvalidRequest,
+                    format!("Can't create DPoP proof: {e}"),
+                )
+                .into(),
+            }
         }
-        "showtoken" => {
-            let rest = std::str::from_utf8(rest)?;
-            if let [with_url, act_name] = &rest.splitn(2, ' ').collect::<Vec<_>>()[..] {
+        _ => ProtocolError::new(
+            ErrorKind::TokenExpired,
+            "Access token changed while creating DPoP proof",
+        )
+        .into(),
+    }
+}
+
+fn no_account(act_name: &str) -> Outcome {
+    ProtocolError::new(ErrorKind::NoAccount, format!("No account '{act_name:}'")).into()
+}
+
+/// Respond to a `refresh` request for `act_name`.
+fn refresh(pstate: &Arc<AuthenticatorState>, act_name: &str, with_url: bool) -> Outcome {
+    let ct_lk = pstate.ct_lock();
+    let act_id = match ct_lk.validate_act_name(act_name) {
+        Some(x) => x,
+        None => return no_account(act_name),
+    };
+    match ct_lk.tokenstate(act_id) {
+        TokenState::Empty if ct_lk.account(act_id).grant_type == GrantType::ClientCredentials => {
+            // There's no user involvement in the client credentials grant, so we can request a
+            // new token in the background.
+            let pstate = Arc::clone(pstate);
+            thread::spawn(move || {
                 let ct_lk = pstate.ct_lock();
-                let act_id = match ct_lk.validate_act_name(act_name) {
-                    Some(x) => x,
-                    None => {
-                        drop(ct_lk);
-                        stream.write_all(format!("error:No account '{act_name:}'").as_bytes())?;
-                        return Ok(());
+                if ct_lk.is_act_id_valid(act_id)
+                    && matches!(ct_lk.tokenstate(act_id), TokenState::Empty)
+                {
+                    let act_name = ct_lk.account(act_id).name.clone();
+                    if let Err(e) = request_client_credentials(Arc::clone(&pstate), ct_lk, act_id) {
+                        pstate
+                            .notifier
+                            .notify_error(&pstate, act_name, e.to_string())
+                            .ok();
                     }
+                }
+            });
+            Outcome::Ok(Reply::Scheduled)
+        }
+        TokenState::Empty | TokenState::Pending { .. } => {
+            match request_token(Arc::clone(pstate), ct_lk, act_id) {
+                Ok(pending) => Outcome::Ok(pending.reply(with_url)),
+                Err(e) => ProtocolError::new(ErrorKind::RequestFailed, e.to_string()).into(),
+            }
+        }
+        TokenState::Active { .. } => {
+            drop(ct_lk);
+            pstate.refresher.sched_refresh(Arc::clone(pstate), act_id);
+            Outcome::Ok(Reply::Scheduled)
+        }
+    }
+}
+
+/// Respond to a `revoke` request for `act_name`.
+fn revoke(pstate: &Arc<AuthenticatorState>, act_name: &str) -> Outcome {
+    let mut ct_lk = pstate.ct_lock();
+    let mut act_id = match ct_lk.validate_act_name(act_name) {
+        Some(x) => x,
+        None => return no_account(act_name),
+    };
+    let act = ct_lk.account(act_id);
+    let mut revoked = Ok(());
+    if let (
+        Some(revocation_uri),
+        TokenState::Active {
+            access_token,
+            refresh_token,
+            ..
+        },
+    ) = (act.revocation_uri(), ct_lk.tokenstate(act_id))
+    {
+        let revocation_uri = revocation_uri.to_owned();
+        let client_auth = ClientAuth::new(act);
+        // Revoking the refresh token will, on many servers, also revoke any access tokens derived
+        // from it, so it's revoked first.
+        let mut tokens = Vec::new();
+        if let Some(x) = refresh_token {
+            tokens.push((x.clone(), "refresh_token"));
+        }
+        tokens.push((access_token.clone(), "access_token"));
+        drop(ct_lk);
+        revoked = revoke_tokens(
+            &revocation_uri,
+            &client_auth,
+            &tokens
+                .iter()
+                .map(|(x, y)| (x.as_str(), *y))
+                .collect::<Vec<_>>(),
+        );
+        ct_lk = pstate.ct_lock();
+        act_id = match ct_lk.validate_act_name(act_name) {
+            Some(x) => x,
+            None => return no_account(act_name),
+        };
+    }
+    // Even if the server failed to revoke the tokens, we forget them: the user has asked for them
+    // not to be used again.
+    ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+    drop(ct_lk);
+
+    pstate
+        .eventer
+        .token_event(act_name.to_owned(), TokenEvent::Revoked);
+    match revoked {
+        Ok(()) => Outcome::Ok(Reply::Ok),
+        Err(e) => ProtocolError::new(
+            ErrorKind::RevokeFailed,
+            format!("Tokens forgotten, but the server failed to revoke them: {e:}"),
+        )
+        .into(),
+    }
+}
+
+/// Return the status of every account, sorted by account name.
+fn account_statuses(ct_lk: &CTGuard) -> Vec<AccountStatus> {
+    let mut acts = Vec::new();
+    for act_id in ct_lk.act_ids() {
+        let mut st = AccountStatus {
+            account: ct_lk.account(act_id).name.clone(),
+            state: AccountState::None,
+            last_notification: None,
+            access_token_obtained: None,
+            access_token_expiry: None,
+            last_refresh_attempt: None,
+            consecutive_refresh_fails: 0,
+            refresh_in_progress: false,
+        };
+        match ct_lk.tokenstate(act_id) {
+            TokenState::Empty => (),
+            TokenState::Pending {
+                last_notification, ..
+            } => {
+                st.state = AccountState::Pending;
+                st.last_notification = last_notification.and_then(instant_rfc3339);
+            }
+            TokenState::Active {
+                access_token_obtained,
+                access_token_expiry,
+                ongoing_refresh,
+                consecutive_refresh_fails,
+                last_refresh_attempt,
+                ..
+            } => {
+                st.state = if *access_token_expiry > Instant::now() {
+                    AccountState::Active
+                } else {
+                    AccountState::Expired
                 };
-                match ct_lk.tokenstate(act_id) {
-                    TokenState::Empty => {
-                        let url = request_token(Arc::clone(&pstate), ct_lk, act_id)?;
-                        if *with_url == "withurl" {
-                            stream.write_all(format!("pending:{url:}").as_bytes())?;
-                        } else {
-                            stream.write_all(b"pending:")?;
+                st.access_token_obtained = instant_rfc3339(*access_token_obtained);
+                st.access_token_expiry = instant_rfc3339(*access_token_expiry);
+                st.last_refresh_attempt = last_refresh_attempt.and_then(instant_rfc3339);
+                st.consecutive_refresh_fails = *consecutive_refresh_fails;
+                st.refresh_in_progress = *ongoing_refresh;
+            }
+        }
+        acts.push(st);
+    }
+    acts.sort_by(|a, b| a.account.cmp(&b.account));
+    acts
+}
+
+/// Respond to a `showtoken` request for `act_name`. If `wait` is `Some`, and an access token might
+/// later become available, wait until it does, or the timeout expires, before responding. If
+/// `min_valid` is `Some`, and the access token expires sooner than that, synchronously refresh
+/// the access token before responding.
+fn show_token(
+    pstate: &Arc<AuthenticatorState>,
+    with_url: bool,
+    wait: Option<Duration>,
+    min_valid: Option<Duration>,
+    act_name: &str,
+) -> Outcome {
+    let start = Instant::now();
+    // Only the first pass initiates requests for, or refreshes of, tokens: after that we wait for
+    // them to complete, so that a failing server can't cause us to make requests in a tight loop.
+    let mut first_pass = true;
+    // Have we refreshed the access token because of `min_valid`?
+    let mut refreshed = false;
+    let mut ct_lk = pstate.ct_lock();
+    loop {
+        let act_id = match ct_lk.validate_act_name(act_name) {
+            Some(x) => x,
+            None => return no_account(act_name),
+        };
+        let username = ct_lk.account(act_id).username.clone();
+        let outcome = match ct_lk.tokenstate(act_id) {
+            TokenState::Empty
+                if ct_lk.account(act_id).grant_type == GrantType::ClientCredentials =>
+            {
+                match request_client_credentials(Arc::clone(pstate), ct_lk, act_id) {
+                    Ok(access_token) => {
+                        return Outcome::Ok(Reply::AccessToken {
+                            access_token,
+                            username,
+                            dpop_proof: None,
+                        })
+                    }
+                    Err(e) => {
+                        // If several clients ask for a token at the same time, each makes a
+                        // request, and all but the first to complete then fail because the
+                        // account's tokenstate has changed. The token the first obtained is as
+                        // good as any other, so we use that.
+                        ct_lk = pstate.ct_lock();
+                        if let Some(act_id) = ct_lk.validate_act_name(act_name) {
+                            if matches!(ct_lk.tokenstate(act_id), TokenState::Active { .. }) {
+                                continue;
+                            }
                         }
+                        return ProtocolError::new(ErrorKind::RequestFailed, e.to_string()).into();
                     }
-                    TokenState::Pending { url, .. } => {
-                        let response = if *with_url == "withurl" {
-                            format!("pending:{url:}")
-                        } else {
-                            "pending:".to_owned()
-                        };
-                        drop(ct_lk);
-                        stream.write_all(response.as_bytes())?;
+                }
+            }
+            TokenState::Empty if first_pass => {
+                match request_token(Arc::clone(pstate), ct_lk, act_id) {
+                    Ok(_) if wait.is_some() => {
+                        first_pass = false;
+                        ct_lk = pstate.ct_lock();
+                        continue;
                     }
-                    TokenState::Active {
-                        access_token,
-                        access_token_expiry,
-                        ongoing_refresh,
-                        ..
-                    } => {
-                        let response = if access_token_expiry > &Instant::now() {
-                            format!("access_token:{access_token:}")
-                        } else if *ongoing_refresh {
-                            "error:Access token has expired. Refreshing is in progress but has not yet succeeded"
-                                .into()
-                        } else {
-                            pstate.refresher.sched_refresh(Arc::clone(&pstate), act_id);
-                            "error:Access token has expired. Refreshing initiated".into()
-                        };
-                        drop(ct_lk);
-                        stream.write_all(response.as_bytes())?;
+                    Ok(pending) => return Outcome::Ok(pending.reply(with_url)),
+                    Err(e) => {
+                        return ProtocolError::new(ErrorKind::RequestFailed, e.to_string()).into()
                     }
                 }
-                return Ok(());
             }
-        }
-        "shutdown" if rest.is_empty() => {
-            raise(Signal::SIGTERM).ok();
-            return Ok(());
-        }
-        "status" if rest.is_empty() => {
-            let ct_lk = pstate.ct_lock();
-            let mut acts = Vec::new();
-            for act_id in ct_lk.act_ids() {
-                let act = ct_lk.account(act_id);
-                let st = match ct_lk.tokenstate(act_id) {
-                    TokenState::Empty => "No access token".into(),
-                    TokenState::Pending {
-                        last_notification: Some(i),
-                        ..
-                    } => format!(
-                        "Access token pending authentication (last notification {})",
-                        instant_fmt(*i)
-                    ),
-                    TokenState::Pending {
-                        last_notification: None,
-                        ..
-                    } => "Access token pending authentication".into(),
-                    TokenState::Active {
-                        access_token_obtained,
-                        access_token_expiry,
-                        last_refresh_attempt,
-                        ..
-                    } => {
-                        if *access_token_expiry > Instant::now() {
-                            format!(
-                                "Active access token (obtained {}; expires {})",
-                                instant_fmt(*access_token_obtained),
-                                instant_fmt(*access_token_expiry)
-                            )
-                        } else if let Some(i) = last_refresh_attempt {
+            TokenState::Empty => {
+                return ProtocolError::new(
+                    ErrorKind::AuthFailed,
+                    "Authorisation failed or was cancelled",
+                )
+                .into();
+            }
+            TokenState::Pending {
+                url, device_codes, ..
+            } => Outcome::Ok(
+                PendingAuth {
+                    url: url.clone(),
+                    user_code: device_codes
+                        .as_ref()
+                        .map(|DeviceCodes { user_code, .. }| user_code.clone()),
+                }
+                .reply(with_url),
+            ),
+            TokenState::Active {
+                access_token,
+                access_token_expiry,
+                ongoing_refresh,
+                ..
+            } => {
+                let now = Instant::now();
+                // If `now + min_valid` can't be represented, no access token can satisfy it.
+                let valid_until = min_valid.map_or(Some(now), |x| now.checked_add(x));
+                if valid_until.is_some_and(|x| *access_token_expiry > x) {
+                    return Outcome::Ok(Reply::AccessToken {
+                        access_token: access_token.clone(),
+                        username,
+                        dpop_proof: None,
+                    });
+                } else if let Some(min_valid) = min_valid {
+                    if *ongoing_refresh {
+                        // Wait for the ongoing refresh to finish, then reconsider.
+                        ct_lk = ct_lk.wait_for_change(Duration::from_secs(MAX_WAIT_SECS));
+                        continue;
+                    }
+                    if refreshed {
+                        return ProtocolError::new(
+                            ErrorKind::TokenExpired,
                             format!(
-                                "Access token expired (last refresh attempt {})",
-                                instant_fmt(*i)
+                                "Access token expires in less than {}s even after refreshing",
+                                min_valid.as_secs()
+                            ),
+                        )
+                        .into();
+                    }
+                    drop(ct_lk);
+                    match pstate.refresher.refresh(pstate, act_id) {
+                        Ok(()) => {
+                            refreshed = true;
+                            ct_lk = pstate.ct_lock();
+                            continue;
+                        }
+                        Err(e) => {
+                            return ProtocolError::new(
+                                ErrorKind::TokenExpired,
+                                format!("Access token expires in less than {}s and refreshing it failed: {e:}", min_valid.as_secs()),
                             )
-                        } else {
-                            "Access token expired (refresh not yet attempted)".into()
+                            .into();
                         }
                     }
-                };
-                acts.push(format!("{}: {st}", act.name));
-            }
-            acts.sort();
-            if acts.is_empty() {
-                stream.write_all(b"error:No accounts configured")?;
-            } else {
-                stream.write_all(format!("ok:{}", acts.join("\n")).as_bytes())?;
+                } else if *ongoing_refresh || !first_pass {
+                    ProtocolError::new(
+                        ErrorKind::TokenExpired,
+                        "Access token has expired. Refreshing is in progress but has not yet succeeded",
+                    )
+                    .into()
+                } else {
+                    pstate.refresher.sched_refresh(Arc::clone(pstate), act_id);
+                    ProtocolError::new(
+                        ErrorKind::TokenExpired,
+                        "Access token has expired. Refreshing initiated",
+                    )
+                    .into()
+                }
             }
-            return Ok(());
+        };
+        first_pass = false;
+
+        // An access token may become available later: if the user asked us to, wait for it.
+        let timeout = wait
+            .and_then(|d| d.checked_sub(Instant::now().checked_duration_since(start)?))
+            .filter(|x| !x.is_zero())
+            .map(|x| x.min(Duration::from_secs(MAX_WAIT_SECS)));
+        match timeout {
+            Some(x) => ct_lk = ct_lk.wait_for_change(x),
+            None => return outcome,
         }
-        x => stream.write_all(format!("error:Unknown command '{x}'").as_bytes())?,
     }
-    Err("Invalid command".into())
 }
 
-/// Attempt to print an [Instant] as a user-readable string. By the very nature of [Instant]s,
-/// there is no guarantee this is possible or that the time presented is accurate.
-fn instant_fmt(i: Instant) -> String {
+/// Attempt to print an [Instant] as an RFC 3339 string. By the very nature of [Instant]s, there is
+/// no guarantee this is possible or that the time presented is accurate.
+fn instant_rfc3339(i: Instant) -> Option<String> {
+    instant_datetime(i).map(|dt| dt.to_rfc3339())
+}
+
+/// Attempt to convert an [Instant] to a wall-clock time.
+fn instant_datetime(i: Instant) -> Option<DateTime<Local>> {
     let now = Instant::now();
-    if i < now {
-        if let Some(d) = now.checked_duration_since(i) {
-            if let Some(st) = SystemTime::now().checked_sub(d) {
-                let dt: DateTime<Local> = st.into();
-                return dt.to_rfc2822();
-            }
-        }
-    } else if let Some(d) = i.checked_duration_since(now) {
-        if let Some(st) = SystemTime::now().checked_add(d) {
-            let dt: DateTime<Local> = st.into();
-            return dt.to_rfc2822();
-        }
-    }
-    "<unknown time>".into()
+    let st = if i < now {
+        SystemTime::now().checked_sub(now.checked_duration_since(i)?)
+    } else {
+        SystemTime::now().checked_add(i.checked_duration_since(now)?)
+    };
+    st.map(|x| x.into())
 }
 
 /// If [`Config::startup_cmd`] is non-`None`, call this function to run that command (in a thread, so
@@ -334,7 +1054,7 @@ fn instant_fmt(i: Instant) -> String {
 fn startup_cmd(cmd: String) {
     thread::spawn(move || {
         match env::var("SHELL") {
-            Ok(s) => match Command::new(s).args(["-c", &cmd]).spawn() {
+            Ok(s) => match process::Command::new(s).args(["-c", &cmd]).spawn() {
                 Ok(mut child) => match child.wait() {
                     Ok(status) => {
                         if !status.success() {
@@ -378,6 +1098,46 @@ pub fn server(conf_path: PathBuf, conf: Config, cache_path: &Path) -> Result<(),
         "rwxc",
     )?;
     #[cfg(target_os = "openbsd")]
+    if let Some(dir) = conf.state_file.as_ref().and_then(|x| x.parent()) {
+        unveil(
+            dir.as_os_str()
+                .to_str()
+                .ok_or("Cannot use state file path in unveil")?,
+            "rwc",
+        )?;
+    }
+    #[cfg(target_os = "openbsd")]
+    if let Some(audit_log) = conf.audit_log.as_ref() {
+        unveil(
+            audit_log
+                .as_os_str()
+                .to_str()
+                .ok_or("Cannot use audit log path in unveil")?,
+            "wc",
+        )?;
+    }
+    // Keys and certificates are read each time they're used. Note that files added to the
+    // configuration after startup can't be read.
+    #[cfg(target_os = "openbsd")]
+    for act in conf.accounts.values() {
+        for p in [
+            &act.client_assertion_key,
+            &act.tls_ca_cert,
+            &act.tls_client_cert,
+            &act.tls_client_key,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            unveil(
+                p.as_os_str()
+                    .to_str()
+                    .ok_or("Cannot use key or certificate path in unveil")?,
+                "r",
+            )?;
+        }
+    }
+    #[cfg(target_os = "openbsd")]
     unveil(std::env::var("SHELL")?, "rx")?;
     #[cfg(target_os = "openbsd")]
     unveil("/dev/random", "rx")?;
@@ -421,6 +1181,10 @@ pub fn server(conf_path: PathBuf, conf: Config, cache_path: &Path) -> Result<(),
         Arc::clone(&refresher),
     ));
 
+    if let Err(e) = pstate.load_state() {
+        warn!("Can't restore stored state: {e}");
+    }
+
     if let Some(x) = http_state {
         http_server::http_server(Arc::clone(&pstate), x)?;
     }
@@ -431,7 +1195,12 @@ pub fn server(conf_path: PathBuf, conf: Config, cache_path: &Path) -> Result<(),
     refresher.refresher(Arc::clone(&pstate))?;
     notifier.notifier(Arc::clone(&pstate))?;
 
-    let listener = UnixListener::bind(sock_path)?;
+    let listener = UnixListener::bind(&sock_path)?;
+    // Which users can reach the socket is determined by the permissions of the directory it is
+    // in, and each request is then checked against the requesting process's credentials (see
+    // `peer::authorise`), so the socket itself needn't restrict who can connect. Without this,
+    // users given access to the directory for `allow_uid` still couldn't connect.
+    fs::set_permissions(&sock_path, PermissionsExt::from_mode(0o666))?;
     match &pstate.ct_lock().config().startup_cmd {
         Some(s) => {
             startup_cmd(s.to_owned());
@@ -442,11 +1211,26 @@ pub fn server(conf_path: PathBuf, conf: Config, cache_path: &Path) -> Result<(),
             let _unused = notify(&[NotifyState::Ready]);
         }
     }
-    for stream in listener.incoming().flatten() {
+    let (tx, rx) = sync_channel::<UnixStream>(SOCKET_WORKERS);
+    let rx = Arc::new(Mutex::new(rx));
+    for _ in 0..SOCKET_WORKERS {
         let pstate = Arc::clone(&pstate);
-        if let Err(e) = request(pstate, stream) {
-            warn!("{e:}");
-        }
+        let rx = Arc::clone(&rx);
+        thread::spawn(move || loop {
+            let stream = rx.lock().unwrap().recv();
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = request(Arc::clone(&pstate), stream) {
+                        warn!("{e:}");
+                    }
+                }
+                Err(_) => return,
+            }
+        });
+    }
+    // If every worker is busy, and the queue is full, this blocks until a worker becomes free.
+    for stream in listener.incoming().flatten() {
+        tx.send(stream)?;
     }
 
     Ok(())
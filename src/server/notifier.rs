@@ -70,6 +70,7 @@ impl Notifier {
                 if let TokenState::Pending {
                     ref mut last_notification,
                     ref url,
+                    ref device_codes,
                     ..
                 } = ts
                 {
@@ -82,24 +83,37 @@ impl Notifier {
                     }
                     *last_notification = Some(now);
                     let url = url.clone();
+                    let user_code = device_codes.as_ref().map(|x| x.user_code.clone());
                     let act = ct_lk.account(act_id);
                     if let Some(ref cmd) = ct_lk.config().auth_notify_cmd {
-                        auth_cmds.push((act.name.clone(), cmd.clone(), url));
+                        auth_cmds.push((act.name.clone(), cmd.clone(), url, user_code));
                     }
                     ct_lk.tokenstate_replace(act_id, ts);
                 }
             }
             drop(ct_lk);
 
-            for (act_name, cmd, url) in auth_cmds {
-                if let Err(e) = shell_cmd(
-                    &cmd,
-                    [
-                        ("PIZAUTH_ACCOUNT", act_name.as_str()),
-                        ("PIZAUTH_URL", url.as_str()),
-                    ],
-                    AUTH_NOTIFY_CMD_TIMEOUT,
-                ) {
+            for (act_name, cmd, url, user_code) in auth_cmds {
+                let r = match user_code {
+                    Some(user_code) => shell_cmd(
+                        &cmd,
+                        [
+                            ("PIZAUTH_ACCOUNT", act_name.as_str()),
+                            ("PIZAUTH_URL", url.as_str()),
+                            ("PIZAUTH_USER_CODE", user_code.as_str()),
+                        ],
+                        AUTH_NOTIFY_CMD_TIMEOUT,
+                    ),
+                    None => shell_cmd(
+                        &cmd,
+                        [
+                            ("PIZAUTH_ACCOUNT", act_name.as_str()),
+                            ("PIZAUTH_URL", url.as_str()),
+                        ],
+                        AUTH_NOTIFY_CMD_TIMEOUT,
+                    ),
+                };
+                if let Err(e) = r {
                     error!("{e}");
                 }
             }
@@ -0,0 +1,41 @@
+//! Pushed authorization requests (RFC 9126). Rather than encoding every authorisation parameter in
+//! the URL given to the user, we POST them to the server, which returns a short-lived reference to
+//! them that the URL contains instead.
+
+use std::error::Error;
+
+use serde_json::Value;
+
+use super::{client_auth::ClientAuth, UREQ_TIMEOUT};
+
+/// Push the authorisation parameters `pairs` (excluding `client_id`) to `par_uri`, returning the
+/// `request_uri` which refers to them.
+pub fn push_authorization_request(
+    par_uri: &str,
+    client_auth: &ClientAuth,
+    pairs: &[(&str, &str)],
+) -> Result<String, Box<dyn Error>> {
+    let agent_conf = ureq::Agent::config_builder()
+        .timeout_global(Some(UREQ_TIMEOUT))
+        .build();
+    let body = match client_auth.post_form(
+        &ureq::Agent::new_with_config(agent_conf),
+        par_uri,
+        pairs,
+        None,
+    ) {
+        Ok(response) => response.into_body().read_to_string()?,
+        Err(ureq::Error::StatusCode(code)) => {
+            return Err(format!("pushed authorization request failed: HTTP code {code}").into());
+        }
+        Err(e) => return Err(format!("couldn't connect to {par_uri}: {e}").into()),
+    };
+    let parsed = serde_json::from_str::<Value>(&body).map_err(|e| format!("Invalid JSON: {e}"))?;
+    if let Some(err_msg) = parsed["error"].as_str() {
+        return Err(format!("pushed authorization request failed: {err_msg}").into());
+    }
+    match parsed["request_uri"].as_str() {
+        Some(x) => Ok(x.to_owned()),
+        None => Err("pushed authorization request failed: invalid response received".into()),
+    }
+}
@@ -0,0 +1,156 @@
+//! Deciding which control socket clients can execute which commands.
+
+use std::{
+    fmt::{self, Display, Formatter},
+    io,
+    os::unix::net::UnixStream,
+    path::PathBuf,
+};
+
+use nix::unistd::geteuid;
+use pizauth::protocol::{Command, ErrorKind, ProtocolError};
+
+use super::CTGuard;
+use crate::{
+    compat::{exe_chain, peer_cred},
+    config::Account,
+};
+
+/// The process at the other end of a control socket connection, as determined when it connected.
+pub struct Peer {
+    /// The process's pid, if this platform can tell us it.
+    pub pid: Option<i32>,
+    /// The process's effective uid.
+    pub uid: u32,
+    /// The executables of the process and its ancestors (see [`exe_chain`]).
+    pub exes: Vec<PathBuf>,
+}
+
+impl Peer {
+    pub fn new(stream: &UnixStream) -> io::Result<Self> {
+        let (pid, uid) = peer_cred(stream)?;
+        Ok(Self {
+            pid,
+            uid,
+            exes: pid.map(exe_chain).unwrap_or_default(),
+        })
+    }
+
+    /// Is the process running as the same user as the server?
+    fn is_owner(&self) -> bool {
+        self.uid == geteuid().as_raw()
+    }
+
+    /// Can the process access `act`'s tokens?
+    fn may_access(&self, act: &Account) -> bool {
+        let uid_ok = match &act.allow_uid {
+            Some(uids) => uids.contains(&self.uid),
+            None => self.is_owner(),
+        };
+        // Programs often run `pizauth show` via a shell, so we accept any of the process's
+        // ancestors: `exe_chain` returns canonical paths, so `allow_exe` entries which are
+        // symlinks are resolved before comparison.
+        let exe_ok = match &act.allow_exe {
+            Some(exes) => exes.iter().any(|x| {
+                let x = x.canonicalize().unwrap_or_else(|_| x.to_owned());
+                self.exes.contains(&x)
+            }),
+            None => true,
+        };
+        uid_ok && exe_ok
+    }
+}
+
+impl Display for Peer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.pid {
+            Some(pid) => write!(f, "pid {pid} ")?,
+            None => write!(f, "unknown pid ")?,
+        }
+        write!(f, "uid {}", self.uid)?;
+        if let Some(exe) = self.exes.first() {
+            write!(f, " ({})", exe.display())?;
+        }
+        Ok(())
+    }
+}
+
+/// Check whether `peer` is allowed to execute `cmd`. Commands which name an account need access
+/// to that account; `dump` and `restore` need access to every account; other commands can only
+/// be executed by processes running as the same user as the server.
+pub fn authorise(ct_lk: &CTGuard, peer: &Peer, cmd: &Command) -> Result<(), ProtocolError> {
+    let denied = |msg: String| Err(ProtocolError::new(ErrorKind::PermissionDenied, msg));
+    match cmd {
+        Command::Refresh { account, .. }
+        | Command::Revoke { account }
+        | Command::ShowToken { account, .. } => match ct_lk.validate_act_name(account) {
+            Some(act_id) if !peer.may_access(ct_lk.account(act_id)) => {
+                denied(format!("Permission denied for account '{account}'"))
+            }
+            // If the account doesn't exist, the command will report that itself.
+            _ => Ok(()),
+        },
+        Command::Dump | Command::Restore { .. } => {
+            match ct_lk
+                .act_ids()
+                .map(|act_id| ct_lk.account(act_id))
+                .find(|act| !peer.may_access(act))
+            {
+                Some(act) => denied(format!("Permission denied for account '{}'", act.name)),
+                None => Ok(()),
+            }
+        }
+        Command::Info
+        | Command::Reload
+        | Command::Shutdown
+        | Command::Status
+        | Command::Subscribe => {
+            if peer.is_owner() {
+                Ok(())
+            } else {
+                denied("Permission denied".into())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn may_access() {
+        let owner = geteuid().as_raw();
+        let other = owner + 1;
+        let act = |rules: &str| {
+            let conf = Config::from_str(&format!(
+                r#"account "x" {{ auth_uri = "http://a.com"; client_id = "b"; token_uri = "http://c.com"; {rules} }}"#
+            ))
+            .unwrap();
+            (*conf.accounts["x"]).clone()
+        };
+        let peer = |uid: u32, exes: &[&str]| Peer {
+            pid: None,
+            uid,
+            exes: exes.iter().map(PathBuf::from).collect(),
+        };
+
+        // By default, only the server's user can access an account.
+        let a = act("");
+        assert!(peer(owner, &[]).may_access(&a));
+        assert!(!peer(other, &[]).may_access(&a));
+
+        // `allow_uid` replaces, rather than adds to, the server's user.
+        let a = act(&format!("allow_uid = [{other}];"));
+        assert!(peer(other, &[]).may_access(&a));
+        assert!(!peer(owner, &[]).may_access(&a));
+        assert!(!peer(other + 1, &[]).may_access(&a));
+
+        // Both `allow_uid` and `allow_exe` must be satisfied.
+        let a = act(&format!(r#"allow_uid = [{other}]; allow_exe = ["/d"];"#));
+        assert!(peer(other, &["/e", "/d"]).may_access(&a));
+        assert!(!peer(other, &["/e"]).may_access(&a));
+        assert!(!peer(other + 1, &["/d"]).may_access(&a));
+    }
+}
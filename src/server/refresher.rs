@@ -11,12 +11,14 @@ use boot_time::Instant;
 #[cfg(debug_assertions)]
 use log::debug;
 use log::{error, info};
+use pizauth::protocol::EventKind;
 use serde_json::Value;
 
 use crate::{
+    config::GrantType,
     server::{
-        eventer::TokenEvent, expiry_instant, AccountId, AuthenticatorState, CTGuard, TokenState,
-        MAX_WAIT_SECS, UREQ_TIMEOUT,
+        client_auth::ClientAuth, dpop::token_type_ok, eventer::TokenEvent, expiry_instant,
+        AccountId, AuthenticatorState, CTGuard, TokenState, MAX_WAIT_SECS, UREQ_TIMEOUT,
     },
     shell_cmd::shell_cmd,
 };
@@ -57,110 +59,117 @@ impl Refresher {
     pub fn sched_refresh(self: &Arc<Self>, pstate: Arc<AuthenticatorState>, act_id: AccountId) {
         let refresher = Arc::clone(self);
         thread::spawn(move || {
-            let mut ct_lk = pstate.ct_lock();
-            if ct_lk.is_act_id_valid(act_id) {
-                let mut new_ts = ct_lk.tokenstate(act_id).clone();
-                if let TokenState::Active {
-                    ref mut ongoing_refresh,
-                    ..
-                } = new_ts
-                {
-                    if !*ongoing_refresh {
-                        *ongoing_refresh = true;
+            refresher.refresh(&pstate, act_id).ok();
+        });
+    }
+
+    /// Refresh the token for `act_id`, blocking until the token is refreshed or an error occurred.
+    /// If the token was not refreshed (including if `act_id` is no longer valid, is not
+    /// [`TokenState::Active`], or is already being refreshed), returns `Err` with a string
+    /// suitable for reporting to the user.
+    pub fn refresh(
+        self: &Arc<Self>,
+        pstate: &Arc<AuthenticatorState>,
+        act_id: AccountId,
+    ) -> Result<(), String> {
+        let mut ct_lk = pstate.ct_lock();
+        if !ct_lk.is_act_id_valid(act_id) {
+            return Err("Account changed while refreshing".into());
+        }
+        let mut new_ts = ct_lk.tokenstate(act_id).clone();
+        match new_ts {
+            TokenState::Active {
+                ref mut ongoing_refresh,
+                ..
+            } if !*ongoing_refresh => *ongoing_refresh = true,
+            TokenState::Active { .. } => return Err("Refreshing is already in progress".into()),
+            _ => return Err("No access token to refresh".into()),
+        }
+        let act_id = ct_lk.tokenstate_replace(act_id, new_ts);
+        let act_name = ct_lk.account(act_id).name.clone();
+        match self.inner_refresh(pstate, ct_lk, act_id) {
+            RefreshKind::AccountOrTokenStateChanged => {
+                Err("Account changed while refreshing".into())
+            }
+            RefreshKind::NoRefreshToken => Err("No refresh token available".into()),
+            RefreshKind::PermanentError(msg) => {
+                info!("Permanent refresh error for {act_name}: {msg}");
+                pstate.eventer.event(&act_name, EventKind::RefreshFailed);
+                pstate
+                    .eventer
+                    .token_event(act_name, TokenEvent::Invalidated);
+                Err(msg)
+            }
+            RefreshKind::Refreshed => {
+                self.notify_changes();
+                pstate.eventer.token_event(act_name, TokenEvent::Refresh);
+                Ok(())
+            }
+            RefreshKind::TransitoryError(act_id, msg) => {
+                pstate.eventer.event(&act_name, EventKind::RefreshFailed);
+                ct_lk = pstate.ct_lock();
+                if ct_lk.is_act_id_valid(act_id) {
+                    let mut new_ts = ct_lk.tokenstate(act_id).clone();
+                    if let TokenState::Active {
+                        ref mut last_refresh_attempt,
+                        ref mut consecutive_refresh_fails,
+                        ..
+                    } = new_ts
+                    {
+                        *last_refresh_attempt = Some(Instant::now());
+                        *consecutive_refresh_fails += 1;
+                        let consecutive_refresh_fails = *consecutive_refresh_fails;
                         let act_id = ct_lk.tokenstate_replace(act_id, new_ts);
-                        let act_name = ct_lk.account(act_id).name.clone();
-                        match refresher.inner_refresh(&pstate, ct_lk, act_id) {
-                            RefreshKind::AccountOrTokenStateChanged
-                            | RefreshKind::NoRefreshToken => (),
-                            RefreshKind::PermanentError(msg) => {
-                                info!("Permanent refresh error for {act_name}: {msg}");
-                                pstate
-                                    .eventer
-                                    .token_event(act_name, TokenEvent::Invalidated);
-                            }
-                            RefreshKind::Refreshed => {
-                                refresher.notify_changes();
-                                pstate.eventer.token_event(act_name, TokenEvent::Refresh);
-                            }
-                            RefreshKind::TransitoryError(act_id, msg) => {
-                                ct_lk = pstate.ct_lock();
-                                if ct_lk.is_act_id_valid(act_id) {
-                                    let mut new_ts = ct_lk.tokenstate(act_id).clone();
-                                    if let TokenState::Active {
-                                        ref mut last_refresh_attempt,
-                                        ref mut consecutive_refresh_fails,
-                                        ..
-                                    } = new_ts
-                                    {
-                                        *last_refresh_attempt = Some(Instant::now());
-                                        *consecutive_refresh_fails += 1;
-                                        let consecutive_refresh_fails = *consecutive_refresh_fails;
-                                        let act_id = ct_lk.tokenstate_replace(act_id, new_ts);
-                                        if consecutive_refresh_fails
-                                            .rem_euclid(TRANSIENT_ERROR_RETRIES)
-                                            == 0
-                                        {
-                                            if let Some(ref cmd) =
-                                                ct_lk.config().transient_error_if_cmd
-                                            {
-                                                let cmd = cmd.to_owned();
-                                                drop(ct_lk);
-                                                match shell_cmd(
-                                                    &cmd,
-                                                    [("PIZAUTH_ACCOUNT", act_name.as_str())],
-                                                    TRANSIENT_ERROR_IF_CMD_TIMEOUT,
-                                                ) {
-                                                    Ok(()) => {
-                                                        ct_lk = pstate.ct_lock();
-                                                        if ct_lk.is_act_id_valid(act_id) {
-                                                            ct_lk.tokenstate_set_ongoing_refresh(
-                                                                act_id, false,
-                                                            );
-                                                        }
-                                                        drop(ct_lk);
-                                                    }
-                                                    Err(e) => {
-                                                        ct_lk = pstate.ct_lock();
-                                                        if ct_lk.is_act_id_valid(act_id) {
-                                                            ct_lk.tokenstate_replace(
-                                                                act_id,
-                                                                TokenState::Empty,
-                                                            );
-                                                        }
-                                                        drop(ct_lk);
-                                                        error!("Permanent refresh error for {act_name}: {e}");
-                                                        pstate.eventer.token_event(
-                                                            act_name,
-                                                            TokenEvent::Invalidated,
-                                                        );
-                                                    }
-                                                };
-                                            } else {
-                                                ct_lk.tokenstate_set_ongoing_refresh(act_id, false);
-                                                drop(ct_lk);
-                                                info!("Transitory refresh error for {act_name}: {msg}");
-                                            }
-                                        } else {
+                        if consecutive_refresh_fails.rem_euclid(TRANSIENT_ERROR_RETRIES) == 0 {
+                            if let Some(ref cmd) = ct_lk.config().transient_error_if_cmd {
+                                let cmd = cmd.to_owned();
+                                drop(ct_lk);
+                                match shell_cmd(
+                                    &cmd,
+                                    [("PIZAUTH_ACCOUNT", act_name.as_str())],
+                                    TRANSIENT_ERROR_IF_CMD_TIMEOUT,
+                                ) {
+                                    Ok(()) => {
+                                        ct_lk = pstate.ct_lock();
+                                        if ct_lk.is_act_id_valid(act_id) {
                                             ct_lk.tokenstate_set_ongoing_refresh(act_id, false);
-                                            drop(ct_lk);
-                                            info!("Transitory refresh error for {act_name}: {msg}");
                                         }
-                                    } else {
-                                        unreachable!();
+                                        drop(ct_lk);
                                     }
-                                } else {
-                                    drop(ct_lk);
-                                }
-                                // If the main refresher thread noticed we were running it
-                                // might have given up, so give it a chance to recalculate when
-                                // it should next wake up.
-                                refresher.notify_changes();
+                                    Err(e) => {
+                                        ct_lk = pstate.ct_lock();
+                                        if ct_lk.is_act_id_valid(act_id) {
+                                            ct_lk.tokenstate_replace(act_id, TokenState::Empty);
+                                        }
+                                        drop(ct_lk);
+                                        error!("Permanent refresh error for {act_name}: {e}");
+                                        pstate
+                                            .eventer
+                                            .token_event(act_name, TokenEvent::Invalidated);
+                                    }
+                                };
+                            } else {
+                                ct_lk.tokenstate_set_ongoing_refresh(act_id, false);
+                                drop(ct_lk);
+                                info!("Transitory refresh error for {act_name}: {msg}");
                             }
+                        } else {
+                            ct_lk.tokenstate_set_ongoing_refresh(act_id, false);
+                            drop(ct_lk);
+                            info!("Transitory refresh error for {act_name}: {msg}");
                         }
+                    } else {
+                        unreachable!();
                     }
+                } else {
+                    drop(ct_lk);
                 }
+                // If the main refresher thread noticed we were running it might have given up, so
+                // give it a chance to recalculate when it should next wake up.
+                self.notify_changes();
+                Err(msg)
             }
-        });
+        }
     }
 
     /// For a [`TokenState::Active`] token for `act_id`, refresh it, blocking until the token is
@@ -177,18 +186,31 @@ impl Refresher {
         mut act_id: AccountId,
     ) -> RefreshKind {
         info!("starting inner refresh");
+        // Accounts using the client credentials grant don't use refresh tokens: we simply
+        // request a new access token.
+        let client_credentials = ct_lk.account(act_id).grant_type == GrantType::ClientCredentials;
         let mut new_ts = ct_lk.tokenstate(act_id).clone();
+        // A refreshed token is bound to the same DPoP key as the token it replaces.
+        let dpop_key = match new_ts {
+            TokenState::Active { ref dpop_key, .. } => dpop_key.clone(),
+            _ => unreachable!("tokenstate is not TokenState::Active"),
+        };
         let refresh_token = match new_ts {
             TokenState::Active {
                 ref refresh_token,
                 ref mut last_refresh_attempt,
                 ..
             } => match refresh_token {
+                _ if client_credentials => {
+                    *last_refresh_attempt = Some(Instant::now());
+                    act_id = ct_lk.tokenstate_replace(act_id, new_ts);
+                    None
+                }
                 Some(r) => {
                     *last_refresh_attempt = Some(Instant::now());
                     let r = r.to_owned();
                     act_id = ct_lk.tokenstate_replace(act_id, new_ts);
-                    r
+                    Some(r)
                 }
                 None => {
                     ct_lk.tokenstate_replace(act_id, TokenState::Empty);
@@ -199,26 +221,33 @@ impl Refresher {
         };
 
         let act = ct_lk.account(act_id);
-        let token_uri = act.token_uri.clone();
-        let client_id = act.client_id.clone();
-        let mut pairs = vec![
-            ("client_id", client_id.as_str()),
-            ("refresh_token", refresh_token.as_str()),
-            ("grant_type", "refresh_token"),
-        ];
-        let client_secret = act.client_secret.clone();
-        if let Some(ref x) = client_secret {
-            pairs.push(("client_secret", x));
+        let token_uri = act.token_uri().to_owned();
+        let client_auth = ClientAuth::new(act);
+        let scopes_join = act.scopes.join(" ");
+        let mut pairs = Vec::new();
+        match refresh_token {
+            Some(ref r) => {
+                pairs.push(("refresh_token", r.as_str()));
+                pairs.push(("grant_type", "refresh_token"));
+            }
+            None => {
+                pairs.push(("grant_type", "client_credentials"));
+                if !act.scopes.is_empty() {
+                    pairs.push(("scope", scopes_join.as_str()));
+                }
+            }
         }
 
         drop(ct_lk);
         let agent_conf = ureq::Agent::config_builder()
             .timeout_global(Some(UREQ_TIMEOUT))
             .build();
-        let body = match ureq::Agent::new_with_config(agent_conf)
-            .post(token_uri.as_str())
-            .send_form(pairs)
-        {
+        let body = match client_auth.post_form(
+            &ureq::Agent::new_with_config(agent_conf),
+            &token_uri,
+            &pairs,
+            dpop_key.as_ref(),
+        ) {
             Ok(response) => match response.into_body().read_to_string() {
                 Ok(s) => s,
                 Err(e) => return RefreshKind::TransitoryError(act_id, e.to_string()),
@@ -286,9 +315,11 @@ impl Refresher {
             parsed["expires_in"].as_u64(),
             parsed["token_type"].as_str(),
         ) {
-            (Some(access_token), Some(expires_in), Some("Bearer")) => {
+            (Some(access_token), Some(expires_in), token_type)
+                if token_type_ok(token_type, dpop_key.as_ref()) =>
+            {
                 let refresh_token = match parsed.get("refresh_token") {
-                    None => Some(refresh_token),
+                    None => refresh_token,
                     Some(Value::String(x)) => Some(x.to_owned()),
                     Some(_) => None,
                 };
@@ -312,6 +343,7 @@ impl Refresher {
                             consecutive_refresh_fails: 0,
                             last_refresh_attempt: None,
                             refresh_token,
+                            dpop_key,
                         },
                     );
                     drop(ct_lk);
@@ -1,24 +1,62 @@
 use std::{error::Error, sync::Arc};
 
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use pizauth::protocol::{EventKind, Reply};
 use rand::{rng, Rng};
 use sha2::{Digest, Sha256};
 use url::Url;
 
-use super::{AccountId, AuthenticatorState, CTGuard, TokenState, CODE_VERIFIER_LEN, STATE_LEN};
+use super::{
+    client_auth::ClientAuth, device_code::request_device_code, par::push_authorization_request,
+    AccountId, AuthenticatorState, CTGuard, TokenState, CODE_VERIFIER_LEN, STATE_LEN,
+};
+use crate::config::GrantType;
+
+/// What the user needs to do to complete a pending authorisation: visit `url` and, for the device
+/// authorization grant, enter `user_code`.
+pub struct PendingAuth {
+    pub url: Url,
+    pub user_code: Option<String>,
+}
+
+impl PendingAuth {
+    /// Return the [Reply] telling a client that authorisation is pending. If `with_url` is
+    /// `false`, the URL and user code are not included.
+    pub fn reply(self, with_url: bool) -> Reply {
+        if with_url {
+            Reply::Pending {
+                url: Some(self.url.into()),
+                user_code: self.user_code,
+            }
+        } else {
+            Reply::Pending {
+                url: None,
+                user_code: None,
+            }
+        }
+    }
+}
 
 /// Request a new token for `act_id`, whose tokenstate must be `Empty`.
 pub fn request_token(
     pstate: Arc<AuthenticatorState>,
-    mut ct_lk: CTGuard,
+    ct_lk: CTGuard,
     act_id: AccountId,
-) -> Result<Url, Box<dyn Error>> {
+) -> Result<PendingAuth, Box<dyn Error>> {
     assert!(matches!(
         ct_lk.tokenstate(act_id),
         TokenState::Empty | TokenState::Pending { .. }
     ));
 
-    let act = ct_lk.account(act_id);
+    // We drop `ct_lk` while obtaining the client ID, which may mean running a command, and while
+    // pushing the authorisation request, so we take a copy of the account.
+    let act = ct_lk.account(act_id).clone();
+    let act_name = act.name.clone();
+    if act.grant_type == GrantType::DeviceCode {
+        let pending = request_device_code(Arc::clone(&pstate), ct_lk, act_id)?;
+        pstate.eventer.event(&act_name, EventKind::AuthPending);
+        return Ok(pending);
+    }
 
     let mut state = [0u8; STATE_LEN];
     rng().fill_bytes(&mut state);
@@ -35,11 +73,22 @@ pub fn request_token(
     let redirect_uri = act
         .redirect_uri(pstate.http_port, pstate.https_port)?
         .to_string();
+    drop(ct_lk);
+    let client_id = match act.client_id.get() {
+        Ok(x) => x,
+        Err(e) => {
+            let msg = format!("Authentication for {act_name} failed: {e}");
+            pstate
+                .notifier
+                .notify_error(&pstate, act_name, msg.clone())?;
+            return Err(msg.into());
+        }
+    };
     let mut params = vec![
         ("access_type", "offline"),
         ("code_challenge", &code_challenge),
         ("code_challenge_method", "S256"),
-        ("client_id", act.client_id.as_str()),
+        ("client_id", client_id.as_str()),
         ("redirect_uri", redirect_uri.as_str()),
         ("response_type", "code"),
         ("state", &state),
@@ -50,7 +99,44 @@ pub fn request_token(
     for (k, v) in &act.auth_uri_fields {
         params.push((k.as_str(), v.as_str()));
     }
-    let url = Url::parse_with_params(ct_lk.account(act_id).auth_uri.as_str(), &params)?;
+    // Config parsing or discovery guarantees authorization code grant accounts have an `auth_uri`.
+    let auth_uri = act.auth_uri().unwrap();
+    let url = match &act.par_uri {
+        None => Url::parse_with_params(auth_uri, &params)?,
+        Some(par_uri) => {
+            // `ClientAuth` adds `client_id` itself.
+            let pairs = params
+                .iter()
+                .filter(|(k, _)| *k != "client_id")
+                .copied()
+                .collect::<Vec<_>>();
+            let request_uri =
+                match push_authorization_request(par_uri, &ClientAuth::new(&act), &pairs) {
+                    Ok(x) => x,
+                    Err(e) => {
+                        let msg = format!("Authentication for {act_name} failed: {e}");
+                        pstate
+                            .notifier
+                            .notify_error(&pstate, act_name, msg.clone())?;
+                        return Err(msg.into());
+                    }
+                };
+            Url::parse_with_params(
+                auth_uri,
+                [
+                    ("client_id", client_id.as_str()),
+                    ("request_uri", request_uri.as_str()),
+                ],
+            )?
+        }
+    };
+    let mut ct_lk = pstate.ct_lock();
+    if !ct_lk.is_act_id_valid(act_id) {
+        return Err(format!(
+            "Account {act_name} changed while requesting authorization: request a fresh token"
+        )
+        .into());
+    }
     ct_lk.tokenstate_replace(
         act_id,
         TokenState::Pending {
@@ -58,9 +144,14 @@ pub fn request_token(
             last_notification: None,
             url: url.clone(),
             state,
+            device_codes: None,
         },
     );
     drop(ct_lk);
     pstate.notifier.notify_changes();
-    Ok(url)
+    pstate.eventer.event(&act_name, EventKind::AuthPending);
+    Ok(PendingAuth {
+        url,
+        user_code: None,
+    })
 }
@@ -0,0 +1,68 @@
+//! Token revocation (RFC 7009): telling the OAuth2 server that tokens should no longer be
+//! considered valid.
+
+use std::error::Error;
+
+use serde_json::Value;
+
+use super::{client_auth::ClientAuth, UREQ_TIMEOUT};
+
+/// Ask the server at `revocation_uri` to revoke each `(token, token_type_hint)` pair in `tokens`,
+/// in order. All tokens are sent to the server even if an earlier one fails to be revoked; if any
+/// revocation fails, `Err` is returned with a string suitable for reporting to the user.
+pub fn revoke_tokens(
+    revocation_uri: &str,
+    client_auth: &ClientAuth,
+    tokens: &[(&str, &str)],
+) -> Result<(), Box<dyn Error>> {
+    let mut errs = Vec::new();
+    for (token, token_type_hint) in tokens {
+        if let Err(e) = revoke_token(revocation_uri, client_auth, token, token_type_hint) {
+            errs.push(format!("revoking {token_type_hint} failed: {e}"));
+        }
+    }
+    if errs.is_empty() {
+        Ok(())
+    } else {
+        Err(errs.join("; ").into())
+    }
+}
+
+fn revoke_token(
+    revocation_uri: &str,
+    client_auth: &ClientAuth,
+    token: &str,
+    token_type_hint: &str,
+) -> Result<(), Box<dyn Error>> {
+    let pairs = [("token", token), ("token_type_hint", token_type_hint)];
+    // Error responses have a 400 status code, but contain an error message we want to report to
+    // the user, so we can't treat status codes as errors.
+    let agent_conf = ureq::Agent::config_builder()
+        .timeout_global(Some(UREQ_TIMEOUT))
+        .http_status_as_error(false)
+        .build();
+    let response = client_auth
+        .post_form(
+            &ureq::Agent::new_with_config(agent_conf),
+            revocation_uri,
+            &pairs,
+            None,
+        )
+        .map_err(|e| format!("couldn't connect to {revocation_uri}: {e}"))?;
+    // On success, the response body carries no information (RFC 7009 section 2.2).
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    let body = response.into_body().read_to_string().unwrap_or_default();
+    match serde_json::from_str::<Value>(&body)
+        .ok()
+        .and_then(|x| x["error"].as_str().map(|x| x.to_owned()))
+    {
+        // The server doesn't support revoking this type of token, so there's nothing more we can
+        // do (RFC 7009 section 2.2.1).
+        Some(e) if e == "unsupported_token_type" => Ok(()),
+        Some(e) => Err(e.into()),
+        None => Err(format!("HTTP code {}", status.as_u16()).into()),
+    }
+}
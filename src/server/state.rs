@@ -15,8 +15,8 @@ use std::{
     collections::HashMap,
     error::Error,
     path::PathBuf,
-    sync::{Arc, Mutex, MutexGuard},
-    time::SystemTime,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, SystemTime},
 };
 
 use boot_time::Instant;
@@ -29,8 +29,10 @@ use serde::{Deserialize, Serialize};
 use url::Url;
 use wincode::{deserialize, serialize, SchemaRead, SchemaWrite};
 
-use super::{eventer::Eventer, notifier::Notifier, refresher::Refresher};
-use crate::config::{Account, AccountDump, Config};
+use super::{
+    dpop::DpopKey, eventer::Eventer, notifier::Notifier, refresher::Refresher, storage::storage,
+};
+use crate::config::{Account, AccountDump, AccountDumpV1, Config};
 
 /// We lightly encrypt the dump output to make it at least resistant to simple string-based
 /// grepping. This is the length of the dump nonce.
@@ -38,8 +40,8 @@ const NONCE_LEN: usize = 12;
 /// The ChaCha20 key for the dump.
 const CHACHA20_KEY: &[u8; 32] = b"\x66\xa2\x47\xa8\x5e\x48\xcf\xec\xaa\xed\x9b\x36\xeb\xa9\x7d\x53\x50\xd4\x28\x63\x75\x09\x7a\x44\xee\xff\xb9\xc4\x54\x6b\x65\xa3";
 /// The format of the dump. Monotonically increment if the semantics of the `pizauth dump` change
-/// in an incompatible manner.
-const DUMP_VERSION: u64 = 1;
+/// in an incompatible manner, and convert dumps in the old format in [`LockedState::restore`].
+const DUMP_VERSION: u64 = 2;
 
 /// pizauth's global state.
 pub struct AuthenticatorState {
@@ -47,6 +49,9 @@ pub struct AuthenticatorState {
     /// The "global lock" protecting the config and current [`TokenState`]s. Can only be accessed via
     /// [`AuthenticatorState::ct_lock`].
     locked_state: Mutex<LockedState>,
+    /// Notified whenever a [`TokenState`] changes, or the config is updated. Used with
+    /// `locked_state` by [`CTGuard::wait_for_change`].
+    changed: Condvar,
     /// Port of the HTTP server required by OAuth.
     pub http_port: Option<u16>,
     /// Port of the HTTPS server required by OAuth.
@@ -72,6 +77,7 @@ impl AuthenticatorState {
         Self {
             conf_path,
             locked_state: Mutex::new(LockedState::new(conf)),
+            changed: Condvar::new(),
             http_port,
             https_port,
             https_pub_key,
@@ -89,7 +95,7 @@ impl AuthenticatorState {
     /// to be done in such a case, as it is likely that pizauth is in an inconsistent, and
     /// irretrievable, state.
     pub fn ct_lock(&self) -> CTGuard<'_> {
-        CTGuard::new(self.locked_state.lock().unwrap())
+        CTGuard::new(self.locked_state.lock().unwrap(), &self.changed)
     }
 
     /// Update the global [Config] to `new_conf`. This cannot fail, but note that there is no
@@ -100,6 +106,7 @@ impl AuthenticatorState {
             let mut lk = self.locked_state.lock().unwrap();
             lk.update_conf(new_conf);
         }
+        self.changed.notify_all();
         self.notifier.notify_changes();
         self.refresher.notify_changes();
     }
@@ -125,6 +132,23 @@ impl AuthenticatorState {
         Ok(buf)
     }
 
+    /// Write a dump to the [`Storage`](super::storage::Storage) specified by the current
+    /// configuration.
+    pub fn store_state(&self) -> Result<(), Box<dyn Error>> {
+        let storage = storage(&self.conf_path, self.ct_lock().config());
+        storage.store(&self.dump()?)
+    }
+
+    /// Restore the dump, if there is one, in the [`Storage`](super::storage::Storage) specified
+    /// by the current configuration.
+    pub fn load_state(&self) -> Result<(), Box<dyn Error>> {
+        let storage = storage(&self.conf_path, self.ct_lock().config());
+        match storage.load()? {
+            Some(d) => self.restore(d),
+            None => Ok(()),
+        }
+    }
+
     pub fn restore(&self, d: Vec<u8>) -> Result<(), Box<dyn Error>> {
         if d.len() < NONCE_LEN {
             return Err("Input too short")?;
@@ -141,6 +165,7 @@ impl AuthenticatorState {
             let mut lk = self.locked_state.lock().unwrap();
             lk.restore(d)?;
         }
+        self.changed.notify_all();
         self.notifier.notify_changes();
         self.refresher.notify_changes();
         Ok(())
@@ -242,10 +267,14 @@ impl LockedState {
     }
 
     fn restore(&mut self, dump: Vec<u8>) -> Result<(), Box<dyn Error>> {
-        let d: Dump = deserialize(&dump)?;
-        if d.version != DUMP_VERSION {
-            return Err("Unknown dump version".into());
-        }
+        // The version is always the first field in a dump: we check it before deserializing the
+        // whole dump, since dumps from other versions are unlikely to deserialize successfully.
+        let version: u64 = deserialize(&dump)?;
+        let d: Dump = match version {
+            1 => deserialize::<DumpV1>(&dump)?.into(),
+            DUMP_VERSION => deserialize(&dump)?,
+            _ => return Err("Unknown dump version".into()),
+        };
 
         let mut restore = HashMap::new();
         for (act_name, _, old_ts) in &self.details {
@@ -299,6 +328,28 @@ struct Dump {
     accounts: HashMap<String, (AccountDump, TokenStateDump)>,
 }
 
+/// The format of version 1 dumps, as produced by pizauth 1.0.
+#[derive(Deserialize, Serialize, SchemaRead, SchemaWrite)]
+struct DumpV1 {
+    version: u64,
+    accounts: HashMap<String, (AccountDumpV1, TokenStateDumpV1)>,
+}
+
+impl From<DumpV1> for Dump {
+    fn from(d: DumpV1) -> Self {
+        Self {
+            version: DUMP_VERSION,
+            accounts: d
+                .accounts
+                .into_iter()
+                .map(|(act_name, (act_dump, ts_dump))| {
+                    (act_name, (act_dump.into(), ts_dump.into()))
+                })
+                .collect(),
+        }
+    }
+}
+
 /// A lock guard around the [`Config`] and tokens. When this guard is dropped:
 ///
 ///   1. the config lock will be released.
@@ -307,11 +358,23 @@ struct Dump {
 ///      revalidated.
 pub struct CTGuard<'a> {
     guard: MutexGuard<'a, LockedState>,
+    changed: &'a Condvar,
 }
 
 impl<'a> CTGuard<'a> {
-    fn new(guard: MutexGuard<'a, LockedState>) -> Self {
-        CTGuard { guard }
+    fn new(guard: MutexGuard<'a, LockedState>, changed: &'a Condvar) -> Self {
+        CTGuard { guard, changed }
+    }
+
+    /// Release the lock until either a [`TokenState`] or the config changes, or `timeout`
+    /// expires, and then reacquire it. As with dropping a [`CTGuard`], all [`AccountId`]s must be
+    /// revalidated after calling this function. Note that spurious wakeups are possible.
+    pub fn wait_for_change(self, timeout: Duration) -> Self {
+        let (guard, _) = self.changed.wait_timeout(self.guard, timeout).unwrap();
+        CTGuard {
+            guard,
+            changed: self.changed,
+        }
     }
 
     pub fn config(&self) -> &Config {
@@ -342,7 +405,21 @@ impl<'a> CTGuard<'a> {
         self.guard
             .details
             .iter()
-            .find(|x| matches!(&x.2, TokenState::Pending { state: s, .. } if s == state))
+            .find(|x| {
+                matches!(&x.2, TokenState::Pending { state: s, device_codes: None, .. } if s == state)
+            })
+            .map(|x| x.1)
+    }
+
+    /// Return the [`AccountId`] whose pending device authorization has device code `device_code`.
+    pub fn act_id_matching_device_code(&self, device_code: &str) -> Option<AccountId> {
+        self.guard
+            .details
+            .iter()
+            .find(|x| {
+                matches!(&x.2, TokenState::Pending { device_codes: Some(d), .. }
+                    if d.device_code == device_code)
+            })
             .map(|x| x.1)
     }
 
@@ -404,6 +481,7 @@ impl<'a> CTGuard<'a> {
         {
             ts.1 = new_id;
             *ongoing_refresh = new_ongoing_refresh;
+            self.changed.notify_all();
             return new_id;
         }
         unreachable!();
@@ -429,6 +507,7 @@ impl<'a> CTGuard<'a> {
         let new_id = self.guard.next_account_id();
         self.guard.details[i].1 = new_id;
         self.guard.details[i].2 = new_tokenstate;
+        self.changed.notify_all();
         new_id
     }
 }
@@ -449,6 +528,9 @@ pub enum TokenState {
         last_notification: Option<Instant>,
         state: String,
         url: Url,
+        /// If the account uses the device authorization grant, the codes we were given by the
+        /// device authorization endpoint. `code_verifier` and `state` are unused in such cases.
+        device_codes: Option<DeviceCodes>,
     },
     /// There is an active token (and, possibly, also an active refresh token).
     Active {
@@ -461,6 +543,9 @@ pub enum TokenState {
         /// token when the existing one expires (notice the two "may"s!). The remaining fields in
         /// the `Active` variant are only relevant if `refresh_token` is `Some(...)`.
         refresh_token: Option<String>,
+        /// If the account uses DPoP, the key pair to which `access_token` and `refresh_token` are
+        /// bound. The same key is used for the lifetime of the refresh token.
+        dpop_key: Option<DpopKey>,
         /// Is the refresher currently trying to refresh this token?
         ongoing_refresh: bool,
         /// How many times in a row has refreshing failed? This will be reset to zero when
@@ -471,6 +556,15 @@ pub enum TokenState {
     },
 }
 
+/// The codes returned by a device authorization endpoint (RFC 8628 section 3.2).
+#[derive(Clone, Debug)]
+pub struct DeviceCodes {
+    /// The code we use to poll the token endpoint. This must not be shown to the user.
+    pub device_code: String,
+    /// The code the user must enter at the verification URI.
+    pub user_code: String,
+}
+
 #[derive(Deserialize, Serialize, SchemaRead, SchemaWrite)]
 /// The format of a dumped [`TokenState`]. Note that [`std::time::Instant`] instances are translated to
 /// [`std::time::SystemTime`] instances: there is no guarantee that we can precisely represent the
@@ -478,6 +572,21 @@ pub enum TokenState {
 /// [`std::time::Instant::now()`] or [`std::time::SystemTime::now()`], as appropriate, as a safe
 /// fallback.
 pub enum TokenStateDump {
+    Empty,
+    Active {
+        access_token: String,
+        access_token_obtained: SystemTime,
+        access_token_expiry: SystemTime,
+        refresh_token: Option<String>,
+        /// The PKCS#8 encoded DPoP key, if any.
+        dpop_key: Option<Vec<u8>>,
+    },
+}
+
+/// The format of [`TokenStateDump`] in version 1 dumps, from before tokens could be bound to a DPoP
+/// key.
+#[derive(Deserialize, Serialize, SchemaRead, SchemaWrite)]
+enum TokenStateDumpV1 {
     Empty,
     Active {
         access_token: String,
@@ -487,6 +596,26 @@ pub enum TokenStateDump {
     },
 }
 
+impl From<TokenStateDumpV1> for TokenStateDump {
+    fn from(d: TokenStateDumpV1) -> Self {
+        match d {
+            TokenStateDumpV1::Empty => Self::Empty,
+            TokenStateDumpV1::Active {
+                access_token,
+                access_token_obtained,
+                access_token_expiry,
+                refresh_token,
+            } => Self::Active {
+                access_token,
+                access_token_obtained,
+                access_token_expiry,
+                refresh_token,
+                dpop_key: None,
+            },
+        }
+    }
+}
+
 impl TokenState {
     pub fn dump(&self) -> TokenStateDump {
         fn dump_instant(i: &Instant) -> SystemTime {
@@ -510,6 +639,7 @@ impl TokenState {
                 access_token_obtained,
                 access_token_expiry,
                 refresh_token,
+                dpop_key,
                 ongoing_refresh: _,
                 consecutive_refresh_fails: _,
                 last_refresh_attempt: _,
@@ -518,6 +648,7 @@ impl TokenState {
                 access_token_obtained: dump_instant(access_token_obtained),
                 access_token_expiry: dump_instant(access_token_expiry),
                 refresh_token: refresh_token.clone(),
+                dpop_key: dpop_key.as_ref().map(|x| x.pkcs8().to_owned()),
             },
         }
     }
@@ -544,11 +675,13 @@ impl TokenState {
                 access_token_obtained,
                 access_token_expiry,
                 refresh_token,
+                dpop_key,
             } => Self::Active {
                 access_token: access_token.clone(),
                 access_token_obtained: restore_instant(access_token_obtained),
                 access_token_expiry: restore_instant(access_token_expiry),
                 refresh_token: refresh_token.clone(),
+                dpop_key: dpop_key.clone().map(DpopKey::from_pkcs8),
                 ongoing_refresh: false,
                 consecutive_refresh_fails: 0,
                 last_refresh_attempt: None,
@@ -685,6 +818,7 @@ mod test {
                     last_notification: None,
                     state: "xyz".to_string(),
                     url: Url::parse("http://a.com/").unwrap(),
+                    device_codes: None,
                 },
             );
             assert_ne!(act_id, old_x_id);
@@ -779,6 +913,7 @@ mod test {
                     last_notification: None,
                     state: "xyz".to_string(),
                     url: Url::parse("http://a.com/").unwrap(),
+                    device_codes: None,
                 },
             );
         }
@@ -804,6 +939,7 @@ mod test {
                         .checked_add(Duration::from_mins(1))
                         .unwrap(),
                     refresh_token: None,
+                    dpop_key: None,
                     ongoing_refresh: false,
                     consecutive_refresh_fails: 0,
                     last_refresh_attempt: None,
@@ -895,6 +1031,7 @@ mod test {
                         .checked_add(Duration::from_mins(1))
                         .unwrap(),
                     refresh_token: Some("refresh".to_owned()),
+                    dpop_key: None,
                     ongoing_refresh: false,
                     consecutive_refresh_fails: 0,
                     last_refresh_attempt: None,
@@ -939,6 +1076,7 @@ mod test {
                     last_notification: None,
                     state: "xyz".to_string(),
                     url: Url::parse("http://a.com/").unwrap(),
+                    device_codes: None,
                 },
             );
         }
@@ -0,0 +1,202 @@
+//! Backends which persist the server's token state (in
+//! [`AuthenticatorState::dump`](super::AuthenticatorState::dump) format)
+//! across restarts.
+
+use std::{
+    error::Error,
+    fs::{self, rename, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use crate::config::{Config, StateStorage};
+
+/// A place where dumps can be stored and later loaded from.
+pub trait Storage {
+    /// Replace the stored dump with `d`.
+    fn store(&self, d: &[u8]) -> Result<(), Box<dyn Error>>;
+    /// Return the stored dump or `None` if there is no stored dump.
+    fn load(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+}
+
+/// Return the [Storage] specified by `conf`. `conf_path` is used to distinguish the dumps of
+/// different pizauth instances in those backends that are shared between instances.
+pub fn storage(conf_path: &Path, conf: &Config) -> Box<dyn Storage> {
+    let conf_path = conf_path
+        .canonicalize()
+        .unwrap_or_else(|_| conf_path.to_owned());
+    match conf.state_storage {
+        StateStorage::None => Box::new(NoStorage),
+        StateStorage::File => Box::new(FileStorage(conf.state_file.clone().unwrap())),
+        #[cfg(target_os = "linux")]
+        StateStorage::KernelKeyring => {
+            Box::new(keyring::KernelKeyring::new(&conf_path, conf.kernel_keyring))
+        }
+        #[cfg(feature = "secret-service")]
+        StateStorage::SecretService => Box::new(secret_service::SecretService::new(&conf_path)),
+        // The config parser rejects backends that aren't supported by this build.
+        #[allow(unreachable_patterns)]
+        _ => unreachable!(),
+    }
+}
+
+/// Token state is not persisted.
+struct NoStorage;
+
+impl Storage for NoStorage {
+    fn store(&self, _d: &[u8]) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        Ok(None)
+    }
+}
+
+/// Token state is stored in a file, which is replaced atomically so that a crash part way through
+/// writing cannot leave a truncated dump behind.
+struct FileStorage(PathBuf);
+
+impl Storage for FileStorage {
+    fn store(&self, d: &[u8]) -> Result<(), Box<dyn Error>> {
+        let path = &self.0;
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| format!("Invalid state file path {path:?}"))?;
+        let mut tmp_name = file_name.to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = path.with_file_name(tmp_name);
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .map_err(|e| format!("Can't write state file {tmp_path:?}: {e}"))?;
+        f.write_all(d)?;
+        f.sync_all()?;
+        drop(f);
+        rename(&tmp_path, path).map_err(|e| format!("Can't write state file {path:?}: {e}"))?;
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match fs::read(&self.0) {
+            Ok(d) => Ok(Some(d)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(format!("Can't read state file {:?}: {e}", self.0).into()),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod keyring {
+    use std::{error::Error, path::Path};
+
+    use linux_keyutils::{KeyError, KeyRing, KeyRingIdentifier};
+
+    use super::Storage;
+    use crate::config;
+
+    /// Token state is stored as a "user" key in either the kernel's per-user keyring, which lasts
+    /// until the machine is rebooted, or the session keyring, which lasts until the user's session
+    /// ends.
+    pub struct KernelKeyring {
+        description: String,
+        keyring: KeyRingIdentifier,
+    }
+
+    impl KernelKeyring {
+        pub fn new(conf_path: &Path, keyring: config::KernelKeyring) -> Self {
+            Self {
+                description: format!("pizauth:{}", conf_path.display()),
+                keyring: match keyring {
+                    config::KernelKeyring::Session => KeyRingIdentifier::Session,
+                    config::KernelKeyring::User => KeyRingIdentifier::User,
+                },
+            }
+        }
+    }
+
+    impl Storage for KernelKeyring {
+        fn store(&self, d: &[u8]) -> Result<(), Box<dyn Error>> {
+            KeyRing::from_special_id(self.keyring, true)
+                .and_then(|kr| kr.add_key(&self.description, d))
+                .map_err(|e| format!("Can't store state in kernel keyring: {e}"))?;
+            Ok(())
+        }
+
+        fn load(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            let key = KeyRing::from_special_id(self.keyring, true)
+                .and_then(|kr| kr.search(&self.description));
+            match key.and_then(|k| k.read_to_vec()) {
+                Ok(d) => Ok(Some(d)),
+                Err(KeyError::KeyDoesNotExist) => Ok(None),
+                Err(e) => Err(format!("Can't load state from kernel keyring: {e}").into()),
+            }
+        }
+    }
+}
+
+#[cfg(feature = "secret-service")]
+mod secret_service {
+    use std::{collections::HashMap, error::Error, path::Path};
+
+    use secret_service::{blocking::SecretService as SS, EncryptionType};
+
+    use super::Storage;
+
+    /// Token state is stored as an item in the Secret Service's default collection.
+    pub struct SecretService {
+        conf_path: String,
+    }
+
+    impl SecretService {
+        pub fn new(conf_path: &Path) -> Self {
+            Self {
+                conf_path: conf_path.display().to_string(),
+            }
+        }
+
+        fn attributes(&self) -> HashMap<&str, &str> {
+            HashMap::from([
+                ("application", "pizauth"),
+                ("config", self.conf_path.as_str()),
+            ])
+        }
+    }
+
+    impl Storage for SecretService {
+        fn store(&self, d: &[u8]) -> Result<(), Box<dyn Error>> {
+            let ss = SS::connect(EncryptionType::Dh)?;
+            let collection = ss.get_default_collection()?;
+            if collection.is_locked()? {
+                collection.unlock()?;
+            }
+            collection.create_item(
+                &format!("pizauth token state ({})", self.conf_path),
+                self.attributes(),
+                d,
+                true,
+                "application/octet-stream",
+            )?;
+            Ok(())
+        }
+
+        fn load(&self) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+            let ss = SS::connect(EncryptionType::Dh)?;
+            let items = ss.search_items(self.attributes())?;
+            if let Some(item) = items.unlocked.first() {
+                return Ok(Some(item.get_secret()?));
+            }
+            match items.locked.first() {
+                Some(item) => {
+                    item.unlock()?;
+                    Ok(Some(item.get_secret()?))
+                }
+                None => Ok(None),
+            }
+        }
+    }
+}
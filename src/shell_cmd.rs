@@ -1,4 +1,11 @@
-use std::{env, error::Error, process::Command, time::Duration};
+use std::{
+    env,
+    error::Error,
+    io::Read,
+    process::{Child, Command, Stdio},
+    thread,
+    time::Duration,
+};
 
 use wait_timeout::ChildExt;
 
@@ -10,12 +17,47 @@ pub fn shell_cmd<const T: usize>(
     env: [(&str, &str); T],
     timeout: Duration,
 ) -> Result<(), Box<dyn Error>> {
+    let s = env::var("SHELL").map_err(|e| format!("{e:}"))?;
+    let child = Command::new(s)
+        .envs(env)
+        .args(["-c", cmd])
+        .spawn()
+        .map_err(|e| format!("Couldn't execute '{cmd:}': {e:}"))?;
+    wait(cmd, child, timeout)
+}
+
+/// Run the string `cmd` as `$SHELL -c '<cmd>'` with the environment `env`, returning its stdout.
+/// If the command runs for longer than `timeout`, it will be sent `SIGKILL`. If any error occurs,
+/// `Err` is returned with a string suitable for reporting to the user.
+pub fn shell_cmd_stdout<const T: usize>(
+    cmd: &str,
+    env: [(&str, &str); T],
+    timeout: Duration,
+) -> Result<String, Box<dyn Error>> {
     let s = env::var("SHELL").map_err(|e| format!("{e:}"))?;
     let mut child = Command::new(s)
         .envs(env)
         .args(["-c", cmd])
+        .stdout(Stdio::piped())
         .spawn()
         .map_err(|e| format!("Couldn't execute '{cmd:}': {e:}"))?;
+    // If we didn't read stdout until the command had exited, a command producing enough output to
+    // fill the pipe would block until it hit the timeout.
+    let mut stdout = child.stdout.take().unwrap();
+    let reader = thread::spawn(move || {
+        let mut buf = String::new();
+        stdout.read_to_string(&mut buf).map(|_| buf)
+    });
+    wait(cmd, child, timeout)?;
+    reader
+        .join()
+        .map_err(|_| format!("Reading output of '{cmd:}' failed"))?
+        .map_err(|e| format!("Reading output of '{cmd:}' failed: {e:}").into())
+}
+
+/// Wait for `child` (which was spawned from `cmd`) to exit, killing it if it runs for longer than
+/// `timeout`.
+fn wait(cmd: &str, mut child: Child, timeout: Duration) -> Result<(), Box<dyn Error>> {
     let s = child
         .wait_timeout(timeout)
         .map_err(|e| format!("Waiting on '{cmd:}' failed: {e:}"))?;
@@ -1,172 +1,227 @@
 use std::{
     error::Error,
-    io::{stdin, Read, Write},
-    net::Shutdown,
-    os::unix::net::UnixStream,
+    io::{stdin, Read},
     path::Path,
+    str::FromStr,
+    time::Duration,
 };
 
-use crate::server::sock_path;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use pizauth::{sock_path, Client, ClientError};
+use serde_json::json;
+
+use crate::dump_crypt::{self, DumpKey};
+
+/// The formats in which `show` can output an access token.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TokenFormat {
+    /// The access token itself.
+    Raw,
+    /// An HTTP `Authorization` header.
+    Header,
+    /// A base64 encoded SASL XOAUTH2 initial client response.
+    XOAuth2,
+    /// A base64 encoded SASL OAUTHBEARER (RFC 7628) initial client response.
+    OAuthBearer,
+}
 
-pub fn dump(cache_path: &Path) -> Result<Vec<u8>, Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(b"dump:")
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
+impl FromStr for TokenFormat {
+    type Err = String;
 
-    let mut buf = Vec::new();
-    stream.read_to_end(&mut buf)?;
-    Ok(buf)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(Self::Raw),
+            "header" => Ok(Self::Header),
+            "xoauth2" => Ok(Self::XOAuth2),
+            "oauthbearer" => Ok(Self::OAuthBearer),
+            _ => Err(format!("Unknown format '{s}'")),
+        }
+    }
+}
+
+impl TokenFormat {
+    /// Format `access_token` for the user `user`. Returns `Err` if this format requires a user and
+    /// `user` is `None`.
+    pub fn format(self, access_token: &str, user: Option<&str>) -> Result<String, String> {
+        match self {
+            Self::Raw => Ok(access_token.to_owned()),
+            Self::Header => Ok(format!("Authorization: Bearer {access_token}")),
+            Self::XOAuth2 => {
+                let user =
+                    user.ok_or("The xoauth2 format requires a user: set 'username' or use --user")?;
+                Ok(STANDARD.encode(format!("user={user}\x01auth=Bearer {access_token}\x01\x01")))
+            }
+            Self::OAuthBearer => {
+                // RFC 5801 section 5.1: ',' and '=' must be escaped in a GS2 authzid.
+                let authzid = user
+                    .map(|x| format!("a={}", x.replace('=', "=3D").replace(',', "=2C")))
+                    .unwrap_or_default();
+                Ok(STANDARD.encode(format!(
+                    "n,{authzid},\x01auth=Bearer {access_token}\x01\x01"
+                )))
+            }
+        }
+    }
+}
+
+fn connect(cache_path: &Path) -> Result<Client, ClientError> {
+    Client::connect_to(&sock_path(cache_path))
+}
+
+/// Return the server's dump, encrypted with `key` if it is not `None`.
+pub fn dump(cache_path: &Path, key: Option<&DumpKey>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let buf = connect(cache_path)?.dump()?;
+    match key {
+        Some(key) => dump_crypt::encrypt(&buf, key),
+        None => Ok(buf),
+    }
 }
 
 pub fn server_info(cache_path: &Path) -> Result<serde_json::Value, Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(b"info:")
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
-
-    let mut s = String::new();
-    stream.read_to_string(&mut s)?;
-    Ok(serde_json::from_str(&s)?)
+    let info = connect(cache_path)?.info()?;
+    let port = |x: Option<u16>| x.map_or_else(|| "none".to_owned(), |x| x.to_string());
+    let mut j = json!({
+        "http_port": port(info.http_port),
+        "https_port": port(info.https_port),
+    });
+    if let Some(x) = info.https_pub_key {
+        j["https_pub_key"] = json!(x);
+    }
+    Ok(j)
 }
 
 pub fn refresh(cache_path: &Path, account: &str, with_url: bool) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let with_url = if with_url { "withurl" } else { "withouturl" };
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(format!("refresh:{with_url:} {account:}").as_bytes())
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
-
-    let mut rtn = String::new();
-    stream.read_to_string(&mut rtn)?;
-    match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-        ["pending", url] => {
-            Err(format!("Access token unavailable until authorised with URL {url:}").into())
-        }
-        ["scheduled", ""] => Ok(()),
-        ["error", cause] => Err(cause.into()),
-        _ => Err(format!("Malformed response '{rtn:}'").into()),
-    }
+    Ok(connect(cache_path)?.refresh(account, with_url)?)
 }
 
 pub fn reload(cache_path: &Path) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(b"reload:")
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
-
-    let mut rtn = String::new();
-    stream.read_to_string(&mut rtn)?;
-    match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-        ["ok", ""] => Ok(()),
-        ["error", cause] => Err(cause.into()),
-        _ => Err(format!("Malformed response '{rtn:}'").into()),
-    }
+    Ok(connect(cache_path)?.reload()?)
 }
 
-pub fn restore(cache_path: &Path) -> Result<(), Box<dyn Error>> {
+/// Restore a dump read from stdin, decrypting it with `key` if it was created with one.
+pub fn restore(cache_path: &Path, key: Option<&DumpKey>) -> Result<(), Box<dyn Error>> {
     let mut buf = Vec::new();
     stdin().read_to_end(&mut buf)?;
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(b"restore:")
-        .map_err(|_| "Socket not writeable")?;
-    stream.write_all(&buf).map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
-
-    let mut rtn = String::new();
-    stream.read_to_string(&mut rtn)?;
-    match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-        ["ok", ""] => Ok(()),
-        ["error", msg] => Err(msg.into()),
-        _ => Err(format!("Malformed response '{rtn:}'").into()),
-    }
+    let buf = dump_crypt::decrypt(buf, key)?;
+    Ok(connect(cache_path)?.restore(&buf)?)
 }
 
 pub fn revoke(cache_path: &Path, account: &str) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(format!("revoke:{account}").as_bytes())
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
-
-    let mut rtn = String::new();
-    stream.read_to_string(&mut rtn)?;
-    match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-        ["ok", ""] => Ok(()),
-        ["error", cause] => Err(cause.into()),
-        _ => Err(format!("Malformed response '{rtn:}'").into()),
-    }
+    Ok(connect(cache_path)?.revoke(account)?)
 }
 
-pub fn show_token(cache_path: &Path, account: &str, with_url: bool) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let with_url = if with_url { "withurl" } else { "withouturl" };
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(format!("showtoken:{with_url:} {account:}").as_bytes())
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
-
-    let mut rtn = String::new();
-    stream.read_to_string(&mut rtn)?;
-    match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-        ["access_token", x] => {
-            println!("{x:}");
-            Ok(())
+pub fn show_token(
+    cache_path: &Path,
+    account: &str,
+    with_url: bool,
+    format: TokenFormat,
+    user: Option<&str>,
+    wait: Option<Duration>,
+    min_valid: Option<Duration>,
+    dpop_proof: Option<(&str, &str)>,
+) -> Result<(), Box<dyn Error>> {
+    let mut client = connect(cache_path)?;
+    let Some((method, url)) = dpop_proof else {
+        let token = client.show_token(account, with_url, wait, min_valid)?;
+        println!(
+            "{}",
+            format.format(&token.access_token, user.or(token.username.as_deref()))?
+        );
+        return Ok(());
+    };
+    let token =
+        client.show_token_with_dpop_proof(account, with_url, wait, min_valid, method, url)?;
+    let proof = token
+        .dpop_proof
+        .ok_or("Server did not return a DPoP proof")?;
+    // The proof follows the access token on a separate line.
+    match format {
+        TokenFormat::Raw => println!("{}\n{proof}", token.access_token),
+        TokenFormat::Header => {
+            println!("Authorization: DPoP {}\nDPoP: {proof}", token.access_token);
         }
-        ["pending", url] => {
-            Err(format!("Access token unavailable until authorised with URL {url:}").into())
+        TokenFormat::XOAuth2 | TokenFormat::OAuthBearer => {
+            return Err("--dpop-proof can only be used with the raw and header formats".into())
         }
-        ["error", cause] => Err(cause.into()),
-        _ => Err(format!("Malformed response '{rtn:}'").into()),
     }
+    Ok(())
 }
 
 pub fn shutdown(cache_path: &Path) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(b"shutdown:")
-        .map_err(|_| "Socket not writeable")?;
+    Ok(connect(cache_path)?.shutdown()?)
+}
+
+pub fn status(cache_path: &Path, json: bool) -> Result<(), Box<dyn Error>> {
+    let accounts = connect(cache_path)?.status()?;
+    if json {
+        println!(
+            "{}",
+            json!({
+                "accounts": accounts,
+                "status_format_version": 1,
+            })
+        );
+    } else if accounts.is_empty() {
+        return Err("No accounts configured".into());
+    } else {
+        for act in accounts {
+            println!("{}: {}", act.account, act.describe());
+        }
+    }
     Ok(())
 }
 
-pub fn status(cache_path: &Path) -> Result<(), Box<dyn Error>> {
-    let sock_path = sock_path(cache_path);
-    let mut stream = UnixStream::connect(sock_path)
-        .map_err(|_| "pizauth authenticator not running or not responding")?;
-    stream
-        .write_all(b"status:")
-        .map_err(|_| "Socket not writeable")?;
-    stream.shutdown(Shutdown::Write)?;
-
-    let mut rtn = String::new();
-    stream.read_to_string(&mut rtn)?;
-    match rtn.splitn(2, ':').collect::<Vec<_>>()[..] {
-        ["ok", x] => {
-            println!("{x:}");
-            Ok(())
+/// Print events as they happen, until the server goes away.
+pub fn watch(cache_path: &Path, json: bool) -> Result<(), Box<dyn Error>> {
+    for ev in connect(cache_path)?.subscribe()? {
+        let ev = ev?;
+        if json {
+            println!("{}", json!(ev));
+        } else {
+            println!("{} {}: {}", ev.timestamp, ev.account, ev.event);
         }
-        ["error", cause] => Err(cause.into()),
-        _ => Err(format!("Malformed response '{rtn:}'").into()),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn token_formats() {
+        assert_eq!(TokenFormat::Raw.format("abc", Some("u")).unwrap(), "abc");
+        assert_eq!(
+            TokenFormat::Header.format("abc", None).unwrap(),
+            "Authorization: Bearer abc"
+        );
+        // The example from Google's XOAUTH2 documentation.
+        assert_eq!(
+            TokenFormat::XOAuth2
+                .format(
+                    "ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg",
+                    Some("someuser@example.com")
+                )
+                .unwrap(),
+            "dXNlcj1zb21ldXNlckBleGFtcGxlLmNvbQFhdXRoPUJlYXJlciB5YTI5LnZGOWRmdDRxbVRjMk52YjNSbGNrQmhkSFJoZG1semRHRXVZMjl0Q2cBAQ=="
+        );
+        assert!(TokenFormat::XOAuth2.format("abc", None).is_err());
+        assert_eq!(
+            STANDARD
+                .decode(
+                    TokenFormat::OAuthBearer
+                        .format("abc", Some("a,b=c"))
+                        .unwrap()
+                )
+                .unwrap(),
+            b"n,a=a=2Cb=3Dc,\x01auth=Bearer abc\x01\x01"
+        );
+        assert_eq!(
+            STANDARD
+                .decode(TokenFormat::OAuthBearer.format("abc", None).unwrap())
+                .unwrap(),
+            b"n,,\x01auth=Bearer abc\x01\x01"
+        );
+        assert!("json".parse::<TokenFormat>().is_err());
     }
 }